@@ -0,0 +1,148 @@
+//! Decode throughput of ConnectionCore for a 10k-row result, replayed from memory so that only
+//! the framing and buffering of backend messages is measured. Run with
+//! `cargo bench -p client --bench decode`.
+
+use client::ConnectionCore;
+use futures::{
+    executor::block_on,
+    io::{AsyncRead, AsyncWrite},
+};
+use postgres_protocol::message::backend::Message;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Number of rows in the replayed result
+const ROWS: usize = 10_000;
+
+/// Number of times the result is decoded for each read size
+const ITERATIONS: usize = 50;
+
+/// In-memory backend that replays a recorded response, returning at most read_size bytes from
+/// each read (like a stream that hands over one QUIC frame or one coalesced buffer at a time)
+struct Replay<'a> {
+    data: &'a [u8],
+    read_size: usize,
+}
+
+impl AsyncRead for Replay<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buffer: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let length = buffer.len().min(self.read_size).min(self.data.len());
+        let (chunk, rest) = self.data.split_at(length);
+        buffer[..length].copy_from_slice(chunk);
+        self.data = rest;
+        Poll::Ready(Ok(length))
+    }
+}
+
+impl AsyncWrite for Replay<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Append a typed backend message to a recorded response
+fn message(response: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    response.push(tag);
+    response.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+    response.extend_from_slice(body);
+}
+
+/// Record the response to a text-format query returning ROWS rows of (id, name, created_at)
+fn result() -> Vec<u8> {
+    let mut response = Vec::new();
+
+    let mut description = 3i16.to_be_bytes().to_vec();
+    for (name, type_oid) in [("id", 23u32), ("name", 25), ("created_at", 1184)] {
+        description.extend_from_slice(name.as_bytes());
+        description.push(0);
+        description.extend_from_slice(&0u32.to_be_bytes()); // table oid
+        description.extend_from_slice(&0i16.to_be_bytes()); // column number
+        description.extend_from_slice(&type_oid.to_be_bytes());
+        description.extend_from_slice(&(-1i16).to_be_bytes()); // type size
+        description.extend_from_slice(&(-1i32).to_be_bytes()); // type modifier
+        description.extend_from_slice(&0i16.to_be_bytes()); // text format
+    }
+    message(&mut response, b'T', &description);
+
+    for id in 0..ROWS {
+        let values = [
+            id.to_string(),
+            format!("user number {id}"),
+            "2024-01-01 00:00:00.000000+00".to_string(),
+        ];
+        let mut row = 3i16.to_be_bytes().to_vec();
+        for value in values {
+            row.extend_from_slice(&(value.len() as i32).to_be_bytes());
+            row.extend_from_slice(value.as_bytes());
+        }
+        message(&mut response, b'D', &row);
+    }
+
+    message(&mut response, b'C', format!("SELECT {ROWS}\0").as_bytes());
+    message(&mut response, b'Z', b"I");
+    response
+}
+
+/// Decode every message of the response, returning the number of rows seen
+fn decode(response: &[u8], read_size: usize) -> usize {
+    let mut connection = ConnectionCore::new(Replay {
+        data: response,
+        read_size,
+    });
+    let mut rows = 0;
+    loop {
+        match block_on(connection.decode()).expect("replayed response is valid") {
+            Some(Message::DataRow(..)) => rows += 1,
+            Some(Message::ReadyForQuery(..)) => return rows,
+            Some(_) => {}
+            None => panic!("replayed response ended early"),
+        }
+    }
+}
+
+fn main() {
+    let response = result();
+    println!(
+        "{ROWS} rows, {} bytes, best of {ITERATIONS} iterations",
+        response.len()
+    );
+
+    // one small read per QUIC frame (like a default reader), up to coalesced 64 KiB reads
+    // (like a BYOB reader that fills its whole buffer with everything the stream has queued)
+    for read_size in [128, 1_200, 16 * 1024, 64 * 1024] {
+        let mut best = Duration::MAX;
+        for _ in 0..ITERATIONS {
+            let started = Instant::now();
+            assert_eq!(decode(&response, read_size), ROWS);
+            best = best.min(started.elapsed());
+        }
+
+        let seconds = best.as_secs_f64();
+        println!(
+            "{read_size:>6}-byte reads: {:>8.3} ms, {:>7.1} MB/s, {:>5.2} M rows/s",
+            seconds * 1e3,
+            response.len() as f64 / seconds / 1e6,
+            ROWS as f64 / seconds / 1e6,
+        );
+    }
+}
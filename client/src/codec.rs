@@ -0,0 +1,206 @@
+use bytes::{Buf, BytesMut};
+use postgres_protocol::message::backend::{Header, Message};
+use std::io;
+
+/// Default cap on the size of a single backend message
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Tag of NegotiateProtocolVersion messages, which postgres_protocol can't parse
+const NEGOTIATE_PROTOCOL_VERSION_TAG: u8 = b'v';
+
+/// Contents of a NegotiateProtocolVersion message, which servers send during startup when they
+/// don't support the requested minor protocol version or some of the requested protocol
+/// options (startup parameters prefixed with _pq_.)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolNegotiation {
+    /// newest protocol version that the server supports, with the major version in the upper
+    /// 16 bits and the minor version in the lower 16 bits (as in the startup message)
+    pub newest_version: i32,
+    /// protocol options that the server didn't recognize (and ignored)
+    pub unrecognized_options: Vec<String>,
+}
+
+impl ProtocolNegotiation {
+    /// Parse the body of a NegotiateProtocolVersion message (everything after its header)
+    fn parse(mut body: &[u8]) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        if body.len() < 8 {
+            return Err(invalid("NegotiateProtocolVersion message is too short"));
+        }
+        let newest_version = body.get_i32();
+        let count = body.get_i32();
+
+        let mut unrecognized_options = Vec::new();
+        for _ in 0..count {
+            let end = body
+                .iter()
+                .position(|byte| *byte == 0)
+                .ok_or_else(|| invalid("unterminated option in NegotiateProtocolVersion"))?;
+            unrecognized_options.push(String::from_utf8_lossy(&body[..end]).into_owned());
+            body.advance(end + 1);
+        }
+
+        Ok(Self {
+            newest_version,
+            unrecognized_options,
+        })
+    }
+}
+
+/// Decoder for backend Messages, splitting each complete message off the front of a buffer
+/// of raw bytes. Like a tokio_util Decoder, partial messages are left in the buffer until
+/// enough bytes have arrived to parse them.
+#[derive(Debug)]
+pub struct BackendMessageCodec {
+    max_message_size: usize,
+    negotiation: Option<ProtocolNegotiation>,
+}
+
+impl Default for BackendMessageCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+impl BackendMessageCodec {
+    /// Create a codec that rejects messages larger than the maximum size (in bytes)
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            negotiation: None,
+        }
+    }
+
+    /// Change the maximum size (in bytes) of messages that this codec will accept
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// Take the NegotiateProtocolVersion message received since the last call, if any
+    pub fn take_negotiation(&mut self) -> Option<ProtocolNegotiation> {
+        self.negotiation.take()
+    }
+
+    /// Decode the next complete Message at the front of the buffer, if there is one.
+    /// NegotiateProtocolVersion messages are set aside for BackendMessageCodec::take_negotiation
+    /// instead, since postgres_protocol has no Message for them.
+    pub fn decode(&mut self, buffer: &mut BytesMut) -> io::Result<Option<Message>> {
+        loop {
+            match self.decode_next(buffer)? {
+                Some(Decoded::Negotiation(negotiation)) => self.negotiation = Some(negotiation),
+                Some(Decoded::Message(message)) => return Ok(Some(message)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Decode the next complete message of any kind at the front of the buffer
+    fn decode_next(&mut self, buffer: &mut BytesMut) -> io::Result<Option<Decoded>> {
+        // peek at the header to find out how long the next message is
+        let Some(header) = Header::parse(buffer)? else {
+            return Ok(None);
+        };
+
+        // the length in the header excludes the leading message type byte
+        let length = header.len() as usize + 1;
+        if length > self.max_message_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "backend message of {length} bytes exceeds the maximum message size of {} bytes",
+                    self.max_message_size
+                ),
+            ));
+        }
+        if buffer.len() < length {
+            // make room for the rest of the message ahead of time
+            buffer.reserve(length - buffer.len());
+            return Ok(None);
+        }
+
+        let mut message = buffer.split_to(length);
+        if header.tag() == NEGOTIATE_PROTOCOL_VERSION_TAG {
+            return ProtocolNegotiation::parse(&message[5..])
+                .map(|negotiation| Some(Decoded::Negotiation(negotiation)));
+        }
+        Ok(Message::parse(&mut message)?.map(Decoded::Message))
+    }
+}
+
+/// Complete backend message split off by BackendMessageCodec::decode_next
+enum Decoded {
+    /// any message that postgres_protocol can parse
+    Message(Message),
+    /// a NegotiateProtocolVersion message
+    Negotiation(ProtocolNegotiation),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ParameterStatus for client_encoding, followed by ReadyForQuery
+    const MESSAGES: &[u8] = b"S\0\0\0\x19client_encoding\0UTF8\0Z\0\0\0\x05I";
+
+    #[test]
+    fn headers_split_mid_length_wait_for_the_rest() {
+        let mut codec = BackendMessageCodec::default();
+        let mut buffer = BytesMut::from(&b"Z\0\0"[..]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert_eq!(&buffer[..], b"Z\0\0");
+
+        buffer.extend_from_slice(b"\0\x05I");
+        match codec.decode(&mut buffer).unwrap() {
+            Some(Message::ReadyForQuery(body)) => assert_eq!(body.status(), b'I'),
+            _ => panic!("expected ReadyForQuery"),
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn bodies_split_across_chunks_wait_for_the_rest() {
+        let mut codec = BackendMessageCodec::default();
+        let mut buffer = BytesMut::new();
+
+        // the ParameterStatus body arrives in three chunks, the last with part of the next message
+        for chunk in [&MESSAGES[..9], &MESSAGES[9..20]] {
+            buffer.extend_from_slice(chunk);
+            assert!(codec.decode(&mut buffer).unwrap().is_none());
+        }
+        buffer.extend_from_slice(&MESSAGES[20..29]);
+        match codec.decode(&mut buffer).unwrap() {
+            Some(Message::ParameterStatus(body)) => {
+                assert_eq!(body.name().unwrap(), "client_encoding");
+                assert_eq!(body.value().unwrap(), "UTF8");
+            }
+            _ => panic!("expected ParameterStatus"),
+        }
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+
+        buffer.extend_from_slice(&MESSAGES[29..]);
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Message::ReadyForQuery(_))
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn messages_arriving_a_byte_at_a_time_decode_in_order() {
+        let mut codec = BackendMessageCodec::default();
+        let mut buffer = BytesMut::new();
+        let mut tags = Vec::new();
+        for byte in MESSAGES {
+            buffer.extend_from_slice(&[*byte]);
+            while let Some(message) = codec.decode(&mut buffer).unwrap() {
+                tags.push(match message {
+                    Message::ParameterStatus(_) => 'S',
+                    Message::ReadyForQuery(_) => 'Z',
+                    _ => '?',
+                });
+            }
+        }
+        assert_eq!(tags, ['S', 'Z']);
+        assert!(buffer.is_empty());
+    }
+}
@@ -1,210 +1,382 @@
-use crate::log;
-use bytes::BytesMut;
-use fallible_iterator::FallibleIterator;
-use js_sys::Uint8Array;
-use postgres_protocol::{
-    authentication::sasl::{ChannelBinding, ScramSha256, SCRAM_SHA_256},
-    message::backend::{ErrorResponseBody, Header, Message},
+use crate::{
+    error::ConnectionError,
+    protocol::ConnectionCore,
+    query::{Column, BINARY_FORMAT, TEXT_FORMAT},
+    reader::StreamReader,
+    stream::WebTransportStream,
 };
-use std::convert::TryFrom;
-use wasm_bindgen::{JsCast, JsValue};
-use wasm_bindgen_futures::JsFuture;
-use web_sys::{
-    ReadableStreamDefaultReader, WebTransportBidirectionalStream, WritableStreamDefaultWriter,
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use js_sys::{Reflect, Uint8Array};
+use postgres_protocol::message::backend::Message;
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
 };
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{ReadableStreamDefaultReader, WebTransport, WebTransportBidirectionalStream};
+
+/// Bytes that open a control stream with the proxy, which carries pings instead of Postgres
+/// messages (no Postgres message could ever start with them)
+const CONTROL_PREAMBLE: &[u8] = b"PGWT-CTL";
+
+/// Frame type of a control stream Ping, followed by a 4-byte big-endian nonce
+const PING: u8 = b'?';
+
+/// Frame type of a control stream Pong, followed by the Ping's nonce and an upstream status
+const PONG: u8 = b'!';
+
+/// Length of a Pong frame, including its type
+const PONG_LENGTH: usize = 6;
 
-/// WebTransport streams and a buffer of Messages combined into a database Connection
+/// Database Connection over a WebTransport stream, which runs the Postgres protocol through a
+/// ConnectionCore (and so reads messages, tracks server state, and authenticates through the
+/// same methods, by dereferencing to it) and adds everything that depends on the rest of the
+/// WebTransport session: control streams, datagrams, cancellation, and the session's close
+/// info. Connections are a Stream of backend Messages.
+///
+/// Reading and writing are cancellation-safe at the level of single messages (see
+/// ConnectionCore). Higher-level operations that exchange several messages (e.g.
+/// Connection::query) are not cancellation-safe, though: dropping one part-way leaves the rest
+/// of its responses to be read by whatever runs next, so those Connections should be closed
+/// rather than reused.
 pub struct Connection {
-    read: ReadableStreamDefaultReader,
-    write: WritableStreamDefaultWriter,
-    pending: BytesMut,
+    transport: WebTransport,
+    core: ConnectionCore<WebTransportStream>,
+    pub(crate) statements: HashMap<String, String>,
+    pub(crate) schemas: HashMap<String, Rc<[Column]>>,
+    pub(crate) described: Option<Rc<[Column]>>,
+    pub(crate) queued: Vec<BytesMut>,
+    pub(crate) result_format: i16,
+    pub(crate) strict_text: bool,
+    datagrams: Option<ReadableStreamDefaultReader>,
+    close_info: Rc<RefCell<Option<CloseInfo>>>,
 }
 
 impl Connection {
-    /// Send Bytes of data to the writable stream
-    pub async fn encode(&self, data: BytesMut) -> Result<(), JsValue> {
-        let message = Uint8Array::new_with_length(data.len() as u32);
-        message.copy_from(&data);
-        JsFuture::from(self.write.write_with_chunk(&message)).await?;
-        Ok(())
+    /// Create a new Connection over a stream of a WebTransport session
+    fn new(transport: WebTransport, stream: WebTransportStream) -> Self {
+        let close_info = Rc::default();
+        watch_close(&transport, Rc::clone(&close_info));
+        Self {
+            transport,
+            core: ConnectionCore::new(stream),
+            statements: HashMap::new(),
+            schemas: HashMap::new(),
+            described: None,
+            queued: Vec::new(),
+            result_format: TEXT_FORMAT,
+            strict_text: false,
+            datagrams: None,
+            close_info,
+        }
     }
 
-    /// Read the next backend message from the stream
-    // TODO: rewrite this as a Framed stream + Codec
-    pub async fn decode(&mut self) -> Result<Option<Message>, JsValue> {
-        loop {
-            match self.decode_pending()? {
-                Some(message) => return Ok(Some(message)),
-                None => {
-                    // if there's not at least a message's worth of data, wait for another chunk from the stream
-                    let chunk = JsFuture::from(self.read.read()).await?;
-                    let value = js_sys::Reflect::get(&chunk, &"value".into())
-                        .map(|value| Uint8Array::new(&value))?;
-                    let mut buffer = BytesMut::with_capacity(value.length() as usize);
-                    unsafe {
-                        // SAFETY: the Uint8Array containing this data requires equal length
-                        buffer.set_len(value.length() as usize);
-                    }
-                    value.copy_to(&mut buffer);
-                    log(&format!("chunk fetched of size {}", buffer.len()));
-                    self.pending.extend_from_slice(&buffer);
-                }
-            }
+    /// Send Bytes of data to the writable stream, after any queued messages (see
+    /// Connection::send_parse). Cancellation-safe: the data is either handed to the stream in
+    /// full or not at all, even if the returned future is dropped.
+    pub async fn encode(&mut self, data: BytesMut) -> Result<(), ConnectionError> {
+        if self.queued.is_empty() {
+            self.core.encode(data).await
+        } else {
+            self.encode_batch(&[data]).await
         }
     }
 
-    /// Decode a single message from the pending queue without re-fetching the data from upstream
-    fn decode_pending(&mut self) -> Result<Option<Message>, JsValue> {
-        // attempt to extract a header from the queue
-        let header = Header::parse(&self.pending).map_err(|error| {
-            JsValue::from(format!(
-                "Error parsing the header from a backend message: {error}"
-            ))
-        })?;
+    /// Send several buffers of encoded frontend messages (after any queued messages) to the
+    /// writable stream as a single chunk, crossing into JS for one write rather than one write
+    /// per buffer. The bytes are still copied into JS memory once: a view over WASM memory
+    /// can't be handed to the stream, since the stream holds on to its chunks after the write
+    /// call returns, and any allocation may move (and so invalidate) WASM memory in the
+    /// meantime. Queued messages are discarded along with the rest of the batch if the
+    /// returned future is dropped before the write starts.
+    pub async fn encode_batch(&mut self, buffers: &[BytesMut]) -> Result<(), ConnectionError> {
+        let queued = std::mem::take(&mut self.queued);
+        let mut batch =
+            BytesMut::with_capacity(queued.iter().chain(buffers).map(BytesMut::len).sum());
+        for buffer in queued.iter().chain(buffers) {
+            batch.extend_from_slice(buffer);
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.core.encode(batch).await
+    }
+
+    /// Gracefully end the Connection by sending a Terminate message, then closing both halves
+    /// of its WebTransport stream so that the proxy and server see a clean shutdown
+    pub async fn close(mut self) -> Result<(), ConnectionError> {
+        self.core.close().await?;
+        JsFuture::from(self.core.get_ref().cancel()).await?;
+        Ok(())
+    }
+
+    /// Request results in binary format rather than text from Connection::query and friends,
+    /// decoding binary values of common types straight into JS values (see Row::to_js)
+    pub fn set_binary_results(&mut self, binary: bool) {
+        self.result_format = if binary { BINARY_FORMAT } else { TEXT_FORMAT };
+    }
+
+    /// Fail to convert rows holding text values that aren't valid UTF-8, rather than replacing
+    /// the invalid sequences with U+FFFD (see Row::to_js)
+    pub fn set_strict_text(&mut self, strict: bool) {
+        self.strict_text = strict;
+    }
 
-        match header {
-            // parse the Message if we have enough data to work with
-            Some(header) if self.pending.len() >= (header.len() as usize + 1) => {
-                return Message::parse(&mut self.pending.split_to(header.len() as usize + 1))
-                    .map_err(|error| {
-                        JsValue::from(format!(
-                            "Error parsing the next message from the backend: {error}"
-                        ))
-                    });
+    /// Send an unreliable datagram over the Connection's WebTransport session. Datagrams may be
+    /// dropped or reordered, so they're only suitable for lossy signaling (e.g. pings) and
+    /// must never carry Postgres protocol messages.
+    pub async fn send_datagram(&self, data: &[u8]) -> Result<(), ConnectionError> {
+        let write = self.transport.datagrams().writable().get_writer()?;
+        let result = JsFuture::from(write.write_with_chunk(&Uint8Array::from(data))).await;
+        write.release_lock();
+        result?;
+        Ok(())
+    }
+
+    /// Stream unreliable datagrams received over the Connection's WebTransport session.
+    /// Like Connection::send_datagram, these are lossy and unrelated to the Postgres protocol.
+    pub fn datagrams(
+        &mut self,
+    ) -> Result<impl Stream<Item = Result<Bytes, ConnectionError>> + '_, ConnectionError> {
+        let read = match self.datagrams.take() {
+            Some(read) => read,
+            None => self
+                .transport
+                .datagrams()
+                .readable()
+                .get_reader()
+                .dyn_into::<ReadableStreamDefaultReader>()
+                .map_err(JsValue::from)?,
+        };
+        let read = &*self.datagrams.insert(read);
+
+        Ok(futures::stream::unfold(read, |read| async move {
+            let chunk = match JsFuture::from(read.read()).await.and_then(read_chunk) {
+                Ok(chunk) => chunk?,
+                Err(error) => return Some((Err(error.into()), read)),
+            };
+            Some((Ok(Bytes::from(chunk.to_vec())), read))
+        }))
+    }
+
+    /// Get the code and reason that the proxy closed the WebTransport session with, once it
+    /// has closed the session on purpose (e.g. after rejecting the client)
+    pub fn close_info(&self) -> Option<CloseInfo> {
+        self.close_info.borrow().clone()
+    }
+
+    /// Ping the proxy over a fresh control stream of the same WebTransport session, checking
+    /// that the proxy is responsive and can still reach this Connection's upstream without
+    /// touching the Connection's own stream
+    pub async fn ping(&self) -> Result<Pong, ConnectionError> {
+        let pair: WebTransportBidirectionalStream =
+            JsFuture::from(self.transport.create_bidirectional_stream())
+                .await?
+                .into();
+        let mut read = StreamReader::new(&pair.readable())?;
+        let write = pair.writable().get_writer()?;
+
+        let nonce = (js_sys::Math::random() * f64::from(u32::MAX)) as u32;
+        let mut ping = CONTROL_PREAMBLE.to_vec();
+        ping.push(PING);
+        ping.extend_from_slice(&nonce.to_be_bytes());
+        let started = js_sys::Date::now();
+        JsFuture::from(write.write_with_chunk(&Uint8Array::from(&ping[..]))).await?;
+
+        let mut pong = Vec::with_capacity(PONG_LENGTH);
+        while pong.len() < PONG_LENGTH {
+            let result = JsFuture::from(read.read()).await?;
+            match read.chunk(result)? {
+                Some(chunk) => pong.extend(chunk.to_vec()),
+                None => return Err(ConnectionError::closed("Control stream closed before Pong")),
             }
+        }
+        let round_trip_ms = js_sys::Date::now() - started;
+        let _ = JsFuture::from(write.close()).await;
 
-            // if there's not at least a message's worth of data, we're done
-            _ => Ok(None),
+        if pong[0] != PONG || pong[1..5] != nonce.to_be_bytes() {
+            return Err(ConnectionError::protocol("Unexpected response to Ping"));
         }
+        Ok(Pong {
+            upstream_reachable: pong[5] == 0,
+            round_trip_ms,
+        })
+    }
+
+    /// Get a token for cancelling queries on this Connection from elsewhere, e.g. while a
+    /// long-running query holds this Connection
+    pub fn cancel_token(&self) -> Result<CancelToken, ConnectionError> {
+        let (process_id, secret_key) = self.backend_key().ok_or_else(|| {
+            ConnectionError::protocol("No backend key data was received during startup")
+        })?;
+
+        Ok(CancelToken {
+            transport: self.transport.clone(),
+            process_id,
+            secret_key,
+        })
     }
 }
 
-/// Connection-preparing wrapper around the startup sequence of a database Connection.
-/// Full Connections should only be derived by successfully completing a Startup.
-pub struct Startup(Connection);
+impl Deref for Connection {
+    type Target = ConnectionCore<WebTransportStream>;
 
-impl Startup {
-    /// Run through the startup and auth sequences to prepare a Connection for real use
-    // TODO: handle this on the proxy side instead of here
-    pub async fn start(
-        mut self,
-        params: Vec<(&'static str, &'static str)>,
-    ) -> Result<Connection, JsValue> {
-        // send the startup message
-        let mut buffer = BytesMut::new();
-        postgres_protocol::message::frontend::startup_message(params, &mut buffer)
-            .map_err(|error| JsValue::from(format!("Error generating startup message: {error}")))?;
-        self.0.encode(buffer).await?;
-
-        // handle the next message for authentication
-        match self.0.decode().await? {
-            Some(Message::AuthenticationSasl(_body)) => sasl(&mut self.0).await?,
-            Some(_) => return Err(JsValue::from("Unsupported backend message type")),
-            None => return Err(JsValue::from("Connection closed")),
-        }
+    fn deref(&self) -> &Self::Target {
+        &self.core
+    }
+}
 
-        // return the inner connection
-        Ok(self.0)
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.core
     }
 }
 
-/// Generate a Startup connection from a bidirectional stream, if possible
-impl TryFrom<WebTransportBidirectionalStream> for Startup {
-    type Error = JsValue;
+/// Extract the value of a chunk read from a ReadableStream, or None once the stream is done
+fn read_chunk(chunk: JsValue) -> Result<Option<Uint8Array>, JsValue> {
+    let done = js_sys::Reflect::get(&chunk, &"done".into())?;
+    if done.as_bool().unwrap_or_default() {
+        return Ok(None);
+    }
 
-    fn try_from(stream: WebTransportBidirectionalStream) -> Result<Self, Self::Error> {
-        let read = stream
-            .readable()
-            .get_reader()
-            .dyn_into::<ReadableStreamDefaultReader>()?;
+    js_sys::Reflect::get(&chunk, &"value".into()).map(|value| Some(Uint8Array::new(&value)))
+}
 
-        let write = stream.writable().get_writer()?;
+/// Application error code and reason that a WebTransport session was closed with
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloseInfo {
+    /// code chosen by the proxy: 1 when the session's path has no upstream, or 3 when the
+    /// startup message broke the proxy's allowlist
+    pub close_code: u32,
+    /// human-readable reason for closing the session
+    pub reason: String,
+}
 
-        Ok(Self(Connection {
-            read,
-            write,
-            pending: BytesMut::new(),
-        }))
+/// Proxy's answer to a Connection::ping
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pong {
+    /// whether the proxy could open a connection to the upstream database
+    pub upstream_reachable: bool,
+    /// milliseconds between sending the Ping and receiving the Pong
+    pub round_trip_ms: f64,
+}
+
+/// Record the code and reason of a WebTransport session once it closes cleanly. Sessions that
+/// fail instead (e.g. along with their whole QUIC connection) reject their closed Promise,
+/// leaving nothing to record.
+fn watch_close(transport: &WebTransport, close_info: Rc<RefCell<Option<CloseInfo>>>) {
+    let closed = JsFuture::from(transport.closed());
+    wasm_bindgen_futures::spawn_local(async move {
+        let Ok(info) = closed.await else {
+            return;
+        };
+        let field = |name: &str| Reflect::get(&info, &name.into()).unwrap_or_default();
+        *close_info.borrow_mut() = Some(CloseInfo {
+            close_code: field("closeCode").as_f64().unwrap_or_default() as u32,
+            reason: field("reason").as_string().unwrap_or_default(),
+        });
+    });
+}
+
+/// Read backend Messages from the Connection until the readable stream is exhausted
+impl Stream for Connection {
+    type Item = Result<Message, ConnectionError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.core).poll_next(context)
     }
 }
 
-/// Handle SASL-based authentication
-async fn sasl(connection: &mut Connection) -> Result<(), JsValue> {
-    // send the initial SASL message
-    let mut buffer = BytesMut::new();
-    let mut scram = ScramSha256::new(b"supersecretpassword", ChannelBinding::unsupported());
-    postgres_protocol::message::frontend::sasl_initial_response(
-        SCRAM_SHA_256,
-        scram.message(),
-        &mut buffer,
-    )
-    .map_err(|error| {
-        JsValue::from(format!(
-            "Error writing SASL initial response message: {error}"
-        ))
-    })?;
-    connection.encode(buffer).await?;
-
-    // get the body of the SASL continuation
-    let body = match connection.decode().await? {
-        Some(Message::AuthenticationSaslContinue(body)) => body,
-        Some(Message::ErrorResponse(body)) => return Err(format_error(body)),
-        Some(_) => return Err(JsValue::from("Unexpected message during SASL handshake")),
-        None => return Err(JsValue::from("Connection closed during authentication")),
-    };
-    scram
-        .update(body.data())
-        .map_err(|error| JsValue::from(format!("Error continuing SASL handshake: {error}")))?;
-
-    // send the SASL response to the server again
-    let mut buffer = BytesMut::new();
-    postgres_protocol::message::frontend::sasl_response(scram.message(), &mut buffer)
-        .map_err(|error| JsValue::from(format!("Error writing SASL response message: {error}")))?;
-    connection.encode(buffer).await?;
-
-    // get the body of the SASL finalizer
-    let body = match connection.decode().await? {
-        Some(Message::AuthenticationSaslFinal(body)) => body,
-        Some(Message::ErrorResponse(body)) => return Err(format_error(body)),
-        Some(_) => {
-            return Err(JsValue::from(
-                "Unexpected message finalizing SASL handshake",
-            ))
-        }
-        None => return Err(JsValue::from("Connection closed during authentication")),
-    };
-    scram
-        .finish(body.data())
-        .map_err(|error| JsValue::from(format!("Error finalizing SASL handshake: {error}")))?;
-
-    // read the connection information from the stream until we get to a terminal state
-    loop {
-        match connection.decode().await? {
-            Some(
-                Message::BackendKeyData(..)
-                | Message::ParameterStatus(..)
-                | Message::AuthenticationOk,
-            ) => {
-                // TODO: use the backend or parameter data
-            }
-            Some(Message::ReadyForQuery(..)) => return Ok(()),
-            Some(Message::ErrorResponse(body)) => return Err(format_error(body)),
-            Some(_) => return Err(JsValue::from("Unexpected backend message type")),
-            None => return Err(JsValue::from("Connection closed during authentication")),
-        }
+/// Connections that are dropped without Connection::close still send a Terminate message and
+/// close their streams, but only on a best-effort basis: Drop can't wait for async work, so
+/// the writes are queued on the stream without waiting to see whether they succeed.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut buffer = BytesMut::new();
+        postgres_protocol::message::frontend::terminate(&mut buffer);
+        self.core.get_mut().close_now(&buffer);
+    }
+}
+
+/// Handle for cancelling the running query of a Connection. Cancellation only works if the
+/// proxy sends the cancelling stream to the same upstream server as the original Connection,
+/// which holds as long as every stream of a WebTransport session is routed to one server.
+#[wasm_bindgen]
+pub struct CancelToken {
+    transport: WebTransport,
+    process_id: i32,
+    secret_key: i32,
+}
+
+#[wasm_bindgen]
+impl CancelToken {
+    /// Cancel the query currently running on the original Connection (if any) by sending a
+    /// CancelRequest on a fresh stream of the same WebTransport session
+    pub async fn cancel(&self) -> Result<(), ConnectionError> {
+        // open a new stream for the CancelRequest, since the backend never responds to it
+        let pair: WebTransportBidirectionalStream =
+            JsFuture::from(self.transport.create_bidirectional_stream())
+                .await?
+                .into();
+        let write = pair.writable().get_writer()?;
+
+        let mut buffer = BytesMut::new();
+        postgres_protocol::message::frontend::cancel_request(
+            self.process_id,
+            self.secret_key,
+            &mut buffer,
+        );
+        let message = Uint8Array::new_with_length(buffer.len() as u32);
+        message.copy_from(&buffer);
+        JsFuture::from(write.write_with_chunk(&message)).await?;
+        JsFuture::from(write.close()).await?;
+
+        Ok(())
     }
 }
 
-/// Format Error response bodies as useful JsValues for logging
-fn format_error(body: ErrorResponseBody) -> JsValue {
-    let mut fields = body.fields();
-    let mut errors = "Errors: ".to_string();
+/// Connection-preparing wrapper around the startup sequence of a database Connection.
+/// Full Connections should only be derived by successfully completing a Startup.
+pub struct Startup(Connection);
 
-    while let Ok(Some(field)) = fields.next() {
-        // this is silly, but it works for now
-        errors.push_str(field.value());
-        errors.push_str(" ");
+impl Startup {
+    /// Run through the startup and auth sequences to prepare a Connection for real use (see
+    /// ConnectionCore::startup)
+    // TODO: handle this on the proxy side instead of here
+    pub async fn start(
+        mut self,
+        params: &[(&str, &str)],
+        password: &[u8],
+        min_iterations: u32,
+    ) -> Result<Connection, ConnectionError> {
+        self.0
+            .core
+            .startup(params, password, min_iterations)
+            .await?;
+        Ok(self.0)
     }
+}
+
+impl Startup {
+    /// Open a new bidirectional stream on a WebTransport session to start a Connection
+    pub async fn open(transport: &WebTransport) -> Result<Self, ConnectionError> {
+        let pair: WebTransportBidirectionalStream =
+            JsFuture::from(transport.create_bidirectional_stream())
+                .await?
+                .into();
 
-    JsValue::from(&errors)
+        let stream = WebTransportStream::new(&pair)?;
+
+        Ok(Self(Connection::new(transport.clone(), stream)))
+    }
 }
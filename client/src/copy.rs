@@ -0,0 +1,227 @@
+use crate::{
+    connection::Connection,
+    error::ConnectionError,
+    protocol::format_error,
+    query::{command_tag, rows_affected},
+};
+use bytes::{Bytes, BytesMut};
+use futures::{FutureExt, Stream};
+use postgres_protocol::message::{backend::Message, frontend};
+
+/// Writer for the data of a COPY ... FROM STDIN statement, holding the Connection until the
+/// copy is either finished or failed
+pub struct CopyInWriter<'a> {
+    connection: &'a mut Connection,
+    finished: bool,
+}
+
+impl CopyInWriter<'_> {
+    /// Send a chunk of data in the COPY format (chunks don't need to line up with rows)
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), ConnectionError> {
+        // the server rejects bad data as soon as it sees it, so surface any error it has already
+        // sent before sending more data that would only be discarded
+        if let Some(message) = self.connection.decode().now_or_never() {
+            match message? {
+                Some(Message::ErrorResponse(body)) => {
+                    let error = format_error(body);
+                    self.finished = true;
+                    finish(self.connection).await?;
+                    return Err(error);
+                }
+                Some(_) => return Err(ConnectionError::protocol("Unexpected message during COPY")),
+                None => return Err(ConnectionError::closed("Connection closed during COPY")),
+            }
+        }
+
+        let mut buffer = BytesMut::new();
+        frontend::CopyData::new(data)
+            .map_err(|error| {
+                ConnectionError::invalid_input(format!(
+                    "Failed to generate CopyData message: {error}"
+                ))
+            })?
+            .write(&mut buffer);
+        self.connection.encode(buffer).await
+    }
+
+    /// Finish the copy, returning the number of rows copied
+    pub async fn finish(mut self) -> Result<u64, ConnectionError> {
+        self.finished = true;
+        let mut buffer = BytesMut::new();
+        frontend::copy_done(&mut buffer);
+        self.connection.encode(buffer).await?;
+        finish(self.connection).await
+    }
+
+    /// Abort the copy with a reason, rolling back every row sent so far
+    pub async fn fail(mut self, reason: &str) -> Result<(), ConnectionError> {
+        let mut buffer = BytesMut::new();
+        frontend::copy_fail(reason, &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate CopyFail message: {error}"))
+        })?;
+        self.finished = true;
+        self.connection.encode(buffer).await?;
+
+        // the server answers a CopyFail with an error of its own, which is expected here
+        match finish(self.connection).await {
+            Ok(_) => Err(ConnectionError::protocol(
+                "COPY completed despite being failed",
+            )),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Drop can't wait for async work, so CopyInWriters that are dropped before the copy is
+/// finished or failed queue a CopyFail without waiting, rolling back every row sent so far and
+/// skipping the server's response to it
+impl Drop for CopyInWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let mut buffer = BytesMut::new();
+            frontend::copy_fail("COPY was abandoned by the client", &mut buffer)
+                .expect("CopyFail reason contains no null bytes");
+            self.connection.abandon(&buffer);
+        }
+    }
+}
+
+/// Connection running a COPY ... TO STDOUT statement, for the stream of copied data to hold
+/// until the copy is done
+struct CopyOut<'a> {
+    connection: &'a mut Connection,
+    finished: bool,
+}
+
+/// Streams of copied data that are dropped before the copy is done skip whatever the server
+/// still sends for it (there's no way to stop a COPY ... TO STDOUT short of cancelling it)
+impl Drop for CopyOut<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.connection.skip_response();
+        }
+    }
+}
+
+impl Connection {
+    /// Start a COPY ... FROM STDIN statement, returning a writer for the copied data
+    pub async fn copy_in(&mut self, sql: &str) -> Result<CopyInWriter<'_>, ConnectionError> {
+        self.start_copy(sql).await?;
+        match self.decode().await? {
+            Some(Message::CopyInResponse(..)) => Ok(CopyInWriter {
+                connection: self,
+                finished: false,
+            }),
+            message => Err(unexpected(self, message).await),
+        }
+    }
+
+    /// Start a COPY ... TO STDOUT statement, streaming each chunk of copied data (usually one
+    /// row per chunk) until the copy is done
+    pub async fn copy_out(
+        &mut self,
+        sql: &str,
+    ) -> Result<impl Stream<Item = Result<Bytes, ConnectionError>> + '_, ConnectionError> {
+        self.start_copy(sql).await?;
+        match self.decode().await? {
+            Some(Message::CopyOutResponse(..)) => {}
+            message => return Err(unexpected(self, message).await),
+        }
+
+        let copy = CopyOut {
+            connection: self,
+            finished: false,
+        };
+        Ok(futures::stream::unfold(Some(copy), |copy| async {
+            let mut copy = copy?;
+            let message = match copy.connection.decode().await {
+                Ok(message) => message,
+                Err(error) => return Some((Err(error), None)),
+            };
+            match message {
+                Some(Message::CopyData(body)) => Some((Ok(body.into_bytes()), Some(copy))),
+                Some(Message::CopyDone) => {
+                    let result = finish(copy.connection).await;
+                    copy.finished = true;
+                    match result {
+                        Ok(_) => None,
+                        Err(error) => Some((Err(error), None)),
+                    }
+                }
+                Some(Message::ErrorResponse(body)) => {
+                    let error = format_error(body);
+                    let result = finish(copy.connection).await;
+                    copy.finished = true;
+                    Some((result.and(Err(error)), None))
+                }
+                Some(_) => Some((
+                    Err(ConnectionError::protocol("Unexpected message during COPY")),
+                    None,
+                )),
+                None => Some((
+                    Err(ConnectionError::closed("Connection closed during COPY")),
+                    None,
+                )),
+            }
+        }))
+    }
+
+    /// Send a COPY statement through the simple query protocol, which is the only way to run
+    /// COPY without a Sync after every chunk
+    async fn start_copy(&mut self, sql: &str) -> Result<(), ConnectionError> {
+        let mut buffer = BytesMut::new();
+        frontend::query(sql, &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Query message: {error}"))
+        })?;
+        self.encode(buffer).await
+    }
+}
+
+/// Read the end of a COPY statement up to ReadyForQuery, returning the number of rows copied
+async fn finish(connection: &mut Connection) -> Result<u64, ConnectionError> {
+    let mut rows = 0;
+    let mut error = None;
+    loop {
+        match connection.decode().await? {
+            Some(Message::CommandComplete(body)) => {
+                // COPY command tags are formatted as "COPY <rows>"
+                rows = rows_affected(&command_tag(body)?).unwrap_or_default();
+            }
+            Some(Message::ErrorResponse(body)) => error = Some(format_error(body)),
+            Some(Message::ReadyForQuery(..)) => break,
+            Some(_) => {}
+            None => return Err(ConnectionError::closed("Connection closed during COPY")),
+        }
+    }
+
+    match error {
+        Some(error) => Err(error),
+        None => Ok(rows),
+    }
+}
+
+/// Turn the wrong response to a COPY statement into an error, reading the rest of the response
+/// so that the Connection is ready for the next query
+async fn unexpected(connection: &mut Connection, message: Option<Message>) -> ConnectionError {
+    let error = match message {
+        Some(Message::ErrorResponse(body)) => format_error(body),
+        Some(Message::CopyInResponse(..)) => {
+            // the server is waiting for data that will never come, so abort its copy
+            let mut buffer = BytesMut::new();
+            frontend::copy_fail("expected COPY ... TO STDOUT", &mut buffer)
+                .expect("CopyFail reason contains no null bytes");
+            if let Err(error) = connection.encode(buffer).await {
+                return error;
+            }
+            ConnectionError::protocol(
+                "Statement was a COPY ... FROM STDIN, not a COPY ... TO STDOUT",
+            )
+        }
+        Some(_) => ConnectionError::protocol("Statement was not the expected kind of COPY"),
+        None => return ConnectionError::closed("Connection closed during COPY"),
+    };
+
+    // the original error is more useful than anything reported while cleaning up
+    let _ = finish(connection).await;
+    error
+}
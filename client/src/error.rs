@@ -0,0 +1,178 @@
+use js_sys::Reflect;
+use serde::Serialize;
+use std::fmt;
+use wasm_bindgen::{JsCast, JsValue};
+
+/// Errors from a Connection, grouped by kind so that JS callers can branch on them. Every
+/// ConnectionError is handed to JS as an Error object with extra kind and sqlstate properties.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// the server rejected the client's credentials, or the authentication exchange failed
+    Authentication {
+        message: String,
+        sqlstate: Option<String>,
+    },
+    /// the server sent a message that breaks the Postgres protocol (or wasn't expected)
+    Protocol(String),
+    /// the server reported an error, e.g. for a failed query
+    Server(Box<ServerError>),
+    /// the connection closed before the server finished responding
+    Closed(String),
+    /// the WebTransport session or one of its streams failed
+    Stream(String),
+    /// the caller passed a value that can't be sent to the server
+    InvalidInput(String),
+}
+
+impl ConnectionError {
+    /// Create an authentication error that didn't come from the server
+    pub fn authentication(message: impl Into<String>) -> Self {
+        Self::Authentication {
+            message: message.into(),
+            sqlstate: None,
+        }
+    }
+
+    /// Create a protocol error
+    pub fn protocol(message: impl Into<String>) -> Self {
+        Self::Protocol(message.into())
+    }
+
+    /// Create an error for a connection that closed unexpectedly
+    pub fn closed(message: impl Into<String>) -> Self {
+        Self::Closed(message.into())
+    }
+
+    /// Create an error for invalid caller input
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::InvalidInput(message.into())
+    }
+
+    /// Name of this error's kind, as exposed to JS
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Authentication { .. } => "authentication",
+            Self::Protocol(..) => "protocol",
+            Self::Server(..) => "server",
+            Self::Closed(..) => "closed",
+            Self::Stream(..) => "stream",
+            Self::InvalidInput(..) => "invalid_input",
+        }
+    }
+
+    /// SQLSTATE code of the error, if it came from the server
+    pub fn sqlstate(&self) -> Option<&str> {
+        match self {
+            Self::Authentication { sqlstate, .. } => sqlstate.as_deref(),
+            Self::Server(error) => Some(&error.code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Authentication { message, .. } => {
+                write!(formatter, "Authentication failed: {message}")
+            }
+            Self::Server(error) => error.fmt(formatter),
+            Self::Protocol(message)
+            | Self::Closed(message)
+            | Self::Stream(message)
+            | Self::InvalidInput(message) => formatter.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+/// Treat errors thrown by WebTransport APIs as stream errors
+impl From<JsValue> for ConnectionError {
+    fn from(error: JsValue) -> Self {
+        let message = match error.dyn_ref::<js_sys::Error>() {
+            Some(error) => String::from(error.message()),
+            None => error.as_string().unwrap_or_else(|| format!("{error:?}")),
+        };
+        Self::Stream(message)
+    }
+}
+
+/// Treat failures of the byte stream under a ConnectionCore as stream errors
+impl From<std::io::Error> for ConnectionError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Stream(error.to_string())
+    }
+}
+
+/// Convert errors into JS Error objects with { kind, sqlstate } properties, along with the
+/// severity, detail, hint, and position of server errors
+impl From<ConnectionError> for JsValue {
+    fn from(error: ConnectionError) -> Self {
+        let object = js_sys::Error::new(&error.to_string());
+        let sqlstate = error.sqlstate().map_or(JsValue::NULL, JsValue::from);
+        // setting properties on a fresh Error object can't fail
+        let _ = Reflect::set(&object, &"kind".into(), &error.kind().into());
+        let _ = Reflect::set(&object, &"sqlstate".into(), &sqlstate);
+        if let ConnectionError::Server(error) = &error {
+            let optional =
+                |value: &Option<String>| value.as_deref().map_or(JsValue::NULL, JsValue::from);
+            let position = error.position.map_or(JsValue::NULL, JsValue::from);
+            let _ = Reflect::set(&object, &"severity".into(), &error.severity.as_str().into());
+            let _ = Reflect::set(&object, &"detail".into(), &optional(&error.detail));
+            let _ = Reflect::set(&object, &"hint".into(), &optional(&error.hint));
+            let _ = Reflect::set(&object, &"position".into(), &position);
+        }
+        object.into()
+    }
+}
+
+/// Fields of an ErrorResponse (or NoticeResponse) from the server, keyed by their field codes
+/// in the protocol. Only severity, code, and message are always sent; the rest depend on the
+/// error.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerError {
+    /// S: severity (ERROR, FATAL, or PANIC for errors, and WARNING, NOTICE, DEBUG, INFO, or LOG
+    /// for notices), possibly localized
+    pub severity: String,
+    /// C: SQLSTATE code
+    pub code: String,
+    /// M: primary human-readable message
+    pub message: String,
+    /// D: secondary message with more detail about the problem
+    pub detail: Option<String>,
+    /// H: suggestion for fixing the problem
+    pub hint: Option<String>,
+    /// P: 1-based character index of the error in the original query string
+    pub position: Option<u32>,
+    /// W: context of the error, e.g. a call stack of PL/pgSQL functions
+    #[serde(rename = "where")]
+    pub where_: Option<String>,
+    /// s: schema of the object associated with the error
+    pub schema: Option<String>,
+    /// t: table associated with the error
+    pub table: Option<String>,
+    /// c: column associated with the error
+    pub column: Option<String>,
+    /// d: data type associated with the error
+    pub data_type: Option<String>,
+    /// n: constraint associated with the error
+    pub constraint: Option<String>,
+}
+
+/// Format server errors like psql does, with any detail and hint on lines of their own
+impl fmt::Display for ServerError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}: {}", self.severity, self.message)?;
+        if let Some(detail) = &self.detail {
+            write!(formatter, "\nDETAIL: {detail}")?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(formatter, "\nHINT: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ServerError {}
@@ -0,0 +1,218 @@
+use crate::{
+    connection::Connection,
+    error::{ConnectionError, ServerError},
+    protocol::{server_error, TransactionStatus},
+    query::{bind, command_tag, Column, Param, Row, TEXT_FORMAT},
+};
+use bytes::BytesMut;
+use fallible_iterator::FallibleIterator;
+use js_sys::{Object, Reflect};
+use postgres_protocol::{
+    message::{backend::Message, frontend},
+    Oid,
+};
+use std::rc::Rc;
+use wasm_bindgen::JsValue;
+
+/// A single backend response to the messages of the extended query protocol, as read by
+/// Connection::next_response
+#[derive(Debug)]
+pub enum Response {
+    /// a Parse succeeded
+    ParseComplete,
+    /// a Bind succeeded
+    BindComplete,
+    /// a Close succeeded
+    CloseComplete,
+    /// the parameter types of a described statement
+    ParameterDescription(Vec<Oid>),
+    /// the columns of a described statement or portal, used for every DataRow that follows
+    RowDescription(Rc<[Column]>),
+    /// the described statement or portal returns no rows
+    NoData,
+    /// a row returned by an Execute
+    DataRow(Row),
+    /// an Execute finished, with its command tag
+    CommandComplete(String),
+    /// an Execute ran an empty query
+    EmptyQuery,
+    /// an Execute returned as many rows as it asked for, leaving the rest in the portal
+    PortalSuspended,
+    /// a message failed, after which the server discards every message until the next Sync
+    Error(Box<ServerError>),
+    /// a Sync was processed, and the server is ready for the next query
+    ReadyForQuery(TransactionStatus),
+}
+
+impl Response {
+    /// Convert this Response into a JS object with a type (e.g. "dataRow") and any payload:
+    /// parameterTypes, columns, row (in the same form as Row::to_js), commandTag, error, or
+    /// transactionStatus
+    pub fn to_js(&self) -> Result<JsValue, JsValue> {
+        let (kind, field, value) = match self {
+            Self::ParseComplete => ("parseComplete", None, JsValue::UNDEFINED),
+            Self::BindComplete => ("bindComplete", None, JsValue::UNDEFINED),
+            Self::CloseComplete => ("closeComplete", None, JsValue::UNDEFINED),
+            Self::ParameterDescription(types) => (
+                "parameterDescription",
+                Some("parameterTypes"),
+                serde_wasm_bindgen::to_value(types)?,
+            ),
+            Self::RowDescription(columns) => (
+                "rowDescription",
+                Some("columns"),
+                serde_wasm_bindgen::to_value(&**columns)?,
+            ),
+            Self::NoData => ("noData", None, JsValue::UNDEFINED),
+            Self::DataRow(row) => ("dataRow", Some("row"), row.to_js()?),
+            Self::CommandComplete(tag) => ("commandComplete", Some("commandTag"), tag.into()),
+            Self::EmptyQuery => ("emptyQuery", None, JsValue::UNDEFINED),
+            Self::PortalSuspended => ("portalSuspended", None, JsValue::UNDEFINED),
+            Self::Error(error) => (
+                "error",
+                Some("error"),
+                ConnectionError::Server(error.clone()).into(),
+            ),
+            Self::ReadyForQuery(status) => (
+                "readyForQuery",
+                Some("transactionStatus"),
+                status.as_str().into(),
+            ),
+        };
+
+        let response = Object::new();
+        Reflect::set(&response, &"type".into(), &kind.into())?;
+        if let Some(field) = field {
+            Reflect::set(&response, &field.into(), &value)?;
+        }
+        Ok(response.into())
+    }
+}
+
+/// Low-level access to the extended query protocol, sending one message at a time and reading
+/// one response at a time, for callers that need more control than Connection::query (e.g. to
+/// see results before the end of a batch of statements).
+///
+/// The server only answers a Sync with ReadyForQuery. A Flush makes the server send every
+/// response it has buffered so far, but without a ReadyForQuery, so callers that Flush must
+/// stop reading once they have the responses they asked for, rather than waiting for a
+/// ReadyForQuery that never comes. Once any message fails, the server discards every message
+/// up to the next Sync, so a Sync is needed to recover from an error either way.
+///
+/// Since the server holds on to its responses until a Flush or Sync, messages are queued
+/// rather than written one at a time, and the whole queue is written in a single batch with the
+/// next Flush or Sync (or before reading the next response). A pipeline of 100 messages then
+/// takes a single write to the stream instead of 100.
+impl Connection {
+    /// Queue a Parse message, preparing a statement (or the unnamed statement, if the name is
+    /// empty) with parameter types inferred by the server
+    pub fn send_parse(&mut self, name: &str, sql: &str) -> Result<(), ConnectionError> {
+        let mut buffer = BytesMut::new();
+        frontend::parse(name, sql, [], &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Parse message: {error}"))
+        })?;
+        self.queued.push(buffer);
+        Ok(())
+    }
+
+    /// Queue a Bind message, binding typed parameters to a portal (or the unnamed portal, if
+    /// the name is empty) for a prepared statement
+    pub fn send_bind(
+        &mut self,
+        portal: &str,
+        statement: &str,
+        params: &[Param],
+    ) -> Result<(), ConnectionError> {
+        let mut buffer = BytesMut::new();
+        bind(portal, statement, params, TEXT_FORMAT, &mut buffer)?;
+        self.queued.push(buffer);
+        Ok(())
+    }
+
+    /// Queue a Describe message for either a statement (b'S') or a portal (b'P')
+    pub fn send_describe(&mut self, variant: u8, name: &str) -> Result<(), ConnectionError> {
+        if !matches!(variant, b'S' | b'P') {
+            return Err(ConnectionError::invalid_input(
+                "Only statements (S) and portals (P) can be described",
+            ));
+        }
+        let mut buffer = BytesMut::new();
+        frontend::describe(variant, name, &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Describe message: {error}"))
+        })?;
+        self.queued.push(buffer);
+        Ok(())
+    }
+
+    /// Queue an Execute message, running a portal until it returns max_rows rows (or until it
+    /// finishes, if max_rows is 0)
+    pub fn send_execute(&mut self, portal: &str, max_rows: i32) -> Result<(), ConnectionError> {
+        let mut buffer = BytesMut::new();
+        frontend::execute(portal, max_rows, &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Execute message: {error}"))
+        })?;
+        self.queued.push(buffer);
+        Ok(())
+    }
+
+    /// Send a Flush message, asking the server to send every response it has buffered, along
+    /// with every queued message
+    pub async fn send_flush(&mut self) -> Result<(), ConnectionError> {
+        let mut buffer = BytesMut::new();
+        frontend::flush(&mut buffer);
+        self.encode(buffer).await
+    }
+
+    /// Send a Sync message, ending the current batch of messages (and its implicit
+    /// transaction, if any) with a ReadyForQuery, along with every queued message
+    pub async fn send_sync(&mut self) -> Result<(), ConnectionError> {
+        let mut buffer = BytesMut::new();
+        frontend::sync(&mut buffer);
+        self.encode(buffer).await
+    }
+
+    /// Read the next response to the extended query messages sent so far, or None once the
+    /// Connection closes. DataRows are described by the latest RowDescription.
+    pub async fn next_response(&mut self) -> Result<Option<Response>, ConnectionError> {
+        self.encode_batch(&[]).await?;
+        let response = match self.decode().await? {
+            Some(Message::ParseComplete) => Response::ParseComplete,
+            Some(Message::BindComplete) => Response::BindComplete,
+            Some(Message::CloseComplete) => Response::CloseComplete,
+            Some(Message::ParameterDescription(body)) => {
+                Response::ParameterDescription(body.parameters().collect().map_err(|error| {
+                    ConnectionError::protocol(format!(
+                        "Error parsing parameter description: {error}"
+                    ))
+                })?)
+            }
+            Some(Message::RowDescription(body)) => {
+                let columns = Column::parse(body)?;
+                self.described = Some(columns.clone());
+                Response::RowDescription(columns)
+            }
+            Some(Message::NoData) => Response::NoData,
+            Some(Message::DataRow(body)) => {
+                let columns = self.described.clone().ok_or_else(|| {
+                    ConnectionError::protocol("Data row returned before its row description")
+                })?;
+                Response::DataRow(Row::parse(columns, body, self.strict_text)?)
+            }
+            Some(Message::CommandComplete(body)) => Response::CommandComplete(command_tag(body)?),
+            Some(Message::EmptyQueryResponse) => Response::EmptyQuery,
+            Some(Message::PortalSuspended) => Response::PortalSuspended,
+            Some(Message::ErrorResponse(body)) => {
+                Response::Error(Box::new(server_error(body.fields())))
+            }
+            Some(Message::ReadyForQuery(..)) => Response::ReadyForQuery(self.transaction_status()),
+            Some(_) => {
+                return Err(ConnectionError::protocol(
+                    "Unexpected message returned from the extended query protocol",
+                ))
+            }
+            None => return Ok(None),
+        };
+
+        Ok(Some(response))
+    }
+}
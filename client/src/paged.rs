@@ -0,0 +1,144 @@
+use crate::{
+    connection::Connection,
+    error::ConnectionError,
+    protocol::format_error,
+    query::{bind, command_tag, Column, Param, QueryResult, Row},
+};
+use bytes::BytesMut;
+use postgres_protocol::message::{backend::Message, frontend};
+use std::rc::Rc;
+
+/// Pages of the rows returned by a statement, fetched from the server one page at a time by
+/// re-executing the statement's suspended portal. The whole statement runs before a single
+/// SYNC, so the server keeps the portal (and its implicit transaction) open between pages.
+pub struct Paged<'a> {
+    connection: &'a mut Connection,
+    start: Option<BytesMut>,
+    page_size: i32,
+    columns: Option<Rc<[Column]>>,
+    finished: bool,
+}
+
+impl Paged<'_> {
+    /// Fetch the next page of up to page_size rows, or None once every row has been fetched.
+    /// The last page carries the statement's command tag.
+    pub async fn next_page(&mut self) -> Result<Option<QueryResult>, ConnectionError> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        // the first page is requested along with the rest of the statement, and later pages
+        // re-execute the unnamed portal, with a FLUSH (not a SYNC) to get each page right away
+        let mut buffer = self.start.take().unwrap_or_default();
+        frontend::execute("", self.page_size, &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Execute message: {error}"))
+        })?;
+        frontend::flush(&mut buffer);
+        self.connection.encode(buffer).await?;
+
+        let mut rows = Vec::new();
+        loop {
+            match self.connection.decode().await? {
+                Some(Message::ParseComplete | Message::BindComplete) => {}
+                Some(Message::RowDescription(body)) => self.columns = Some(Column::parse(body)?),
+                Some(Message::NoData) => self.columns = Some(Rc::from([])),
+                Some(Message::DataRow(body)) => {
+                    let columns = self.columns.clone().ok_or_else(|| {
+                        ConnectionError::protocol("Data row returned before its row description")
+                    })?;
+                    rows.push(Row::parse(columns, body, self.connection.strict_text)?);
+                }
+                Some(Message::PortalSuspended) => return Ok(Some(self.page(rows, None))),
+                Some(Message::CommandComplete(body)) => {
+                    let tag = command_tag(body)?;
+                    self.finish().await?;
+                    return Ok(Some(self.page(rows, Some(tag))));
+                }
+                Some(Message::EmptyQueryResponse) => {
+                    self.finish().await?;
+                    return Ok(Some(self.page(rows, None)));
+                }
+                Some(Message::ErrorResponse(body)) => {
+                    let error = format_error(body);
+                    self.finish().await?;
+                    return Err(error);
+                }
+                Some(_) => {
+                    return Err(ConnectionError::protocol(
+                        "Unexpected message returned from the paged query",
+                    ))
+                }
+                None => return Err(ConnectionError::closed("Connection closed during query")),
+            }
+        }
+    }
+
+    /// Collect a page of rows under the statement's column descriptions
+    fn page(&self, rows: Vec<Row>, tag: Option<String>) -> QueryResult {
+        let columns = self.columns.clone().unwrap_or_else(|| Rc::from([]));
+        QueryResult::new(columns, rows, tag)
+    }
+
+    /// End the statement with a SYNC, reading everything up to the backend's ReadyForQuery
+    async fn finish(&mut self) -> Result<(), ConnectionError> {
+        self.finished = true;
+        let mut buffer = BytesMut::new();
+        frontend::sync(&mut buffer);
+        self.connection.encode(buffer).await?;
+        loop {
+            match self.connection.decode().await? {
+                Some(Message::ReadyForQuery(..)) => return Ok(()),
+                Some(_) => {}
+                None => return Err(ConnectionError::closed("Connection closed during query")),
+            }
+        }
+    }
+}
+
+/// Drop can't wait for async work, so Paged queries that are dropped before their last page
+/// queue a SYNC without waiting, closing the portal and skipping whatever the server still
+/// sends for it
+impl Drop for Paged<'_> {
+    fn drop(&mut self) {
+        if !self.finished && self.start.is_none() {
+            let mut buffer = BytesMut::new();
+            frontend::sync(&mut buffer);
+            self.connection.abandon(&buffer);
+        }
+    }
+}
+
+impl Connection {
+    /// Run a single statement with typed parameters like Connection::query, but fetch its rows
+    /// in pages of up to page_size rows each, keeping memory use bounded for large results.
+    /// Nothing is sent until the first page is requested.
+    pub fn query_paged(
+        &mut self,
+        sql: &str,
+        params: &[Param],
+        page_size: i32,
+    ) -> Result<Paged<'_>, ConnectionError> {
+        if page_size <= 0 {
+            return Err(ConnectionError::invalid_input(
+                "Page size must be a positive number of rows",
+            ));
+        }
+
+        let mut start = BytesMut::new();
+        frontend::parse("", sql, [], &mut start).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Parse message: {error}"))
+        })?;
+        bind("", "", params, self.result_format, &mut start)?;
+        frontend::describe(b'P', "", &mut start).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Describe message: {error}"))
+        })?;
+
+        Ok(Paged {
+            connection: self,
+            start: Some(start),
+            page_size,
+            columns: None,
+            finished: false,
+        })
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{
+    connection::Connection,
+    error::{ConnectionError, ServerError},
+    protocol::format_error,
+    query::{command_tag, execute, Column, Param, QueryResult, Row},
+};
+use bytes::BytesMut;
+use postgres_protocol::message::{backend::Message, frontend};
+use std::rc::Rc;
+
+/// SQLSTATE code for statements sent to a transaction that has already failed
+const IN_FAILED_SQL_TRANSACTION: &str = "25P02";
+
+/// Builder for a batch of statements that are sent to the server all at once, followed by a
+/// single SYNC, instead of waiting for each statement's ReadyForQuery in turn.
+///
+/// Like any other statements before a SYNC, the statements of a Pipeline run in a single
+/// implicit transaction (unless they manage transactions themselves). Once one statement fails,
+/// the server skips every later statement, and the implicit transaction is rolled back, so the
+/// effects of earlier statements are undone even though their results are still returned.
+pub struct Pipeline<'a> {
+    connection: &'a mut Connection,
+    statements: Vec<(String, Vec<Param>)>,
+}
+
+impl Pipeline<'_> {
+    /// Queue a statement with typed parameters
+    pub fn query(&mut self, sql: impl Into<String>, params: Vec<Param>) -> &mut Self {
+        self.statements.push((sql.into(), params));
+        self
+    }
+
+    /// Send every queued statement in one batch, returning the result of each statement in
+    /// order. Statements skipped after an earlier failure return errors of their own.
+    pub async fn run(self) -> Result<Vec<Result<QueryResult, ConnectionError>>, ConnectionError> {
+        let count = self.statements.len();
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+
+        // run every statement through the unnamed statement and portal, which are replaced
+        // by each statement in turn
+        let mut buffer = BytesMut::new();
+        for (sql, params) in &self.statements {
+            frontend::parse("", sql, [], &mut buffer).map_err(|error| {
+                ConnectionError::invalid_input(format!("Failed to generate Parse message: {error}"))
+            })?;
+            execute("", params, self.connection.result_format, &mut buffer)?;
+        }
+        frontend::sync(&mut buffer);
+        self.connection.encode(buffer).await?;
+
+        // each statement's results end at its CommandComplete (or equivalent), or at the first
+        // error, after which the server skips everything up to the SYNC's ReadyForQuery
+        let mut results = Vec::with_capacity(count);
+        let mut columns: Option<Rc<[Column]>> = None;
+        let mut rows = Vec::new();
+        loop {
+            match self.connection.decode().await? {
+                Some(Message::ParseComplete | Message::BindComplete) => {}
+                Some(Message::RowDescription(body)) => columns = Some(Column::parse(body)?),
+                Some(Message::NoData) => columns = Some(Rc::from([])),
+                Some(Message::DataRow(body)) => {
+                    let columns = columns.clone().ok_or_else(|| {
+                        ConnectionError::protocol("Data row returned before its row description")
+                    })?;
+                    rows.push(Row::parse(columns, body, self.connection.strict_text)?);
+                }
+                Some(Message::CommandComplete(body)) => results.push(Ok(QueryResult::new(
+                    columns.take().unwrap_or_else(|| Rc::from([])),
+                    std::mem::take(&mut rows),
+                    Some(command_tag(body)?),
+                ))),
+                Some(Message::EmptyQueryResponse | Message::PortalSuspended) => {
+                    results.push(Ok(QueryResult::new(
+                        columns.take().unwrap_or_else(|| Rc::from([])),
+                        std::mem::take(&mut rows),
+                        None,
+                    )))
+                }
+                Some(Message::ErrorResponse(body)) => {
+                    columns = None;
+                    rows.clear();
+                    results.push(Err(format_error(body)));
+                }
+                Some(Message::ReadyForQuery(..)) => break,
+                Some(_) => {
+                    return Err(ConnectionError::protocol(
+                        "Unexpected message returned from the pipeline",
+                    ))
+                }
+                None => return Err(ConnectionError::closed("Connection closed during pipeline")),
+            }
+        }
+
+        if results.len() > count {
+            return Err(ConnectionError::protocol(
+                "Pipeline returned more results than statements",
+            ));
+        }
+        // the server reports nothing for skipped statements, so report them the way it reports
+        // statements sent to an aborted transaction
+        results.resize_with(count, || {
+            Err(ConnectionError::Server(Box::new(ServerError {
+                severity: "ERROR".to_string(),
+                code: IN_FAILED_SQL_TRANSACTION.to_string(),
+                message: "Statement skipped after an earlier statement in the pipeline failed"
+                    .to_string(),
+                ..ServerError::default()
+            })))
+        });
+        Ok(results)
+    }
+}
+
+impl Connection {
+    /// Start a Pipeline of statements to send to the server in a single batch
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            connection: self,
+            statements: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,927 @@
+use crate::{
+    codec::{BackendMessageCodec, ProtocolNegotiation},
+    error::{ConnectionError, ServerError},
+};
+use bytes::{Buf, BytesMut};
+use fallible_iterator::FallibleIterator;
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready, Stream,
+};
+use postgres_protocol::{
+    authentication::{
+        md5_hash,
+        sasl::{ChannelBinding, ScramSha256, SCRAM_SHA_256, SCRAM_SHA_256_PLUS},
+    },
+    message::backend::{
+        AuthenticationSaslBody, ErrorFields, ErrorResponseBody, Message, NotificationResponseBody,
+        ParameterStatusBody,
+    },
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use zeroize::Zeroizing;
+
+/// Lowest SCRAM iteration count accepted from servers by default, which is the minimum that
+/// RFC 7677 allows (and Postgres' default)
+pub const DEFAULT_MIN_SCRAM_ITERATIONS: u32 = 4096;
+
+/// Protocol version (3.0) requested by every startup message
+const PROTOCOL_VERSION: i32 = 3 << 16;
+
+/// Number of bytes made available to each read from the transport
+const READ_SIZE: usize = 64 * 1024;
+
+/// Capacity of the pending message buffer that is kept around between messages
+const RETAINED_CAPACITY: usize = 64 * 1024;
+
+/// Number of notices kept around for ConnectionCore::take_notices, after which the oldest are
+/// dropped
+const MAX_NOTICES: usize = 100;
+
+/// Transport-agnostic half of a database connection: the framing of backend messages, the
+/// startup and authentication handshake, and the bookkeeping of everything the backend sends
+/// outside of a statement's own responses (notifications, notices, server parameters, and
+/// transaction status). ConnectionCore runs over any byte stream, whether the web_sys
+/// WebTransport streams behind the WASM Connection or a native QUIC or TCP stream (e.g. for
+/// Rust integration tests and CLI tools).
+///
+/// Reading and writing are cancellation-safe at the level of single messages: dropping a
+/// ConnectionCore::decode or ConnectionCore::encode future (e.g. when it loses a select against
+/// a timeout) never loses or duplicates bytes. Partial messages stay buffered until the rest of
+/// their bytes arrive, and data passed to encode is either written in full (by later calls, if
+/// need be) or not at all.
+pub struct ConnectionCore<T> {
+    io: T,
+    codec: BackendMessageCodec,
+    pending: BytesMut,
+    read_buffer: Box<[u8]>,
+    outgoing: BytesMut,
+    backend_key: Option<(i32, i32)>,
+    parameters: HashMap<String, String>,
+    rejected_protocol_options: Vec<String>,
+    notifications: VecDeque<Notification>,
+    notices: VecDeque<ServerError>,
+    transaction_status: TransactionStatus,
+    abandoned: usize,
+    lost: bool,
+}
+
+impl<T> ConnectionCore<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wrap a fresh byte stream to the backend, which is ready for ConnectionCore::startup
+    pub fn new(io: T) -> Self {
+        Self {
+            io,
+            codec: BackendMessageCodec::default(),
+            pending: BytesMut::new(),
+            read_buffer: vec![0; READ_SIZE].into_boxed_slice(),
+            outgoing: BytesMut::new(),
+            backend_key: None,
+            parameters: HashMap::new(),
+            rejected_protocol_options: Vec::new(),
+            notifications: VecDeque::new(),
+            notices: VecDeque::new(),
+            transaction_status: TransactionStatus::Idle,
+            abandoned: 0,
+            lost: false,
+        }
+    }
+
+    /// Run through the startup and auth sequences to prepare the connection for real use.
+    /// Besides user and database, the startup parameters can hold any runtime setting (e.g.
+    /// search_path or statement_timeout), along with an options string of command-line
+    /// arguments for the backend (see options_string), but each parameter may only be given
+    /// once. The password is only borrowed for the handshake: callers own (and should zeroize)
+    /// it. SCRAM servers that ask for fewer than min_iterations iterations are rejected.
+    pub async fn startup(
+        &mut self,
+        params: &[(&str, &str)],
+        password: &[u8],
+        min_iterations: u32,
+    ) -> Result<(), ConnectionError> {
+        // the server would quietly pick one of the values of a repeated parameter
+        for (index, (key, _)) in params.iter().enumerate() {
+            if params[..index].iter().any(|(earlier, _)| earlier == key) {
+                return Err(ConnectionError::invalid_input(format!(
+                    "Startup parameter {key} is given more than once"
+                )));
+            }
+        }
+
+        // send the startup message, remembering the user for password hashing
+        let user = params
+            .iter()
+            .find_map(|(key, value)| (*key == "user").then_some(*value))
+            .unwrap_or_default();
+        let mut buffer = BytesMut::new();
+        postgres_protocol::message::frontend::startup_message(params.iter().copied(), &mut buffer)
+            .map_err(|error| {
+                ConnectionError::invalid_input(format!("Error generating startup message: {error}"))
+            })?;
+        self.encode(buffer).await?;
+
+        // servers that can't honor all of the startup message say so before authenticating
+        let message = self.decode().await?;
+        if let Some(negotiation) = self.codec.take_negotiation() {
+            self.negotiate(negotiation)?;
+        }
+
+        // handle the next message for authentication
+        match message {
+            Some(Message::AuthenticationOk) => {}
+            Some(Message::AuthenticationSasl(body)) => {
+                sasl(self, password, body, min_iterations).await?
+            }
+            Some(Message::AuthenticationCleartextPassword) => send_password(self, password).await?,
+            Some(Message::AuthenticationMd5Password(body)) => {
+                let hash = Zeroizing::new(md5_hash(user.as_bytes(), password, body.salt()));
+                send_password(self, hash.as_bytes()).await?
+            }
+            Some(Message::ErrorResponse(body)) => return Err(format_error(body)),
+            Some(_) => {
+                return Err(ConnectionError::protocol(
+                    "Unsupported backend message type",
+                ))
+            }
+            None => return Err(ConnectionError::closed("Connection closed")),
+        }
+
+        // the connection is usable once the backend is ready for queries
+        ready(self).await
+    }
+
+    /// Write encoded frontend messages to the stream, after anything left over from earlier
+    /// writes. Cancellation-safe: the data is either written in full or not at all, even if the
+    /// returned future is dropped.
+    pub async fn encode(&mut self, data: BytesMut) -> Result<(), ConnectionError> {
+        let mut data = Some(data);
+        futures::future::poll_fn(|context| loop {
+            ready!(self.poll_flush(context))?;
+            match data.take() {
+                Some(data) => self.outgoing = data,
+                None => return Poll::Ready(Ok(())),
+            }
+        })
+        .await
+    }
+
+    /// Gracefully end the connection by sending a Terminate message, then closing the write
+    /// half of the stream so that the proxy and server see a clean shutdown
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        let mut buffer = BytesMut::new();
+        postgres_protocol::message::frontend::terminate(&mut buffer);
+        self.encode(buffer).await?;
+        futures::future::poll_fn(|context| Pin::new(&mut self.io).poll_close(context))
+            .await
+            .map_err(|error| self.lose(error))
+    }
+
+    /// Queue a statement without waiting for its response, e.g. from a Drop impl that can't
+    /// await the write. It's written right away if the stream is ready for it (or else along
+    /// with the next read or write), its response (up to ReadyForQuery) is skipped by later
+    /// reads, and write failures surface there too.
+    pub(crate) fn abandon(&mut self, data: &[u8]) {
+        self.outgoing.extend_from_slice(data);
+        self.abandoned += 1;
+        // later polls register a real waker for anything that can't be written yet
+        let _ = self.poll_flush(&mut Context::from_waker(futures::task::noop_waker_ref()));
+    }
+
+    /// Read the next backend message from the stream. Cancellation-safe: if the returned future
+    /// is dropped before it completes, no message is lost, and the next call picks up where it
+    /// left off.
+    pub async fn decode(&mut self) -> Result<Option<Message>, ConnectionError> {
+        futures::future::poll_fn(|context| self.poll_decode(context)).await
+    }
+
+    /// Stream asynchronous notifications (from LISTEN/NOTIFY) as they arrive, starting with
+    /// any that were received while waiting on other messages (e.g. in the middle of a query)
+    pub fn notifications(
+        &mut self,
+    ) -> impl Stream<Item = Result<Notification, ConnectionError>> + '_ {
+        futures::stream::poll_fn(move |context| loop {
+            if let Some(notification) = self.notifications.pop_front() {
+                return Poll::Ready(Some(Ok(notification)));
+            }
+
+            match ready!(self.poll_backend(context)) {
+                Ok(Some(Message::NotificationResponse(body))) => {
+                    return Poll::Ready(Some(Notification::parse(body)));
+                }
+                Ok(Some(Message::ParameterStatus(body))) => {
+                    if let Err(error) = self.record_parameter(body) {
+                        return Poll::Ready(Some(Err(error)));
+                    }
+                }
+                Ok(Some(_)) => {
+                    // other asynchronous messages between queries aren't interesting here
+                }
+                Ok(None) => return Poll::Ready(None),
+                Err(error) => return Poll::Ready(Some(Err(error))),
+            }
+        })
+    }
+
+    /// Skip the rest of the responses to a statement that has already been sent (up to its
+    /// ReadyForQuery), e.g. from a Drop impl that can't await them
+    pub(crate) fn skip_response(&mut self) {
+        self.abandoned += 1;
+    }
+
+    /// Read and skip the responses to every abandoned statement (up to their ReadyForQuery),
+    /// so that the transaction status reflects them, e.g. before checking whether the ROLLBACK
+    /// of a dropped Transaction has left the connection idle
+    pub(crate) async fn skip_abandoned(&mut self) -> Result<(), ConnectionError> {
+        futures::future::poll_fn(|context| {
+            while self.abandoned > 0 {
+                match ready!(self.poll_backend(context))? {
+                    Some(message) => {
+                        // nothing is returned while statements are abandoned
+                        let _ = self.observe(message)?;
+                    }
+                    None => {
+                        return Poll::Ready(Err(ConnectionError::closed(
+                            "Connection closed before every abandoned statement finished",
+                        )))
+                    }
+                }
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Poll for the next backend message, skipping everything that ConnectionCore::observe
+    /// sets aside
+    fn poll_decode(
+        &mut self,
+        context: &mut Context<'_>,
+    ) -> Poll<Result<Option<Message>, ConnectionError>> {
+        loop {
+            match ready!(self.poll_backend(context))? {
+                Some(message) => {
+                    if let Some(message) = self.observe(message)? {
+                        return Poll::Ready(Ok(Some(message)));
+                    }
+                }
+                None => return Poll::Ready(Ok(None)),
+            }
+        }
+    }
+
+    /// Record a backend message, returning it unless it was set aside: notifications for
+    /// ConnectionCore::notifications and notices for ConnectionCore::take_notices, along with
+    /// server parameters, all of which may arrive at any time. The transaction status of every
+    /// ReadyForQuery is recorded along the way, and the responses to abandoned statements (e.g.
+    /// the ROLLBACK of a dropped Transaction) are skipped entirely.
+    fn observe(&mut self, message: Message) -> Result<Option<Message>, ConnectionError> {
+        match message {
+            Message::NotificationResponse(body) => {
+                self.notifications.push_back(Notification::parse(body)?);
+            }
+            Message::NoticeResponse(body) => {
+                if self.notices.len() == MAX_NOTICES {
+                    self.notices.pop_front();
+                }
+                self.notices.push_back(server_error(body.fields()));
+            }
+            Message::ParameterStatus(body) => self.record_parameter(body)?,
+            Message::ReadyForQuery(body) => {
+                self.transaction_status = TransactionStatus::parse(body.status())?;
+                if self.abandoned > 0 {
+                    self.abandoned -= 1;
+                } else {
+                    return Ok(Some(Message::ReadyForQuery(body)));
+                }
+            }
+            _ if self.abandoned > 0 => {}
+            message => return Ok(Some(message)),
+        }
+
+        Ok(None)
+    }
+
+    /// Poll for the next backend message, reading from the stream as needed (and writing out
+    /// any abandoned statements first). Messages are only split off the pending buffer once
+    /// they're complete, so returning Pending at any point leaves the connection ready to be
+    /// polled again by a different future.
+    fn poll_backend(
+        &mut self,
+        context: &mut Context<'_>,
+    ) -> Poll<Result<Option<Message>, ConnectionError>> {
+        if !self.outgoing.is_empty() {
+            // a write still in flight doesn't hold up reading the responses before it
+            if let Poll::Ready(Err(error)) = self.poll_flush(context) {
+                return Poll::Ready(Err(error));
+            }
+        }
+
+        loop {
+            let message = self.codec.decode(&mut self.pending).map_err(|error| {
+                ConnectionError::protocol(format!(
+                    "Error parsing the next message from the backend: {error}"
+                ))
+            })?;
+            if let Some(message) = message {
+                // let go of the memory held by large messages once they've been consumed
+                if self.pending.is_empty() && self.pending.capacity() > RETAINED_CAPACITY {
+                    self.pending = BytesMut::new();
+                }
+                return Poll::Ready(Ok(Some(message)));
+            }
+
+            // if there's not at least a message's worth of data, read more into the read
+            // buffer (which is only zeroed once, unlike fresh space at the end of the queue)
+            // and append it to the queue, which reuses the space of consumed messages
+            let read = Pin::new(&mut self.io).poll_read(context, &mut self.read_buffer);
+            let length = match ready!(read) {
+                Ok(length) => length,
+                Err(error) => return Poll::Ready(Err(self.lose(error))),
+            };
+            self.pending.extend_from_slice(&self.read_buffer[..length]);
+
+            // a finished stream has nothing left to read, so treat it as the end of the connection
+            if length == 0 {
+                self.lost = true;
+                if !self.pending.is_empty() {
+                    return Poll::Ready(Err(ConnectionError::closed(
+                        "Connection closed in the middle of a backend message",
+                    )));
+                }
+                return Poll::Ready(Ok(None));
+            }
+        }
+    }
+
+    /// Poll until everything waiting to be written has been written and flushed to the stream
+    fn poll_flush(&mut self, context: &mut Context<'_>) -> Poll<Result<(), ConnectionError>> {
+        while !self.outgoing.is_empty() {
+            match ready!(Pin::new(&mut self.io).poll_write(context, &self.outgoing)) {
+                Ok(0) => {
+                    self.lost = true;
+                    return Poll::Ready(Err(ConnectionError::closed(
+                        "Connection closed before every message was written",
+                    )));
+                }
+                Ok(length) => self.outgoing.advance(length),
+                Err(error) => return Poll::Ready(Err(self.lose(error))),
+            }
+        }
+        Pin::new(&mut self.io)
+            .poll_flush(context)
+            .map_err(|error| self.lose(error))
+    }
+
+    /// Mark the connection as lost after its stream failed
+    fn lose(&mut self, error: std::io::Error) -> ConnectionError {
+        self.lost = true;
+        error.into()
+    }
+}
+
+impl<T> ConnectionCore<T> {
+    /// Get a reference to the underlying stream
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Get a mutable reference to the underlying stream. Reading from or writing to it
+    /// directly corrupts the connection's framing.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Reject backend messages larger than the maximum size (in bytes) instead of buffering them
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.codec.set_max_message_size(max_message_size);
+    }
+
+    /// Get the current value of a server parameter reported by the backend (e.g. server_version,
+    /// server_encoding, or TimeZone)
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters.get(name).map(String::as_str)
+    }
+
+    /// Get every server parameter reported by the backend so far
+    pub fn parameters(&self) -> &HashMap<String, String> {
+        &self.parameters
+    }
+
+    /// Get the protocol options (startup parameters prefixed with _pq_.) that the server
+    /// didn't recognize during startup, and so ignored
+    pub fn rejected_protocol_options(&self) -> &[String] {
+        &self.rejected_protocol_options
+    }
+
+    /// Get the process ID of the backend serving this connection
+    pub fn process_id(&self) -> Option<i32> {
+        self.backend_key.map(|(process_id, _)| process_id)
+    }
+
+    /// Get the process ID and secret key that cancel requests for this connection carry
+    pub fn backend_key(&self) -> Option<(i32, i32)> {
+        self.backend_key
+    }
+
+    /// Check if the stream has failed or been closed by the other side, after which every read
+    /// or write fails
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    /// Get the transaction status reported by the backend's latest ReadyForQuery message
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    /// Take every notice (e.g. warnings and RAISE NOTICE messages) received since the last call,
+    /// oldest first. Only the latest notices are kept if they aren't taken in time.
+    pub fn take_notices(&mut self) -> Vec<ServerError> {
+        self.notices.drain(..).collect()
+    }
+
+    /// Apply a server's NegotiateProtocolVersion response to the startup message: startup can
+    /// continue without any protocol options the server rejected, but not with an older
+    /// protocol version than the client speaks
+    fn negotiate(&mut self, negotiation: ProtocolNegotiation) -> Result<(), ConnectionError> {
+        let version = |version: i32| format!("{}.{}", version >> 16, version & 0xffff);
+        if negotiation.newest_version < PROTOCOL_VERSION {
+            return Err(ConnectionError::protocol(format!(
+                "Server only supports protocol version {}, but the client requires {}",
+                version(negotiation.newest_version),
+                version(PROTOCOL_VERSION)
+            )));
+        }
+        self.rejected_protocol_options = negotiation.unrecognized_options;
+        Ok(())
+    }
+
+    /// Record the new value of a server parameter
+    fn record_parameter(&mut self, body: ParameterStatusBody) -> Result<(), ConnectionError> {
+        let error =
+            |error| ConnectionError::protocol(format!("Error parsing parameter status: {error}"));
+        let name = body.name().map_err(error)?;
+        let value = body.value().map_err(error)?;
+        self.parameters.insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+/// Read backend Messages from the connection until the stream is exhausted
+impl<T> Stream for ConnectionCore<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message, ConnectionError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_decode(context).map(Result::transpose)
+    }
+}
+
+/// Transaction status of the backend, as reported by every ReadyForQuery message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    /// not in a transaction block
+    Idle,
+    /// in a transaction block
+    InTransaction,
+    /// in a failed transaction block, where every statement is rejected until the transaction
+    /// is rolled back
+    Failed,
+}
+
+impl TransactionStatus {
+    /// Convert the status byte of a ReadyForQuery message
+    fn parse(status: u8) -> Result<Self, ConnectionError> {
+        match status {
+            b'I' => Ok(Self::Idle),
+            b'T' => Ok(Self::InTransaction),
+            b'E' => Ok(Self::Failed),
+            status => Err(ConnectionError::protocol(format!(
+                "Unknown transaction status: {:?}",
+                char::from(status)
+            ))),
+        }
+    }
+
+    /// Name of this status, as exposed to JS
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::InTransaction => "in_transaction",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Asynchronous notification sent by the backend to a channel that this connection LISTENs to
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    /// process ID of the backend that sent the notification
+    pub pid: i32,
+    /// name of the channel the notification was sent to
+    pub channel: String,
+    /// payload of the notification (empty if none was provided)
+    pub payload: String,
+}
+
+impl Notification {
+    /// Collect the fields of a NotificationResponse message
+    fn parse(body: NotificationResponseBody) -> Result<Self, ConnectionError> {
+        let error =
+            |error| ConnectionError::protocol(format!("Error parsing notification: {error}"));
+        Ok(Self {
+            pid: body.process_id(),
+            channel: body.channel().map_err(error)?.to_string(),
+            payload: body.message().map_err(error)?.to_string(),
+        })
+    }
+}
+
+/// Handle SASL-based authentication with SCRAM-SHA-256.
+///
+/// Channel binding (SCRAM-SHA-256-PLUS) is never used: its binding data has to come from the
+/// TLS connection that the server itself terminates, but the browser's TLS session ends at the
+/// proxy, and WebTransport doesn't expose TLS exporters or peer certificates to the page anyway.
+/// Servers that advertise both mechanisms are told that the client doesn't support binding,
+/// and servers that only accept SCRAM-SHA-256-PLUS are rejected up front.
+async fn sasl<T>(
+    connection: &mut ConnectionCore<T>,
+    password: &[u8],
+    body: AuthenticationSaslBody,
+    min_iterations: u32,
+) -> Result<(), ConnectionError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // pick a mechanism from the ones that the server advertises
+    let mechanisms: Vec<_> = body
+        .mechanisms()
+        .map(|mechanism| Ok(mechanism.to_string()))
+        .collect()
+        .map_err(|error| {
+            ConnectionError::protocol(format!("Error parsing SASL mechanisms: {error}"))
+        })?;
+    if !mechanisms
+        .iter()
+        .any(|mechanism| mechanism == SCRAM_SHA_256)
+    {
+        if mechanisms
+            .iter()
+            .any(|mechanism| mechanism == SCRAM_SHA_256_PLUS)
+        {
+            return Err(ConnectionError::authentication(
+                "Server requires SCRAM-SHA-256-PLUS channel binding, which isn't available through a WebTransport proxy",
+            ));
+        }
+        return Err(ConnectionError::authentication(format!(
+            "Unsupported SASL mechanisms: {}",
+            mechanisms.join(", ")
+        )));
+    }
+
+    // send the initial SASL message
+    let mut buffer = BytesMut::new();
+    let mut scram = ScramSha256::new(password, ChannelBinding::unsupported());
+    let client_nonce = attribute(scram.message(), "r=")
+        .ok_or_else(|| ConnectionError::protocol("SASL client-first message has no nonce"))?
+        .to_string();
+    postgres_protocol::message::frontend::sasl_initial_response(
+        SCRAM_SHA_256,
+        scram.message(),
+        &mut buffer,
+    )
+    .map_err(|error| {
+        ConnectionError::invalid_input(format!(
+            "Error writing SASL initial response message: {error}"
+        ))
+    })?;
+    connection.encode(buffer).await?;
+
+    // get the body of the SASL continuation
+    let body = match connection.decode().await? {
+        Some(Message::AuthenticationSaslContinue(body)) => body,
+        Some(Message::ErrorResponse(body)) => return Err(authentication_error(body)),
+        Some(_) => {
+            return Err(ConnectionError::protocol(
+                "Unexpected message during SASL handshake",
+            ))
+        }
+        None => {
+            return Err(ConnectionError::closed(
+                "Connection closed during authentication",
+            ))
+        }
+    };
+    verify_server_first(body.data(), &client_nonce, min_iterations)?;
+    scram.update(body.data()).map_err(|error| {
+        ConnectionError::authentication(format!("Error continuing SASL handshake: {error}"))
+    })?;
+
+    // send the SASL response to the server again
+    let mut buffer = BytesMut::new();
+    postgres_protocol::message::frontend::sasl_response(scram.message(), &mut buffer).map_err(
+        |error| {
+            ConnectionError::invalid_input(format!("Error writing SASL response message: {error}"))
+        },
+    )?;
+    connection.encode(buffer).await?;
+
+    // get the body of the SASL finalizer
+    let body = match connection.decode().await? {
+        Some(Message::AuthenticationSaslFinal(body)) => body,
+        Some(Message::ErrorResponse(body)) => return Err(authentication_error(body)),
+        Some(_) => {
+            return Err(ConnectionError::protocol(
+                "Unexpected message finalizing SASL handshake",
+            ))
+        }
+        None => {
+            return Err(ConnectionError::closed(
+                "Connection closed during authentication",
+            ))
+        }
+    };
+    if let Some(error) = attribute(body.data(), "e=") {
+        return Err(ConnectionError::authentication(format!(
+            "Server rejected the SASL handshake: {error}"
+        )));
+    }
+    scram.finish(body.data()).map_err(|error| {
+        ConnectionError::authentication(format!(
+            "Could not verify the server's SASL signature: {error}"
+        ))
+    })?;
+
+    Ok(())
+}
+
+/// Check the server-first message of a SCRAM handshake before handing it to ScramSha256, so
+/// that each problem gets an error of its own: the server's nonce has to extend the client's
+/// nonce, and the server can't weaken the password hash below the minimum iteration count
+fn verify_server_first(
+    message: &[u8],
+    client_nonce: &str,
+    min_iterations: u32,
+) -> Result<(), ConnectionError> {
+    let nonce = attribute(message, "r=")
+        .ok_or_else(|| ConnectionError::protocol("SASL server-first message has no nonce"))?;
+    if nonce.len() <= client_nonce.len() || !nonce.starts_with(client_nonce) {
+        return Err(ConnectionError::authentication(
+            "Server's SASL nonce doesn't extend the client's nonce",
+        ));
+    }
+
+    let iterations: u32 = attribute(message, "i=")
+        .and_then(|iterations| iterations.parse().ok())
+        .ok_or_else(|| {
+            ConnectionError::protocol("SASL server-first message has no valid iteration count")
+        })?;
+    if iterations < min_iterations {
+        return Err(ConnectionError::authentication(format!(
+            "Server asked for {iterations} SCRAM iterations, but at least {min_iterations} are required"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Find the value of a comma-delimited SCRAM attribute by its prefix (e.g. "r=" for the nonce)
+fn attribute<'a>(message: &'a [u8], prefix: &str) -> Option<&'a str> {
+    std::str::from_utf8(message)
+        .ok()?
+        .split(',')
+        .find_map(|attribute| attribute.strip_prefix(prefix))
+}
+
+/// Handle cleartext and MD5 authentication, where the (possibly hashed) password is sent as-is
+async fn send_password<T>(
+    connection: &mut ConnectionCore<T>,
+    password: &[u8],
+) -> Result<(), ConnectionError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = BytesMut::new();
+    postgres_protocol::message::frontend::password_message(password, &mut buffer).map_err(
+        |error| ConnectionError::invalid_input(format!("Error writing password message: {error}")),
+    )?;
+    connection.encode(buffer).await?;
+
+    match connection.decode().await? {
+        Some(Message::AuthenticationOk) => Ok(()),
+        Some(Message::ErrorResponse(body)) => Err(authentication_error(body)),
+        Some(_) => Err(ConnectionError::protocol(
+            "Unexpected message during password authentication",
+        )),
+        None => Err(ConnectionError::closed(
+            "Connection closed during authentication",
+        )),
+    }
+}
+
+/// Read the connection information from the stream until the backend is ready for queries
+async fn ready<T>(connection: &mut ConnectionCore<T>) -> Result<(), ConnectionError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        match connection.decode().await? {
+            Some(Message::BackendKeyData(body)) => {
+                connection.backend_key = Some((body.process_id(), body.secret_key()));
+            }
+            Some(Message::AuthenticationOk) => {}
+            Some(Message::ReadyForQuery(..)) => return Ok(()),
+            Some(Message::ErrorResponse(body)) => return Err(format_error(body)),
+            Some(_) => return Err(ConnectionError::protocol("Unexpected backend message type")),
+            None => {
+                return Err(ConnectionError::closed(
+                    "Connection closed during authentication",
+                ))
+            }
+        }
+    }
+}
+
+/// Encode runtime settings as an options startup parameter of -c name=value arguments,
+/// escaping the whitespace and backslashes in each value (which the backend would otherwise
+/// split the options at) with backslashes
+pub fn options_string<'a>(settings: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut options = String::new();
+    for (name, value) in settings {
+        if !options.is_empty() {
+            options.push(' ');
+        }
+        options.push_str("-c ");
+        for character in name.chars().chain(['=']).chain(value.chars()) {
+            if character.is_ascii_whitespace() || character == '\\' {
+                options.push('\\');
+            }
+            options.push(character);
+        }
+    }
+
+    options
+}
+
+/// Convert Error response bodies into server errors, collecting each field by its code
+pub(crate) fn format_error(body: ErrorResponseBody) -> ConnectionError {
+    ConnectionError::Server(Box::new(server_error(body.fields())))
+}
+
+/// Format Error responses received mid-handshake as authentication failures
+fn authentication_error(body: ErrorResponseBody) -> ConnectionError {
+    let error = server_error(body.fields());
+    ConnectionError::Authentication {
+        message: error.to_string(),
+        sqlstate: Some(error.code),
+    }
+}
+
+/// Collect the fields of an Error or Notice response body into a ServerError, ignoring unknown
+/// fields (which the protocol allows servers to add at any time)
+pub fn server_error(mut fields: ErrorFields<'_>) -> ServerError {
+    let mut error = ServerError::default();
+    while let Ok(Some(field)) = fields.next() {
+        let value = field.value().to_string();
+        match field.type_() {
+            b'S' => error.severity = value,
+            b'C' => error.code = value,
+            b'M' => error.message = value,
+            b'D' => error.detail = Some(value),
+            b'H' => error.hint = Some(value),
+            b'P' => error.position = value.parse().ok(),
+            b'W' => error.where_ = Some(value),
+            b's' => error.schema = Some(value),
+            b't' => error.table = Some(value),
+            b'c' => error.column = Some(value),
+            b'd' => error.data_type = Some(value),
+            b'n' => error.constraint = Some(value),
+            _ => {}
+        }
+    }
+
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::io;
+
+    /// In-memory backend that answers with canned bytes, recording everything written to it
+    struct Backend {
+        responses: &'static [u8],
+        received: Vec<u8>,
+    }
+
+    impl AsyncRead for Backend {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            context: &mut Context<'_>,
+            buffer: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.responses).poll_read(context, buffer)
+        }
+    }
+
+    impl AsyncWrite for Backend {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            context: &mut Context<'_>,
+            buffer: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.received).poll_write(context, buffer)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.received).poll_flush(context)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.received).poll_close(context)
+        }
+    }
+
+    #[test]
+    fn the_first_message_after_startup_is_a_backend_message() {
+        // AuthenticationOk, ParameterStatus, BackendKeyData, ReadyForQuery, then CommandComplete
+        let responses = b"R\0\0\0\x08\0\0\0\0\
+            S\0\0\0\x16server_version\x0016\0\
+            K\0\0\0\x0c\0\0\0\x2a\0\0\0\x07\
+            Z\0\0\0\x05I\
+            C\0\0\0\x0dSELECT 1\0";
+        let mut connection = ConnectionCore::new(Backend {
+            responses,
+            received: Vec::new(),
+        });
+
+        block_on(connection.startup(&[("user", "postgres")], b"", DEFAULT_MIN_SCRAM_ITERATIONS))
+            .unwrap();
+        assert_eq!(connection.parameter("server_version"), Some("16"));
+        assert_eq!(connection.backend_key(), Some((42, 7)));
+        match block_on(connection.decode()).unwrap() {
+            Some(Message::CommandComplete(body)) => assert_eq!(body.tag().unwrap(), "SELECT 1"),
+            _ => panic!("expected CommandComplete"),
+        }
+
+        // nothing but the startup message itself went out ahead of the protocol
+        let received = &connection.get_ref().received;
+        let length = i32::from_be_bytes(received[..4].try_into().unwrap());
+        assert_eq!(length as usize, received.len());
+        assert_eq!(&received[4..8], &PROTOCOL_VERSION.to_be_bytes());
+    }
+
+    #[test]
+    fn abandoned_rollbacks_leave_the_connection_idle_for_the_next_transaction() {
+        // BEGIN, the ROLLBACK of a dropped Transaction, then the next BEGIN
+        let responses = b"C\0\0\0\x0aBEGIN\0Z\0\0\0\x05T\
+            C\0\0\0\x0dROLLBACK\0Z\0\0\0\x05I\
+            C\0\0\0\x0aBEGIN\0Z\0\0\0\x05T";
+        let mut connection = ConnectionCore::new(Backend {
+            responses,
+            received: Vec::new(),
+        });
+        let query = |sql| {
+            let mut buffer = BytesMut::new();
+            postgres_protocol::message::frontend::query(sql, &mut buffer).unwrap();
+            buffer
+        };
+
+        block_on(connection.encode(query("BEGIN"))).unwrap();
+        while !matches!(
+            block_on(connection.decode()).unwrap(),
+            Some(Message::ReadyForQuery(..))
+        ) {}
+        assert_eq!(
+            connection.transaction_status(),
+            TransactionStatus::InTransaction
+        );
+
+        // dropping a Transaction only queues its ROLLBACK, so the status is stale until it's read
+        connection.abandon(&query("ROLLBACK"));
+        assert_eq!(
+            connection.transaction_status(),
+            TransactionStatus::InTransaction
+        );
+        block_on(connection.skip_abandoned()).unwrap();
+        assert_eq!(connection.transaction_status(), TransactionStatus::Idle);
+
+        block_on(connection.encode(query("BEGIN"))).unwrap();
+        match block_on(connection.decode()).unwrap() {
+            Some(Message::CommandComplete(body)) => assert_eq!(body.tag().unwrap(), "BEGIN"),
+            _ => panic!("expected the CommandComplete of the second BEGIN"),
+        }
+        let expected = [query("BEGIN"), query("ROLLBACK"), query("BEGIN")].concat();
+        assert_eq!(connection.get_ref().received, expected);
+    }
+}
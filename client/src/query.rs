@@ -0,0 +1,641 @@
+use crate::{connection::Connection, error::ConnectionError, protocol::format_error};
+use bytes::{BufMut, Bytes, BytesMut};
+use fallible_iterator::FallibleIterator;
+use js_sys::Uint8Array;
+use postgres_protocol::{
+    message::{
+        backend::{CommandCompleteBody, DataRowBody, Message, RowDescriptionBody},
+        frontend,
+    },
+    types, IsNull, Oid,
+};
+use serde::{
+    ser::{Error as _, SerializeSeq, SerializeStruct},
+    Serialize, Serializer,
+};
+use std::{
+    borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    rc::Rc,
+    str::Utf8Error,
+};
+use wasm_bindgen::{JsCast, JsValue};
+
+/// Format code of text-encoded values
+pub(crate) const TEXT_FORMAT: i16 = 0;
+
+/// Format code of binary-encoded values
+pub(crate) const BINARY_FORMAT: i16 = 1;
+
+/// OIDs of the types whose values are decoded into JS values
+const BOOL_OID: Oid = 16;
+const BYTEA_OID: Oid = 17;
+const NAME_OID: Oid = 19;
+const INT8_OID: Oid = 20;
+const INT2_OID: Oid = 21;
+const INT4_OID: Oid = 23;
+const TEXT_OID: Oid = 25;
+const FLOAT4_OID: Oid = 700;
+const FLOAT8_OID: Oid = 701;
+const BPCHAR_OID: Oid = 1042;
+const VARCHAR_OID: Oid = 1043;
+const TIMESTAMP_OID: Oid = 1114;
+const TIMESTAMPTZ_OID: Oid = 1184;
+
+/// Milliseconds between the Unix epoch and the Postgres epoch (2000-01-01 00:00:00 UTC)
+const POSTGRES_EPOCH_MILLIS: f64 = 946_684_800_000.0;
+
+/// A single query parameter converted from a JS value
+#[derive(Debug)]
+pub enum Param {
+    /// JS null or undefined, bound as NULL
+    Null,
+    /// JS booleans, bound as text
+    Bool(bool),
+    /// JS numbers, bound as text so that the server can coerce them to any numeric type
+    Number(f64),
+    /// JS strings, bound as text
+    Text(String),
+    /// JS Uint8Arrays, bound as binary (e.g. for bytea parameters)
+    Bytes(Vec<u8>),
+}
+
+impl Param {
+    /// Convert a JS value into a parameter, if it has a supported type
+    pub fn from_js(value: &JsValue) -> Result<Self, ConnectionError> {
+        if value.is_null() || value.is_undefined() {
+            Ok(Self::Null)
+        } else if let Some(value) = value.as_bool() {
+            Ok(Self::Bool(value))
+        } else if let Some(value) = value.as_f64() {
+            Ok(Self::Number(value))
+        } else if let Some(value) = value.as_string() {
+            Ok(Self::Text(value))
+        } else if let Some(value) = value.dyn_ref::<Uint8Array>() {
+            Ok(Self::Bytes(value.to_vec()))
+        } else {
+            Err(ConnectionError::invalid_input(format!(
+                "Unsupported query parameter type: {:?}",
+                value.js_typeof()
+            )))
+        }
+    }
+
+    /// Format code used to bind this parameter
+    fn format(&self) -> i16 {
+        match self {
+            Self::Bytes(..) => BINARY_FORMAT,
+            _ => TEXT_FORMAT,
+        }
+    }
+
+    /// Write the encoded value of this parameter to the Bind message buffer
+    fn encode(&self, buffer: &mut BytesMut) -> IsNull {
+        match self {
+            Self::Null => return IsNull::Yes,
+            Self::Bool(value) => buffer.put_slice(if *value { b"true" } else { b"false" }),
+            Self::Number(value) if value.is_infinite() => buffer.put_slice(if *value > 0.0 {
+                b"Infinity"
+            } else {
+                b"-Infinity"
+            }),
+            Self::Number(value) => buffer.put_slice(value.to_string().as_bytes()),
+            Self::Text(value) => buffer.put_slice(value.as_bytes()),
+            Self::Bytes(value) => buffer.put_slice(value),
+        }
+
+        IsNull::No
+    }
+}
+
+/// Description of a single column of a query result, taken from a RowDescription message
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Column {
+    /// name of the column
+    pub name: String,
+    /// OID of the table that the column belongs to (0 if it isn't a table column)
+    pub table_oid: Oid,
+    /// attribute number of the column within its table (0 if it isn't a table column)
+    pub column_id: i16,
+    /// OID of the column's data type
+    pub type_oid: Oid,
+    /// size of the column's data type in bytes (negative for variable-width types)
+    pub type_size: i16,
+    /// type-specific modifier of the column's data type (e.g. the length of a varchar)
+    pub type_modifier: i32,
+    /// format code of the column's values (0 for text, 1 for binary)
+    pub format: i16,
+}
+
+impl Column {
+    /// Collect the column descriptions of a RowDescription message
+    pub(crate) fn parse(body: RowDescriptionBody) -> Result<Rc<[Self]>, ConnectionError> {
+        body.fields()
+            .map(|field| {
+                Ok(Self {
+                    name: field.name().to_string(),
+                    table_oid: field.table_oid(),
+                    column_id: field.column_id(),
+                    type_oid: field.type_oid(),
+                    type_size: field.type_size(),
+                    type_modifier: field.type_modifier(),
+                    format: field.format(),
+                })
+            })
+            .collect::<Vec<_>>()
+            .map(Rc::from)
+            .map_err(|error| {
+                ConnectionError::protocol(format!("Error parsing row description: {error}"))
+            })
+    }
+}
+
+/// A single row of a query result, holding the raw value of each column alongside the
+/// column descriptions shared by every row of the same result
+#[derive(Debug)]
+pub struct Row {
+    columns: Rc<[Column]>,
+    values: Vec<Option<Bytes>>,
+    strict_text: bool,
+}
+
+impl Row {
+    /// Collect the column values of a DataRow message without copying them. With strict_text,
+    /// text values that aren't valid UTF-8 fail to convert to JS instead of being decoded lossily.
+    pub(crate) fn parse(
+        columns: Rc<[Column]>,
+        body: DataRowBody,
+        strict_text: bool,
+    ) -> Result<Self, ConnectionError> {
+        let buffer = body.buffer_bytes();
+        let values: Vec<_> = body
+            .ranges()
+            .map(|range| Ok(range.map(|range| buffer.slice(range))))
+            .collect()
+            .map_err(|error| {
+                ConnectionError::protocol(format!("Error parsing data row: {error}"))
+            })?;
+
+        if values.len() != columns.len() {
+            return Err(ConnectionError::protocol(format!(
+                "Data row has {} values, but the row description has {} columns",
+                values.len(),
+                columns.len()
+            )));
+        }
+
+        Ok(Self {
+            columns,
+            values,
+            strict_text,
+        })
+    }
+
+    /// Convert this Row into a JS array of { name, oid, value } objects, with NULL values
+    /// represented as null and every other value decoded as described by Value::decode
+    pub fn to_js(&self) -> Result<JsValue, JsValue> {
+        self.serialize(&serde_wasm_bindgen::Serializer::new().serialize_missing_as_null(true))
+            .map_err(JsValue::from)
+    }
+}
+
+/// Serialize Rows as a sequence of self-describing column values
+impl Serialize for Row {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut sequence = serializer.serialize_seq(Some(self.values.len()))?;
+        for (column, value) in self.columns.iter().zip(&self.values) {
+            let value = value
+                .as_deref()
+                .map(|value| Value::decode(column, value, self.strict_text))
+                .transpose()
+                .map_err(|error| {
+                    S::Error::custom(format!(
+                        "Column {} holds invalid UTF-8: {error}",
+                        column.name
+                    ))
+                })?;
+            sequence.serialize_element(&Field {
+                name: &column.name,
+                oid: column.type_oid,
+                value,
+            })?;
+        }
+        sequence.end()
+    }
+}
+
+/// Every row returned by a query, along with the description of the columns of those rows
+/// (which is empty for statements that return no rows) and the statement's command tag
+#[derive(Debug)]
+pub struct QueryResult {
+    pub columns: Rc<[Column]>,
+    pub rows: Vec<Row>,
+    /// tag of the CommandComplete message, e.g. "INSERT 0 5" or "SELECT 3" (None for empty
+    /// queries)
+    pub command_tag: Option<String>,
+    /// number of rows affected (or returned) by the statement, from the end of its command tag
+    pub rows_affected: Option<u64>,
+}
+
+impl QueryResult {
+    /// Create a QueryResult from the parts of a statement's response, reading the number of
+    /// affected rows from the command tag
+    pub(crate) fn new(columns: Rc<[Column]>, rows: Vec<Row>, command_tag: Option<String>) -> Self {
+        let rows_affected = command_tag.as_deref().and_then(rows_affected);
+        Self {
+            columns,
+            rows,
+            command_tag,
+            rows_affected,
+        }
+    }
+
+    /// Convert this QueryResult into a JS object of { columns, rows, commandTag, rowsAffected },
+    /// with rows in the same form as Row::to_js
+    pub fn to_js(&self) -> Result<JsValue, JsValue> {
+        self.serialize(&serde_wasm_bindgen::Serializer::new().serialize_missing_as_null(true))
+            .map_err(JsValue::from)
+    }
+}
+
+impl Serialize for QueryResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut result = serializer.serialize_struct("QueryResult", 4)?;
+        result.serialize_field("columns", &*self.columns)?;
+        result.serialize_field("rows", &self.rows)?;
+        result.serialize_field("commandTag", &self.command_tag)?;
+        result.serialize_field("rowsAffected", &self.rows_affected)?;
+        result.end()
+    }
+}
+
+/// Read the command tag of a CommandComplete message
+pub(crate) fn command_tag(body: CommandCompleteBody) -> Result<String, ConnectionError> {
+    body.tag().map(String::from).map_err(|error| {
+        ConnectionError::protocol(format!("Error parsing command complete tag: {error}"))
+    })
+}
+
+/// Parse the row count at the end of a command tag (e.g. the 5 of "INSERT 0 5"), which is
+/// missing for commands that don't deal in rows (e.g. "CREATE TABLE")
+pub(crate) fn rows_affected(tag: &str) -> Option<u64> {
+    tag.rsplit(' ').next()?.parse().ok()
+}
+
+/// A single column value of a Row, paired with its column description for serialization
+#[derive(Serialize)]
+struct Field<'a> {
+    name: &'a str,
+    oid: Oid,
+    value: Option<Value<'a>>,
+}
+
+/// Column values, decoded according to their format code (and, for binary values, their type)
+enum Value<'a> {
+    Text(Cow<'a, str>),
+    Binary(Cow<'a, [u8]>),
+    Bool(bool),
+    Int(i32),
+    BigInt(i64),
+    Float(f64),
+    /// milliseconds since the Unix epoch
+    Timestamp(f64),
+}
+
+impl<'a> Value<'a> {
+    /// Decode a column value according to its format code. Values of bytea columns become raw
+    /// bytes, whether they arrive in binary or in text (as hex). Other text values become
+    /// strings, replacing invalid UTF-8 with U+FFFD unless decoding strictly, in which case
+    /// invalid UTF-8 is an error. Binary values of common types become their JS equivalents:
+    /// booleans for bool, numbers for int2, int4, float4, and float8, BigInts for int8,
+    /// strings for text types, and milliseconds since the Unix epoch for timestamp and
+    /// timestamptz (with infinite timestamps as +/-Infinity). Binary values of any other type
+    /// (or that fail to decode) are left as raw bytes.
+    fn decode(column: &Column, value: &'a [u8], strict: bool) -> Result<Self, Utf8Error> {
+        if column.format == TEXT_FORMAT {
+            if column.type_oid == BYTEA_OID {
+                // bytea_output = 'escape' values aren't hex, so they're left as text
+                if let Some(bytes) = decode_hex_bytea(value) {
+                    return Ok(Self::Binary(Cow::Owned(bytes)));
+                }
+            }
+            return decode_text(value, strict).map(Self::Text);
+        }
+
+        let decoded = match column.type_oid {
+            BOOL_OID => types::bool_from_sql(value).map(Self::Bool),
+            INT2_OID => types::int2_from_sql(value).map(|value| Self::Int(value.into())),
+            INT4_OID => types::int4_from_sql(value).map(Self::Int),
+            INT8_OID => types::int8_from_sql(value).map(Self::BigInt),
+            FLOAT4_OID => types::float4_from_sql(value).map(|value| Self::Float(value.into())),
+            FLOAT8_OID => types::float8_from_sql(value).map(Self::Float),
+            TEXT_OID | VARCHAR_OID | BPCHAR_OID | NAME_OID => {
+                return decode_text(value, strict).map(Self::Text)
+            }
+            TIMESTAMP_OID | TIMESTAMPTZ_OID => types::timestamp_from_sql(value).map(|micros| {
+                Self::Timestamp(match micros {
+                    i64::MAX => f64::INFINITY,
+                    i64::MIN => f64::NEG_INFINITY,
+                    micros => micros as f64 / 1000.0 + POSTGRES_EPOCH_MILLIS,
+                })
+            }),
+            _ => return Ok(Self::Binary(Cow::Borrowed(value))),
+        };
+        Ok(decoded.unwrap_or(Self::Binary(Cow::Borrowed(value))))
+    }
+}
+
+/// Decode a text value as UTF-8, either strictly or replacing invalid sequences with U+FFFD
+fn decode_text(value: &[u8], strict: bool) -> Result<Cow<'_, str>, Utf8Error> {
+    if strict {
+        std::str::from_utf8(value).map(Cow::Borrowed)
+    } else {
+        Ok(String::from_utf8_lossy(value))
+    }
+}
+
+/// Decode a bytea value in the text format of bytea_output = 'hex' (e.g. \x0aff)
+fn decode_hex_bytea(value: &[u8]) -> Option<Vec<u8>> {
+    let digits = value.strip_prefix(b"\\x")?;
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let high = (pair[0] as char).to_digit(16)?;
+            let low = (pair[1] as char).to_digit(16)?;
+            Some((high * 16 + low) as u8)
+        })
+        .collect()
+}
+
+impl Serialize for Value<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Text(value) => serializer.serialize_str(value),
+            Self::Binary(value) => serializer.serialize_bytes(value),
+            Self::Bool(value) => serializer.serialize_bool(*value),
+            Self::Int(value) => serializer.serialize_i32(*value),
+            // JS numbers can't hold every int8, but JS BigInts (which i128s become) can
+            Self::BigInt(value) => serializer.serialize_i128((*value).into()),
+            Self::Float(value) | Self::Timestamp(value) => serializer.serialize_f64(*value),
+        }
+    }
+}
+
+/// SQLSTATE of errors caused by referencing a prepared statement that doesn't exist
+const INVALID_SQL_STATEMENT_NAME: &str = "26000";
+
+impl Connection {
+    /// Run a single statement with typed parameters through the extended query protocol
+    /// (PARSE + BIND + DESCRIBE + EXECUTE + SYNC), collecting the column descriptions and
+    /// every row returned before ReadyForQuery.
+    pub async fn query(
+        &mut self,
+        sql: &str,
+        params: &[Param],
+    ) -> Result<QueryResult, ConnectionError> {
+        // create a parse message for the query against the unnamed prepared statement
+        let mut buffer = BytesMut::new();
+        frontend::parse("", sql, [], &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Parse message: {error}"))
+        })?;
+        execute("", params, self.result_format, &mut buffer)?;
+        frontend::sync(&mut buffer);
+        self.encode(buffer).await?;
+
+        self.collect(None).await
+    }
+
+    /// Run a string of one or more statements (separated by semicolons) through the simple
+    /// query protocol, returning a QueryResult for each statement in order. Unlike the extended
+    /// query protocol, this runs statements that can't be prepared (e.g. multi-statement scripts
+    /// or VACUUM), but it takes no parameters and always returns values as text.
+    pub async fn simple_query(&mut self, sql: &str) -> Result<Vec<QueryResult>, ConnectionError> {
+        let mut buffer = BytesMut::new();
+        frontend::query(sql, &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Query message: {error}"))
+        })?;
+        self.encode(buffer).await?;
+
+        // each statement's results end at its CommandComplete (or EmptyQueryResponse), and the
+        // first error skips every later statement
+        let mut results = Vec::new();
+        let mut columns: Option<Rc<[Column]>> = None;
+        let mut rows = Vec::new();
+        let mut error = None;
+        loop {
+            match self.decode().await? {
+                Some(Message::RowDescription(body)) => columns = Some(Column::parse(body)?),
+                Some(Message::DataRow(body)) => {
+                    let columns = columns.clone().ok_or_else(|| {
+                        ConnectionError::protocol("Data row returned before its row description")
+                    })?;
+                    rows.push(Row::parse(columns, body, self.strict_text)?);
+                }
+                Some(Message::CommandComplete(body)) => results.push(QueryResult::new(
+                    columns.take().unwrap_or_else(|| Rc::from([])),
+                    std::mem::take(&mut rows),
+                    Some(command_tag(body)?),
+                )),
+                Some(Message::EmptyQueryResponse) => {
+                    results.push(QueryResult::new(Rc::from([]), Vec::new(), None))
+                }
+                Some(Message::ErrorResponse(body)) => error = Some(format_error(body)),
+                Some(Message::ReadyForQuery(..)) => break,
+                Some(_) => {
+                    return Err(ConnectionError::protocol(
+                        "Unexpected message returned from the simple query",
+                    ))
+                }
+                None => return Err(ConnectionError::closed("Connection closed during query")),
+            }
+        }
+
+        match error {
+            Some(error) => Err(error),
+            None => Ok(results),
+        }
+    }
+
+    /// Prepare a named statement for the SQL, or reuse the statement prepared for the same SQL
+    /// earlier. Statements are named after a hash of their SQL, so the same SQL always maps to
+    /// the same name.
+    pub async fn prepare(&mut self, sql: &str) -> Result<String, ConnectionError> {
+        if let Some(name) = self.statements.get(sql) {
+            return Ok(name.clone());
+        }
+
+        let mut hasher = DefaultHasher::new();
+        sql.hash(&mut hasher);
+        let name = format!("pgwt_{:016x}", hasher.finish());
+
+        // parse the statement on its own, with a Sync to learn whether it succeeded
+        let mut buffer = BytesMut::new();
+        frontend::parse(&name, sql, [], &mut buffer).map_err(|error| {
+            ConnectionError::invalid_input(format!("Failed to generate Parse message: {error}"))
+        })?;
+        frontend::sync(&mut buffer);
+        self.encode(buffer).await?;
+        self.collect(None).await?;
+
+        self.statements.insert(sql.to_string(), name.clone());
+        Ok(name)
+    }
+
+    /// Get the result columns of a prepared statement, as described the last time it ran
+    /// through Connection::query_prepared (or None if it hasn't run yet)
+    pub fn schema_for(&self, statement: &str) -> Option<Rc<[Column]>> {
+        self.schemas.get(statement).cloned()
+    }
+
+    /// Run a statement prepared by Connection::prepare with typed parameters, skipping the
+    /// PARSE step. The result columns described on a statement's first run are cached, so
+    /// later runs in the same result format skip the DESCRIBE step too. Statements that the
+    /// server no longer knows about (e.g. after a DEALLOCATE or DISCARD) are dropped from both
+    /// caches, so preparing their SQL again parses and describes them anew.
+    pub async fn query_prepared(
+        &mut self,
+        name: &str,
+        params: &[Param],
+    ) -> Result<QueryResult, ConnectionError> {
+        let schema = self.schema_for(name).filter(|columns| {
+            columns
+                .iter()
+                .all(|column| column.format == self.result_format)
+        });
+        let mut buffer = BytesMut::new();
+        if schema.is_some() {
+            execute_described(name, params, self.result_format, &mut buffer)?;
+        } else {
+            execute(name, params, self.result_format, &mut buffer)?;
+        }
+        frontend::sync(&mut buffer);
+        self.encode(buffer).await?;
+
+        let cached = schema.is_some();
+        let result = self.collect(schema).await;
+        match &result {
+            Ok(result) if !cached => {
+                self.schemas
+                    .insert(name.to_string(), Rc::clone(&result.columns));
+            }
+            Err(error) if error.sqlstate() == Some(INVALID_SQL_STATEMENT_NAME) => {
+                self.statements.retain(|_, statement| statement != name);
+                self.schemas.remove(name);
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// Collect the column descriptions and rows of a query until the backend is ready for the
+    /// next query, even after an error, so that the next query starts from a clean slate.
+    /// Queries that aren't described start from the columns already known for them.
+    async fn collect(
+        &mut self,
+        mut columns: Option<Rc<[Column]>>,
+    ) -> Result<QueryResult, ConnectionError> {
+        let mut rows = Vec::new();
+        let mut tag = None;
+        let mut error = None;
+        loop {
+            match self.decode().await? {
+                Some(Message::RowDescription(body)) => columns = Some(Column::parse(body)?),
+                Some(Message::DataRow(body)) => {
+                    let columns = columns.clone().ok_or_else(|| {
+                        ConnectionError::protocol("Data row returned before its row description")
+                    })?;
+                    rows.push(Row::parse(columns, body, self.strict_text)?);
+                }
+                Some(Message::NoData) => columns = Some(Rc::from([])),
+                Some(Message::CommandComplete(body)) => tag = Some(command_tag(body)?),
+                Some(Message::ReadyForQuery(..)) => break,
+                Some(
+                    Message::ParseComplete
+                    | Message::BindComplete
+                    | Message::EmptyQueryResponse
+                    | Message::PortalSuspended,
+                ) => {
+                    // these are expected, so the loop can continue
+                }
+                Some(Message::ErrorResponse(body)) => error = Some(format_error(body)),
+                Some(_) => {
+                    return Err(ConnectionError::protocol(
+                        "Unexpected message returned from the query",
+                    ))
+                }
+                None => return Err(ConnectionError::closed("Connection closed during query")),
+            }
+        }
+
+        match error {
+            Some(error) => Err(error),
+            None => Ok(QueryResult::new(
+                columns.unwrap_or_else(|| Rc::from([])),
+                rows,
+                tag,
+            )),
+        }
+    }
+}
+
+/// Encode the messages that run a (parsed) statement with typed parameters through the unnamed
+/// portal: BIND + DESCRIBE + EXECUTE. Callers follow these with a SYNC.
+pub(crate) fn execute(
+    statement: &str,
+    params: &[Param],
+    result_format: i16,
+    buffer: &mut BytesMut,
+) -> Result<(), ConnectionError> {
+    bind("", statement, params, result_format, buffer)?;
+
+    // describe the portal to learn the name and type of every result column
+    frontend::describe(b'P', "", buffer).map_err(|error| {
+        ConnectionError::invalid_input(format!("Failed to generate Describe message: {error}"))
+    })?;
+
+    execute_portal(buffer)
+}
+
+/// Encode the messages that run a (parsed) statement whose result columns are already known:
+/// BIND + EXECUTE, without a DESCRIBE. Callers follow these with a SYNC.
+pub(crate) fn execute_described(
+    statement: &str,
+    params: &[Param],
+    result_format: i16,
+    buffer: &mut BytesMut,
+) -> Result<(), ConnectionError> {
+    bind("", statement, params, result_format, buffer)?;
+    execute_portal(buffer)
+}
+
+/// Encode an EXECUTE message that runs the unnamed portal, fetching every row
+fn execute_portal(buffer: &mut BytesMut) -> Result<(), ConnectionError> {
+    frontend::execute("", 0, buffer)
+        .map_err(|_| ConnectionError::invalid_input("Failed to generate Execute message"))
+}
+
+/// Encode a BIND message for binding typed parameters to a portal, requesting results of every
+/// column in the given format
+pub fn bind(
+    portal: &str,
+    statement: &str,
+    params: &[Param],
+    result_format: i16,
+    buffer: &mut BytesMut,
+) -> Result<(), ConnectionError> {
+    frontend::bind(
+        portal,
+        statement,
+        params.iter().map(Param::format),
+        params,
+        |param, buffer| Ok(param.encode(buffer)),
+        [result_format],
+        buffer,
+    )
+    .map_err(|_| ConnectionError::invalid_input("Failed to generate Bind message"))
+}
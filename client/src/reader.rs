@@ -0,0 +1,177 @@
+use js_sys::{ArrayBuffer, Promise, Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{ReadableStream, ReadableStreamByobReader, ReadableStreamDefaultReader};
+
+/// Size of the buffer that BYOB reads fill, which bounds how many bytes a single read returns
+const READ_BUFFER_SIZE: u32 = 64 * 1024;
+
+/// Reader for the backend half of a Connection's stream.
+///
+/// Every read crosses the JS/WASM boundary and waits on a JS Promise, so reads should return as
+/// many bytes as possible. Where the stream supports it (as WebTransport's byte streams do),
+/// reads go through a BYOB ("bring your own buffer") reader, which fills one reused buffer with
+/// everything the stream has queued, coalescing many small QUIC frames into a single read
+/// without allocating a new array for each. Other streams fall back to a default reader,
+/// which returns whatever chunks the stream produces, one at a time.
+pub(crate) enum StreamReader {
+    /// BYOB reader, along with the buffer for its next read (unless a read is in flight)
+    Byob {
+        reader: ReadableStreamByobReader,
+        buffer: Option<ArrayBuffer>,
+    },
+    /// default reader for streams that aren't byte streams
+    Default(ReadableStreamDefaultReader),
+}
+
+impl StreamReader {
+    /// Lock a readable stream to a new reader, preferring a BYOB reader where possible
+    pub fn new(stream: &ReadableStream) -> Result<Self, JsValue> {
+        match ReadableStreamByobReader::new(stream) {
+            Ok(reader) => Ok(Self::Byob {
+                reader,
+                buffer: None,
+            }),
+            Err(_) => Ok(Self::Default(
+                stream
+                    .get_reader()
+                    .dyn_into::<ReadableStreamDefaultReader>()?,
+            )),
+        }
+    }
+
+    /// Start reading the next bytes from the stream, to be passed to StreamReader::chunk once
+    /// the returned Promise resolves. Only one read may be in flight at a time.
+    pub fn read(&mut self) -> Promise {
+        match self {
+            Self::Byob { reader, buffer } => {
+                // the buffer is transferred to the stream (detaching it) until the read resolves
+                let buffer = buffer
+                    .take()
+                    .unwrap_or_else(|| ArrayBuffer::new(READ_BUFFER_SIZE));
+                reader.read_with_array_buffer_view(&Uint8Array::new(&buffer))
+            }
+            Self::Default(reader) => reader.read(),
+        }
+    }
+
+    /// Extract the bytes of a resolved read, or None once the stream is done. The returned
+    /// array is only valid until the next read, which reuses its buffer.
+    pub fn chunk(&mut self, result: JsValue) -> Result<Option<Uint8Array>, JsValue> {
+        let done = Reflect::get(&result, &"done".into())?
+            .as_bool()
+            .unwrap_or_default();
+        // chunks are cast rather than passed to new Uint8Array(), which would copy them
+        let value = Reflect::get(&result, &"value".into())?
+            .dyn_into::<Uint8Array>()
+            .ok();
+        if let (Self::Byob { buffer, .. }, Some(value)) = (&mut *self, &value) {
+            // the filled view comes back over the same (transferred) buffer, ready for reuse
+            *buffer = Some(value.buffer());
+        }
+
+        if done {
+            return Ok(None);
+        }
+        value
+            .map(Some)
+            .ok_or_else(|| JsValue::from_str("Stream returned a chunk that isn't a Uint8Array"))
+    }
+
+    /// Cancel the stream, discarding anything that hasn't been read yet
+    pub fn cancel(&self) -> Promise {
+        match self {
+            Self::Byob { reader, .. } => reader.cancel(),
+            Self::Default(reader) => reader.cancel(),
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use js_sys::{Function, Object};
+    use wasm_bindgen_futures::JsFuture;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    /// Create a byte stream that has the given chunks queued, then closes
+    fn byte_stream(chunks: &str) -> ReadableStream {
+        let source = Object::new();
+        Reflect::set(&source, &"type".into(), &"bytes".into()).unwrap();
+        let start = Function::new_with_args(
+            "controller",
+            &format!(
+                "for (const chunk of {chunks}) controller.enqueue(new Uint8Array(chunk));
+                controller.close();"
+            ),
+        );
+        Reflect::set(&source, &"start".into(), &start).unwrap();
+        ReadableStream::new_with_underlying_source(&source).unwrap()
+    }
+
+    /// Get the buffer kept for the next BYOB read, if any
+    fn kept_buffer(reader: &StreamReader) -> Option<ArrayBuffer> {
+        match reader {
+            StreamReader::Byob { buffer, .. } => buffer.clone(),
+            StreamReader::Default(..) => panic!("expected a BYOB reader"),
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn byob_reads_reuse_the_buffer_they_were_handed_back() {
+        let mut reader = StreamReader::new(&byte_stream("[[1, 2, 3], [4, 5]]")).unwrap();
+        assert!(kept_buffer(&reader).is_none());
+
+        let result = JsFuture::from(reader.read()).await.unwrap();
+        let chunk = reader.chunk(result).unwrap().unwrap();
+        assert_eq!(chunk.to_vec(), [1, 2, 3]);
+        let first = kept_buffer(&reader).unwrap();
+        assert_eq!(first.byte_length(), READ_BUFFER_SIZE);
+
+        // the next read transfers the kept buffer (detaching it) instead of allocating another
+        let read = reader.read();
+        assert!(kept_buffer(&reader).is_none());
+        assert_eq!(first.byte_length(), 0);
+        let chunk = reader.chunk(JsFuture::from(read).await.unwrap()).unwrap();
+        assert_eq!(chunk.unwrap().to_vec(), [4, 5]);
+        assert_eq!(
+            kept_buffer(&reader).unwrap().byte_length(),
+            READ_BUFFER_SIZE
+        );
+    }
+
+    #[wasm_bindgen_test]
+    async fn byob_reads_that_finish_with_a_value_keep_its_buffer() {
+        let mut reader = StreamReader::new(&byte_stream("[]")).unwrap();
+
+        // a finished BYOB read still hands back the buffer, as an empty view over it
+        let result = JsFuture::from(reader.read()).await.unwrap();
+        let value = Reflect::get(&result, &"value".into()).unwrap();
+        assert_eq!(value.dyn_into::<Uint8Array>().unwrap().length(), 0);
+        assert!(reader.chunk(result).unwrap().is_none());
+        assert_eq!(
+            kept_buffer(&reader).unwrap().byte_length(),
+            READ_BUFFER_SIZE
+        );
+    }
+
+    #[wasm_bindgen_test]
+    async fn byob_reads_without_a_value_allocate_a_fresh_buffer() {
+        let mut reader = StreamReader::new(&byte_stream("[[1]]")).unwrap();
+        let read = reader.read();
+
+        // the buffer handed to a read stays detached if the read never hands it back
+        let result = Object::new();
+        Reflect::set(&result, &"done".into(), &JsValue::TRUE).unwrap();
+        assert!(reader.chunk(result.into()).unwrap().is_none());
+        assert!(kept_buffer(&reader).is_none());
+
+        // so the next read works with a new buffer rather than the detached one
+        JsFuture::from(read).await.unwrap();
+        let result = JsFuture::from(reader.read()).await.unwrap();
+        assert!(reader.chunk(result).unwrap().is_none());
+        assert_eq!(
+            kept_buffer(&reader).unwrap().byte_length(),
+            READ_BUFFER_SIZE
+        );
+    }
+}
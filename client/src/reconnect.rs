@@ -0,0 +1,213 @@
+use crate::{
+    connection::{Connection, Startup},
+    error::ConnectionError,
+    log,
+};
+use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Url, WebTransport, WebTransportHash, WebTransportOptions};
+use zeroize::Zeroizing;
+
+/// Everything needed to open a new Connection through the proxy, whether for the first time
+/// or after the previous Connection was lost
+pub struct ConnectOptions {
+    /// https:// URL of the proxy
+    pub url: String,
+    /// SHA-256 hash of a self-signed proxy certificate to trust
+    pub certificate_hash: Option<Uint8Array>,
+    /// parameters of the startup message (e.g. user and database)
+    pub params: Vec<(String, String)>,
+    /// password for authentication, wiped from memory when these options are dropped
+    pub password: Zeroizing<Vec<u8>>,
+    /// lowest SCRAM iteration count accepted from the server
+    pub min_iterations: u32,
+}
+
+impl ConnectOptions {
+    /// Open a WebTransport session to the proxy and authenticate a new Connection
+    pub async fn open(&self) -> Result<Connection, ConnectionError> {
+        // WebTransport only runs over HTTP/3, so anything other than https is a mistake
+        if Url::new(&self.url)?.protocol() != "https:" {
+            return Err(ConnectionError::invalid_input(format!(
+                "WebTransport URL must use https, but got {}",
+                self.url
+            )));
+        }
+
+        // initialize the WebTransport channel
+        let transport = match &self.certificate_hash {
+            Some(value) => {
+                let mut hash = WebTransportHash::new();
+                hash.algorithm("sha-256").value(value);
+                let mut options = WebTransportOptions::new();
+                options.server_certificate_hashes(&Array::of1(&hash));
+                WebTransport::new_with_options(&self.url, &options)?
+            }
+            None => WebTransport::new(&self.url)?,
+        };
+        JsFuture::from(transport.ready()).await?;
+        log("WebTransport ready!");
+
+        // run through the startup process to get a real Connection
+        let params: Vec<_> = self
+            .params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        Startup::open(&transport)
+            .await?
+            .start(&params, &self.password, self.min_iterations)
+            .await
+    }
+}
+
+/// Retry policy for re-establishing lost Connections, with exponential backoff between
+/// attempts and a listener for reconnect events
+pub struct Reconnect {
+    /// options for opening each new Connection (holding on to the password)
+    pub options: ConnectOptions,
+    /// attempts made before giving up on a lost Connection
+    pub max_retries: u32,
+    /// delay before the first attempt in milliseconds, doubled after every failed attempt
+    pub initial_delay: u32,
+    /// longest delay between attempts in milliseconds
+    pub max_delay: u32,
+    /// JS function called with a { state, attempt, error } object for every reconnect event
+    pub listener: Option<Function>,
+}
+
+impl Reconnect {
+    /// Open a new Connection, retrying with backoff until an attempt succeeds, an attempt
+    /// fails to authenticate (which retrying won't fix), or the retries run out
+    async fn run(&self) -> Result<Connection, ConnectionError> {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+        loop {
+            self.emit("reconnecting", attempt, None);
+            sleep(delay).await?;
+            match self.options.open().await {
+                Ok(connection) => {
+                    self.emit("reconnected", attempt, None);
+                    return Ok(connection);
+                }
+                Err(error)
+                    if attempt >= self.max_retries
+                        || matches!(error, ConnectionError::Authentication { .. }) =>
+                {
+                    self.emit("failed", attempt, Some(&error));
+                    return Err(error);
+                }
+                Err(error) => log(&format!("Reconnect attempt {attempt} failed: {error}")),
+            }
+            attempt += 1;
+            delay = delay.saturating_mul(2).min(self.max_delay);
+        }
+    }
+
+    /// Report a reconnect event to the listener (if any), ignoring errors thrown by it
+    fn emit(&self, state: &str, attempt: u32, error: Option<&ConnectionError>) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+        let event = Object::new();
+        let error = error.map_or(JsValue::NULL, |error| error.to_string().into());
+        // setting properties on a fresh object can't fail
+        let _ = Reflect::set(&event, &"state".into(), &state.into());
+        let _ = Reflect::set(&event, &"attempt".into(), &attempt.into());
+        let _ = Reflect::set(&event, &"error".into(), &error);
+        let _ = listener.call1(&JsValue::NULL, &event);
+    }
+}
+
+/// Connection wrapper that re-establishes its Connection once the WebTransport session or
+/// stream is lost (e.g. when a browser switches networks), if reconnecting is enabled.
+///
+/// Lost Connections are only replaced on the next call to ReconnectingConnection::get, so the
+/// statement that ran into the failure still returns its error rather than being retried, and
+/// anything tied to the old session is gone for good: open transactions are rolled back by the
+/// server, and prepared statements and LISTENs have to be set up again.
+pub struct ReconnectingConnection {
+    connection: Connection,
+    reconnect: Option<Reconnect>,
+    max_message_size: Option<usize>,
+    binary_results: bool,
+    strict_text: bool,
+}
+
+impl ReconnectingConnection {
+    /// Wrap a freshly-opened Connection, along with the policy for replacing it once lost
+    pub fn new(connection: Connection, reconnect: Option<Reconnect>) -> Self {
+        Self {
+            connection,
+            reconnect,
+            max_message_size: None,
+            binary_results: false,
+            strict_text: false,
+        }
+    }
+
+    /// Get the current Connection, replacing it first if it has been lost
+    pub async fn get(&mut self) -> Result<&mut Connection, ConnectionError> {
+        if let (true, Some(reconnect)) = (self.connection.is_lost(), &self.reconnect) {
+            self.connection = reconnect.run().await?;
+            if let Some(max_message_size) = self.max_message_size {
+                self.connection.set_max_message_size(max_message_size);
+            }
+            self.connection.set_binary_results(self.binary_results);
+            self.connection.set_strict_text(self.strict_text);
+        }
+
+        Ok(&mut self.connection)
+    }
+
+    /// Get the current Connection without reconnecting, even if it has been lost
+    pub fn current(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Get the current Connection mutably without reconnecting, e.g. for draining what it
+    /// received before being lost
+    pub fn current_mut(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// Reject backend messages larger than the maximum size, both on the current Connection
+    /// and on any Connection that replaces it
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = Some(max_message_size);
+        self.connection.set_max_message_size(max_message_size);
+    }
+
+    /// Request binary results, both on the current Connection and on any Connection that
+    /// replaces it
+    pub fn set_binary_results(&mut self, binary: bool) {
+        self.binary_results = binary;
+        self.connection.set_binary_results(binary);
+    }
+
+    /// Decode text values strictly, both on the current Connection and on any Connection that
+    /// replaces it
+    pub fn set_strict_text(&mut self, strict: bool) {
+        self.strict_text = strict;
+        self.connection.set_strict_text(strict);
+    }
+
+    /// Unwrap the current Connection, e.g. for closing it
+    pub fn into_inner(self) -> Connection {
+        self.connection
+    }
+}
+
+/// Wait for a number of milliseconds with the global setTimeout, which exists in both windows
+/// and workers
+async fn sleep(milliseconds: u32) -> Result<(), ConnectionError> {
+    let set_timeout: Function = Reflect::get(&js_sys::global(), &"setTimeout".into())?
+        .dyn_into()
+        .map_err(|_| ConnectionError::protocol("setTimeout is unavailable"))?;
+    let promise = Promise::new(&mut |resolve, _| {
+        let _ = set_timeout.call2(&JsValue::NULL, &resolve, &milliseconds.into());
+    });
+    JsFuture::from(promise).await?;
+    Ok(())
+}
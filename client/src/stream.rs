@@ -0,0 +1,157 @@
+use crate::{error::ConnectionError, reader::StreamReader};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready,
+};
+use js_sys::{Promise, Uint8Array};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{WebTransportBidirectionalStream, WritableStreamDefaultWriter};
+
+/// Byte stream over both halves of a WebTransport bidirectional stream, which carries a
+/// ConnectionCore in the browser.
+///
+/// Reads and writes each wait on a JS Promise, which is kept on the stream rather than dropped
+/// between polls, so that futures polling the stream can be dropped (and the stream polled
+/// again by a different future) without losing or duplicating any bytes. Writes are handed to
+/// the writable stream in one piece, and chunks read from the readable stream are kept until
+/// every one of their bytes has been read.
+pub struct WebTransportStream {
+    read: StreamReader,
+    write: WritableStreamDefaultWriter,
+    reading: Option<JsFuture>,
+    chunk: Option<(Uint8Array, u32)>,
+    writing: Option<JsFuture>,
+    closed: bool,
+}
+
+impl WebTransportStream {
+    /// Lock both halves of a bidirectional stream for reading and writing
+    pub fn new(pair: &WebTransportBidirectionalStream) -> Result<Self, ConnectionError> {
+        Ok(Self {
+            read: StreamReader::new(&pair.readable())?,
+            write: pair.writable().get_writer()?,
+            reading: None,
+            chunk: None,
+            writing: None,
+            closed: false,
+        })
+    }
+
+    /// Cancel the readable half of the stream, discarding anything that hasn't been read yet
+    pub fn cancel(&self) -> Promise {
+        self.read.cancel()
+    }
+
+    /// Write a last chunk and close the writable half of the stream without waiting for either
+    /// to finish (e.g. from a Drop impl that can't await the write), unless it's closed already
+    pub fn close_now(&mut self, last: &[u8]) {
+        if !self.closed {
+            self.closed = true;
+            let _ = self.write.write_with_chunk(&Uint8Array::from(last));
+            let _ = self.write.close();
+        }
+    }
+
+    /// Poll the in-flight write to the writable stream (if any) to completion
+    fn poll_writing(&mut self, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(write) = self.writing.as_mut() {
+            let result = ready!(Pin::new(write).poll(context));
+            self.writing = None;
+            result.map_err(stream_error)?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Copy bytes out of chunks from the readable stream, waiting for another chunk once the
+/// last one has been read in full. Reading 0 bytes means that the stream is done.
+impl AsyncRead for WebTransportStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if let Some((chunk, offset)) = this.chunk.as_mut() {
+                let end = chunk
+                    .length()
+                    .min(offset.saturating_add(buffer.len() as u32));
+                let length = (end - *offset) as usize;
+                chunk.subarray(*offset, end).copy_to(&mut buffer[..length]);
+                if end == chunk.length() {
+                    this.chunk = None;
+                } else {
+                    *offset = end;
+                }
+                if length > 0 || buffer.is_empty() {
+                    return Poll::Ready(Ok(length));
+                }
+            }
+
+            let read = this
+                .reading
+                .get_or_insert_with(|| JsFuture::from(this.read.read()));
+            let result = ready!(Pin::new(read).poll(context));
+            this.reading = None;
+
+            // a finished stream has no value to read, which is the end of the stream
+            let chunk = result
+                .and_then(|result| this.read.chunk(result))
+                .map_err(stream_error)?;
+            match chunk {
+                Some(chunk) => this.chunk = Some((chunk, 0)),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+    }
+}
+
+/// Hand writes to the writable stream one chunk at a time, copying each into JS memory
+impl AsyncWrite for WebTransportStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_writing(context))?;
+        let message = Uint8Array::new_with_length(buffer.len() as u32);
+        message.copy_from(buffer);
+        self.writing = Some(JsFuture::from(self.write.write_with_chunk(&message)));
+        Poll::Ready(Ok(buffer.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_writing(context)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // flush any pending writes before closing the writable stream
+        ready!(self.poll_writing(context))?;
+        if !self.closed {
+            self.closed = true;
+            self.writing = Some(JsFuture::from(self.write.close()));
+        }
+        self.poll_writing(context)
+    }
+}
+
+/// Streams that are dropped stop reading, discarding anything the proxy sends afterwards
+impl Drop for WebTransportStream {
+    fn drop(&mut self) {
+        let _ = self.read.cancel();
+    }
+}
+
+/// Report errors thrown by WebTransport streams as I/O errors, with the message of the JS Error
+fn stream_error(error: JsValue) -> io::Error {
+    io::Error::other(ConnectionError::from(error).to_string())
+}
@@ -0,0 +1,84 @@
+use crate::{connection::Connection, error::ConnectionError, protocol::TransactionStatus};
+use bytes::BytesMut;
+use postgres_protocol::message::frontend;
+use std::ops::{Deref, DerefMut};
+
+/// Guard for a transaction block on a Connection, which runs statements through the same
+/// Connection methods (by dereferencing to it). Transactions that are dropped without being
+/// committed or rolled back are rolled back.
+pub struct Transaction<'a> {
+    connection: &'a mut Connection,
+    finished: bool,
+}
+
+impl Transaction<'_> {
+    /// Commit the transaction. Failed transactions can't be committed (the server would only
+    /// roll them back), so they return an error and are rolled back instead.
+    pub async fn commit(mut self) -> Result<(), ConnectionError> {
+        // statements abandoned inside the transaction may have failed it, which only shows up in
+        // the status once their responses have been read
+        self.connection.skip_abandoned().await?;
+        if self.connection.transaction_status() == TransactionStatus::Failed {
+            return Err(ConnectionError::invalid_input(
+                "Can't commit a failed transaction, so it was rolled back",
+            ));
+        }
+
+        self.finished = true;
+        self.connection.simple_query("COMMIT").await?;
+        Ok(())
+    }
+
+    /// Roll back the transaction, undoing every statement run in it
+    pub async fn rollback(mut self) -> Result<(), ConnectionError> {
+        self.finished = true;
+        self.connection.simple_query("ROLLBACK").await?;
+        Ok(())
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.connection
+    }
+}
+
+/// Drop can't wait for async work, so unfinished Transactions queue a ROLLBACK without waiting
+/// for its response, which the Connection skips before reading the response to the next query
+/// (or before starting the next Transaction)
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let mut buffer = BytesMut::new();
+            frontend::query("ROLLBACK", &mut buffer).expect("ROLLBACK contains no null bytes");
+            self.connection.abandon(&buffer);
+        }
+    }
+}
+
+impl Connection {
+    /// Start a transaction block with BEGIN, returning a guard that commits or rolls it back
+    pub async fn transaction(&mut self) -> Result<Transaction<'_>, ConnectionError> {
+        // the ROLLBACK of a dropped Transaction only shows up in the status once it's been read
+        self.skip_abandoned().await?;
+        if self.transaction_status() != TransactionStatus::Idle {
+            return Err(ConnectionError::invalid_input(
+                "Can't start a transaction inside another transaction",
+            ));
+        }
+
+        self.simple_query("BEGIN").await?;
+        Ok(Transaction {
+            connection: self,
+            finished: false,
+        })
+    }
+}
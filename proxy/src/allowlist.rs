@@ -0,0 +1,62 @@
+use crate::startup::StartupMessage;
+use anyhow::Context;
+use std::path::Path;
+
+/// Users and databases that clients may request in their startup messages, enforced by the
+/// proxy before anything is sent upstream (on top of, and independent from, pg_hba.conf).
+/// An empty list allows any value.
+#[derive(Clone, Debug, Default)]
+pub struct Allowlist {
+    users: Vec<String>,
+    databases: Vec<String>,
+}
+
+impl Allowlist {
+    /// Allow only these users and databases (either of which may be empty to allow any)
+    pub fn new(users: Vec<String>, databases: Vec<String>) -> Self {
+        Self { users, databases }
+    }
+
+    /// Add the entries of an allowlist file, with one user=NAME or database=NAME entry per
+    /// line. Blank lines and lines starting with # are ignored.
+    pub fn extend_from_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read allowlist file {}", path.display()))?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once('=') {
+                Some(("user", name)) => self.users.push(name.trim().to_string()),
+                Some(("database", name)) => self.databases.push(name.trim().to_string()),
+                _ => anyhow::bail!(
+                    "Allowlist entries must be formatted as user=NAME or database=NAME (line {} of {})",
+                    number + 1,
+                    path.display()
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the user and database of a startup message (with the database defaulting to the
+    /// user's name, as Postgres does), returning a message for the client if either isn't
+    /// allowed
+    pub fn check(&self, startup: &StartupMessage) -> Result<(), String> {
+        let user = startup.get("user").unwrap_or_default();
+        let database = startup.get("database").unwrap_or(user);
+        if !self.users.is_empty() && !self.users.iter().any(|allowed| allowed == user) {
+            return Err(format!("user \"{user}\" is not allowed by the proxy"));
+        }
+        if !self.databases.is_empty() && !self.databases.iter().any(|allowed| allowed == database) {
+            return Err(format!(
+                "database \"{database}\" is not allowed by the proxy"
+            ));
+        }
+
+        Ok(())
+    }
+}
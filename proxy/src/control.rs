@@ -0,0 +1,170 @@
+use crate::proxy::{Proxy, Upstream};
+use anyhow::Context;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Bytes that a client sends at the very start of a stream to turn it into a control stream.
+/// Read as the length of a Postgres startup message, these would claim over a gigabyte, far
+/// beyond the 10,000 bytes that Postgres accepts, so no Postgres client ever sends them.
+pub const PREAMBLE: [u8; 8] = *b"PGWT-CTL";
+
+/// Frame type of a Ping: a 4-byte big-endian nonce that the Pong echoes
+const PING: u8 = b'?';
+
+/// Frame type of a Pong: the Ping's nonce, then a status byte
+const PONG: u8 = b'!';
+
+/// Pong status for an upstream that accepted a connection
+const UPSTREAM_REACHABLE: u8 = 0;
+
+/// Pong status for an upstream that couldn't be reached within the connect timeout
+const UPSTREAM_UNREACHABLE: u8 = 1;
+
+/// Client stream after checking its first bytes for the control PREAMBLE
+pub enum Negotiated<S> {
+    /// a control stream, with its preamble consumed
+    Control(S),
+    /// a regular Postgres stream, which replays the bytes read while negotiating
+    Postgres(Rewind<S>),
+}
+
+/// Read the first bytes of a client stream to find out whether it's a control stream. Every
+/// Postgres client starts with a message of at least 8 bytes (the length and the protocol or
+/// request code), so this never waits on bytes that a Postgres client wouldn't send anyway.
+pub async fn negotiate<S>(mut stream: S) -> io::Result<Negotiated<S>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0; PREAMBLE.len()];
+    let mut length = 0;
+    while length < prefix.len() {
+        match stream.read(&mut prefix[length..]).await? {
+            0 => break,
+            read => length += read,
+        }
+    }
+
+    if prefix[..length] == PREAMBLE {
+        tracing::debug!("Control stream negotiated");
+        Ok(Negotiated::Control(stream))
+    } else {
+        Ok(Negotiated::Postgres(Rewind::new(
+            prefix[..length].to_vec(),
+            stream,
+        )))
+    }
+}
+
+/// Answer every Ping on a control stream with a Pong reporting whether the upstream is
+/// reachable, until the client finishes the stream
+pub async fn serve<S>(mut stream: S, proxy: &Proxy, upstream: &Upstream) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let frame_type = match stream.read_u8().await {
+            Ok(frame_type) => frame_type,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error).context("Failed to read control frame"),
+        };
+        anyhow::ensure!(
+            frame_type == PING,
+            "Unknown control frame type {frame_type:#04x}"
+        );
+        let nonce = stream
+            .read_u32()
+            .await
+            .context("Failed to read Ping nonce")?;
+
+        let status = match proxy.probe(upstream).await {
+            Ok(()) => UPSTREAM_REACHABLE,
+            Err(error) => {
+                tracing::warn!(%error, "Ping failed to reach upstream");
+                UPSTREAM_UNREACHABLE
+            }
+        };
+        metrics::counter!(
+            "proxy_control_pings_total",
+            "upstream" => if status == UPSTREAM_REACHABLE { "reachable" } else { "unreachable" }
+        )
+        .increment(1);
+
+        let mut pong = [PONG, 0, 0, 0, 0, status];
+        pong[1..5].copy_from_slice(&nonce.to_be_bytes());
+        stream
+            .write_all(&pong)
+            .await
+            .context("Failed to send Pong")?;
+    }
+
+    tracing::debug!("Control stream finished");
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Client stream wrapper that replays bytes already read from the stream before reading
+/// anything new, passing writes straight through
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    /// Wrap a stream that the prefix has already been read from
+    fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S> AsyncRead for Rewind<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut TaskContext<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let remaining = &self.prefix[self.position..];
+            let length = remaining.len().min(buffer.remaining());
+            buffer.put_slice(&remaining[..length]);
+            self.position += length;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(context, buffer)
+    }
+}
+
+impl<S> AsyncWrite for Rewind<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut TaskContext<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(context, data)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(context)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        context: &mut TaskContext<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(context)
+    }
+}
@@ -0,0 +1,312 @@
+use crate::startup::{GSSENC_REQUEST_CODE, MAX_STARTUP_LENGTH, SSL_REQUEST_CODE};
+use std::{
+    fmt::Write,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Client stream wrapper that records the first bytes flowing in each direction, logging them
+/// as hex at trace level once enough have been seen (or once the stream is dropped, for
+/// connections that fail before then). Only the startup messages of each direction are logged
+/// in full: every byte after them is redacted, since it may carry credentials or query data.
+pub struct ByteDump<S> {
+    inner: S,
+    limit: usize,
+    client_to_upstream: Capture,
+    upstream_to_client: Capture,
+}
+
+impl<S> ByteDump<S> {
+    /// Wrap a client stream, recording up to limit bytes in each direction (or none at all,
+    /// passing everything straight through, if the limit is 0)
+    pub fn new(inner: S, limit: usize) -> Self {
+        Self {
+            inner,
+            limit,
+            client_to_upstream: Capture::default(),
+            upstream_to_client: Capture::default(),
+        }
+    }
+}
+
+impl<S> AsyncRead for ByteDump<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buffer.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(context, buffer);
+        if let Poll::Ready(Ok(())) = result {
+            let limit = self.limit;
+            let data = &buffer.filled()[start..];
+            self.client_to_upstream
+                .record(data, limit, Direction::ClientToUpstream);
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for ByteDump<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(context, data);
+        if let Poll::Ready(Ok(length)) = result {
+            let limit = self.limit;
+            self.upstream_to_client
+                .record(&data[..length], limit, Direction::UpstreamToClient);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(context)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(context)
+    }
+}
+
+/// Log whatever was recorded by streams that closed before reaching the limit, which is
+/// usually the interesting case (e.g. a client that hung up in the middle of its handshake)
+impl<S> Drop for ByteDump<S> {
+    fn drop(&mut self) {
+        self.client_to_upstream.log(Direction::ClientToUpstream);
+        self.upstream_to_client.log(Direction::UpstreamToClient);
+    }
+}
+
+/// Direction of the bytes recorded by a Capture, which determines how they're framed
+#[derive(Clone, Copy)]
+enum Direction {
+    ClientToUpstream,
+    UpstreamToClient,
+}
+
+impl Direction {
+    /// Name of the direction for log fields
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ClientToUpstream => "client_to_upstream",
+            Self::UpstreamToClient => "upstream_to_client",
+        }
+    }
+
+    /// Count the leading bytes that belong to startup messages, which are safe to log.
+    /// Clients start with untyped frames (any encryption requests, then the StartupMessage or
+    /// CancelRequest), while the upstream's first typed message answers the startup. Frames
+    /// with lengths that no startup message could have are redacted along with everything
+    /// after them, while frames that were cut off are shown up to the cut.
+    fn visible(self, bytes: &[u8]) -> usize {
+        let int_at = |offset: usize| {
+            bytes
+                .get(offset..offset + 4)
+                .map(|int| i32::from_be_bytes([int[0], int[1], int[2], int[3]]))
+        };
+        // None while the length is still incomplete, or Some(None) if it's out of range
+        let length_at = |offset, minimum| {
+            int_at(offset).map(|length| {
+                usize::try_from(length)
+                    .ok()
+                    .filter(|length| (minimum..=MAX_STARTUP_LENGTH).contains(length))
+            })
+        };
+
+        match self {
+            Self::ClientToUpstream => {
+                let mut offset = 0;
+                loop {
+                    let length = match length_at(offset, 8) {
+                        Some(Some(length)) => length,
+                        Some(None) => return offset,
+                        None => return bytes.len(),
+                    };
+                    let code = int_at(offset + 4);
+                    offset += length;
+                    let encryption_request =
+                        length == 8 && matches!(code, Some(SSL_REQUEST_CODE | GSSENC_REQUEST_CODE));
+                    if !encryption_request || offset >= bytes.len() {
+                        return offset.min(bytes.len());
+                    }
+                }
+            }
+            Self::UpstreamToClient => {
+                // single-byte answers to encryption requests (when the upstream sees them
+                // verbatim) come before the upstream's first real message
+                let offset = match bytes.first() {
+                    Some(b'N' | b'S' | b'G') => 1,
+                    _ => 0,
+                };
+                match length_at(offset + 1, 4) {
+                    Some(Some(length)) => (offset + 1 + length).min(bytes.len()),
+                    Some(None) => offset,
+                    None => bytes.len(),
+                }
+            }
+        }
+    }
+}
+
+/// First bytes seen in one direction of a stream
+#[derive(Default)]
+struct Capture {
+    bytes: Vec<u8>,
+    logged: bool,
+}
+
+impl Capture {
+    /// Record bytes up to the limit, logging them as soon as the limit is reached
+    fn record(&mut self, data: &[u8], limit: usize, direction: Direction) {
+        if self.logged || limit == 0 {
+            return;
+        }
+
+        let remaining = limit.saturating_sub(self.bytes.len());
+        self.bytes
+            .extend_from_slice(&data[..data.len().min(remaining)]);
+        if self.bytes.len() >= limit {
+            self.log(direction);
+        }
+    }
+
+    /// Log the recorded bytes as hex (at most once), with everything after the startup
+    /// messages replaced by "**"
+    fn log(&mut self, direction: Direction) {
+        if self.logged || self.bytes.is_empty() {
+            return;
+        }
+        self.logged = true;
+
+        let visible = direction.visible(&self.bytes);
+        let hex = redacted_hex(&self.bytes, visible);
+        tracing::trace!(
+            direction = direction.as_str(),
+            length = self.bytes.len(),
+            redacted = self.bytes.len() - visible,
+            %hex,
+            "First bytes of proxy connection"
+        );
+    }
+}
+
+/// Format bytes as hex, replacing each byte after the first visible bytes with "**"
+fn redacted_hex(bytes: &[u8], visible: usize) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in &bytes[..visible] {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex.push_str(&"**".repeat(bytes.len() - visible));
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use postgres_protocol::message::frontend;
+
+    /// Cleartext password, which must never show up in a dump
+    const PASSWORD: &str = "hunter2";
+
+    /// Check that exactly the first startup bytes of a capture appear in its hex, with every
+    /// byte after them (and the password in particular) redacted
+    fn assert_redacted(direction: Direction, bytes: &[u8], startup: usize) {
+        let hex = redacted_hex(bytes, direction.visible(bytes));
+        let (shown, redacted) = hex.split_at(startup * 2);
+        assert_eq!(shown, redacted_hex(&bytes[..startup], startup));
+        assert_eq!(redacted, "**".repeat(bytes.len() - startup));
+        assert!(!hex.contains(&redacted_hex(PASSWORD.as_bytes(), PASSWORD.len())));
+    }
+
+    /// Encode an untyped frame with a 4-byte code (e.g. an SSLRequest)
+    fn request(code: i32) -> Vec<u8> {
+        [8i32.to_be_bytes(), code.to_be_bytes()].concat()
+    }
+
+    /// Encode the StartupMessage and PasswordMessage of a client
+    fn startup_and_password() -> (BytesMut, BytesMut) {
+        let mut startup = BytesMut::new();
+        frontend::startup_message([("user", "alice"), ("database", "app")], &mut startup).unwrap();
+        let mut password = BytesMut::new();
+        frontend::password_message(PASSWORD.as_bytes(), &mut password).unwrap();
+        (startup, password)
+    }
+
+    #[test]
+    fn clients_show_encryption_requests_and_the_startup_message() {
+        let (startup, password) = startup_and_password();
+        let preambles = [request(GSSENC_REQUEST_CODE), request(SSL_REQUEST_CODE)].concat();
+        let bytes = [&preambles[..], &startup, &password].concat();
+        assert_redacted(
+            Direction::ClientToUpstream,
+            &bytes,
+            preambles.len() + startup.len(),
+        );
+
+        let bytes = [&startup[..], &password].concat();
+        assert_redacted(Direction::ClientToUpstream, &bytes, startup.len());
+    }
+
+    #[test]
+    fn upstreams_show_the_answer_to_the_startup_message() {
+        // an SSLRequest refusal, AuthenticationCleartextPassword, then AuthenticationOk
+        let authentication = b"R\0\0\0\x08\0\0\0\x03";
+        let bytes = [&b"N"[..], authentication, b"R\0\0\0\x08\0\0\0\0"].concat();
+        assert_redacted(
+            Direction::UpstreamToClient,
+            &bytes,
+            1 + authentication.len(),
+        );
+
+        let bytes = [&authentication[..], b"R\0\0\0\x08\0\0\0\0"].concat();
+        assert_redacted(Direction::UpstreamToClient, &bytes, authentication.len());
+    }
+
+    #[test]
+    fn cut_off_captures_show_everything_up_to_the_cut() {
+        let (startup, _) = startup_and_password();
+        let preamble = request(SSL_REQUEST_CODE);
+        for cut in [2, 8, 10, 8 + startup.len() / 2] {
+            let bytes = [&preamble[..], &startup].concat();
+            assert_redacted(Direction::ClientToUpstream, &bytes[..cut], cut);
+        }
+
+        let bytes = b"NR\0\0\0\x08\0\0\0\x03";
+        for cut in [1, 3, 7] {
+            assert_redacted(Direction::UpstreamToClient, &bytes[..cut], cut);
+        }
+    }
+
+    #[test]
+    fn invalid_lengths_redact_the_rest_of_the_capture() {
+        let (_, password) = startup_and_password();
+        let preamble = request(SSL_REQUEST_CODE);
+        for length in [-1, 4, i32::MAX] {
+            let frame = [length.to_be_bytes(), (3i32 << 16).to_be_bytes()].concat();
+            let bytes = [&frame[..], &password].concat();
+            assert_redacted(Direction::ClientToUpstream, &bytes, 0);
+            let bytes = [&preamble[..], &frame, &password].concat();
+            assert_redacted(Direction::ClientToUpstream, &bytes, preamble.len());
+        }
+
+        for length in [-1, 3, i32::MAX] {
+            let frame = [&b"R"[..], &length.to_be_bytes()].concat();
+            let bytes = [&frame[..], &password].concat();
+            assert_redacted(Direction::UpstreamToClient, &bytes, 0);
+            let bytes = [&b"N"[..], &frame, &password].concat();
+            assert_redacted(Direction::UpstreamToClient, &bytes, 1);
+        }
+    }
+}
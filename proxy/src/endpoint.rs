@@ -1,31 +1,429 @@
+use anyhow::Context;
 use futures::Stream;
+use quinn::{congestion, VarInt};
 use rustls::ServerConfig;
-use std::{net::SocketAddrV4, sync::Arc, time::Duration};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Time to wait after a failed accept before trying again, so that persistent errors (like
+/// running out of file descriptors) don't spin the accept loop
+pub const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// IP protocol versions accepted by an Endpoint's UDP socket
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum IpStack {
+    /// accept IPv4 connections only
+    V4,
+    /// accept IPv6 connections only
+    V6,
+    /// accept both IPv4 and IPv6 connections on a single IPv6 socket
+    Dual,
+}
+
+/// Default to the IP stack that matches the address family of the host
+impl From<IpAddr> for IpStack {
+    fn from(address: IpAddr) -> Self {
+        match address {
+            IpAddr::V4(_) => Self::V4,
+            IpAddr::V6(_) => Self::V6,
+        }
+    }
+}
+
+/// Where an auxiliary plain-HTTP server (for metrics or health checks) listens
+#[derive(Clone, Debug)]
+pub enum HttpAddress {
+    /// a TCP address on the network
+    Tcp(SocketAddr),
+    /// a Unix socket path, reachable only from processes that can open it (e.g. a sidecar),
+    /// replacing any stale socket left at that path
+    Unix(PathBuf),
+}
+
+impl HttpAddress {
+    /// Listen on a Unix socket path if one is given, or else on a port of the host address
+    pub fn new(host: IpAddr, port: Option<u16>, socket: Option<&PathBuf>) -> Option<Self> {
+        match (socket, port) {
+            (Some(path), _) => Some(Self::Unix(path.clone())),
+            (None, Some(port)) => Some(Self::Tcp(SocketAddr::new(host, port))),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Remove the socket left at a Unix socket path by an earlier listener (e.g. one that crashed),
+/// making way for a new listener. Anything else at the path is left in place and reported as an
+/// error, so that a mistyped path never deletes a file.
+#[cfg(unix)]
+pub fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to inspect {}", path.display()))
+        }
+    };
+    anyhow::ensure!(
+        metadata.file_type().is_socket(),
+        "Refusing to replace {}, which isn't a socket",
+        path.display()
+    );
+    std::fs::remove_file(path)
+        .with_context(|| format!("Failed to remove stale socket {}", path.display()))
+}
+
+impl fmt::Display for HttpAddress {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => address.fmt(formatter),
+            Self::Unix(path) => path.display().fmt(formatter),
+        }
+    }
+}
+
+/// QUIC congestion control algorithms
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum CongestionControl {
+    /// CUBIC, quinn's default and a good fit for most links
+    Cubic,
+    /// NewReno, the classic loss-based algorithm
+    NewReno,
+    /// BBR, which models bandwidth and round-trip time (e.g. for high bandwidth-delay links)
+    Bbr,
+}
+
+/// Options applied to the UDP socket of an Endpoint
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketOptions {
+    /// set SO_REUSEADDR before binding, so that a restarted proxy can bind right away
+    pub reuse_address: bool,
+    /// set SO_REUSEPORT before binding, so that several processes can share one port (only
+    /// supported on Linux and the BSDs, where the kernel balances datagrams across sockets)
+    #[cfg(unix)]
+    pub reuse_port: bool,
+    /// size of the socket's send buffer in bytes (the OS default if unset)
+    pub send_buffer_size: Option<usize>,
+    /// size of the socket's receive buffer in bytes (the OS default if unset)
+    pub recv_buffer_size: Option<usize>,
+}
 
 /// QUIC connection-listener server
 pub struct Endpoint {
-    server_config: quinn::ServerConfig,
+    tls: ServerConfig,
+    transport: quinn::TransportConfig,
+    socket: SocketOptions,
 }
 
 impl Endpoint {
     /// Create a new Endpoint from a TLS configuration
     pub fn new(tls: ServerConfig) -> Self {
-        let mut transport_config = quinn::TransportConfig::default();
-        transport_config.keep_alive_interval(Some(Duration::from_secs(2)));
-        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls));
-        server_config.transport_config(transport_config.into());
-        Self { server_config }
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(Duration::from_secs(2)));
+        Self {
+            tls,
+            transport,
+            socket: SocketOptions::default(),
+        }
+    }
+
+    /// Apply options to the UDP socket that the Endpoint listens on. Address and port reuse
+    /// only take effect when the Endpoint binds its own socket with Endpoint::listen.
+    pub fn with_socket_options(mut self, socket: SocketOptions) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    /// Send keep-alive packets at the given interval, closing connections that have been
+    /// idle for longer than the idle timeout
+    pub fn with_timeouts(
+        mut self,
+        keep_alive_interval: Duration,
+        max_idle_timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            max_idle_timeout > keep_alive_interval,
+            "Max idle timeout ({max_idle_timeout:?}) must be longer than the keep-alive interval ({keep_alive_interval:?})"
+        );
+        let max_idle_timeout =
+            quinn::IdleTimeout::try_from(max_idle_timeout).context("Invalid max idle timeout")?;
+
+        self.transport
+            .keep_alive_interval(Some(keep_alive_interval))
+            .max_idle_timeout(Some(max_idle_timeout));
+        Ok(self)
     }
 
-    /// Listen on a specific port using this Endpoint's configuration
+    /// Use a specific congestion control algorithm, optionally overriding its initial
+    /// congestion window (in bytes)
+    pub fn with_congestion_control(
+        mut self,
+        algorithm: CongestionControl,
+        initial_window: Option<u64>,
+    ) -> Self {
+        match algorithm {
+            CongestionControl::Cubic => {
+                let mut config = congestion::CubicConfig::default();
+                if let Some(initial_window) = initial_window {
+                    config.initial_window(initial_window);
+                }
+                self.transport
+                    .congestion_controller_factory(Arc::new(config));
+            }
+            CongestionControl::NewReno => {
+                let mut config = congestion::NewRenoConfig::default();
+                if let Some(initial_window) = initial_window {
+                    config.initial_window(initial_window);
+                }
+                self.transport
+                    .congestion_controller_factory(Arc::new(config));
+            }
+            CongestionControl::Bbr => {
+                let mut config = congestion::BbrConfig::default();
+                if let Some(initial_window) = initial_window {
+                    config.initial_window(initial_window);
+                }
+                self.transport
+                    .congestion_controller_factory(Arc::new(config));
+            }
+        }
+        self
+    }
+
+    /// Cap the number of concurrently open streams that each client may initiate. Every
+    /// WebTransport session needs a bi-directional CONNECT stream alongside its proxied streams,
+    /// and HTTP/3 needs at least 3 uni-directional streams for its control and QPACK streams.
+    pub fn with_stream_limits(mut self, bidi: u32, uni: u32) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            bidi >= 2,
+            "At least 2 concurrent bi-directional streams are required"
+        );
+        anyhow::ensure!(
+            uni >= 3,
+            "At least 3 concurrent uni-directional streams are required"
+        );
+        self.transport
+            .max_concurrent_bidi_streams(bidi.into())
+            .max_concurrent_uni_streams(uni.into());
+        Ok(self)
+    }
+
+    /// Size the QUIC flow-control windows, in bytes. The stream receive window caps how much
+    /// a client may send on any one stream before waiting for the proxy to read it, while the
+    /// (connection-level) receive window caps the total across every stream of a connection,
+    /// so it should be at least as large as the stream receive window, and a multiple of it
+    /// for clients that run several streams at once (unlimited if unset). The send window
+    /// caps how much the proxy sends on a connection before waiting for acknowledgements,
+    /// bounding the memory held per connection. Each window should be at least the expected
+    /// round-trip time multiplied by the desired throughput, e.g. 1.25 MB for 100 Mbps at a
+    /// 100ms RTT.
+    pub fn with_flow_control(
+        mut self,
+        stream_receive_window: u64,
+        receive_window: Option<u64>,
+        send_window: u64,
+    ) -> anyhow::Result<Self> {
+        if let Some(receive_window) = receive_window {
+            anyhow::ensure!(
+                receive_window >= stream_receive_window,
+                "Receive window ({receive_window}) must be at least as large as the stream receive window ({stream_receive_window})"
+            );
+            self.transport.receive_window(
+                VarInt::from_u64(receive_window).context("Receive window is too large")?,
+            );
+        }
+        self.transport
+            .stream_receive_window(
+                VarInt::from_u64(stream_receive_window)
+                    .context("Stream receive window is too large")?,
+            )
+            .send_window(send_window);
+        Ok(self)
+    }
+
+    /// Listen on a specific socket address using this Endpoint's configuration
     #[tracing::instrument(skip(self), err)]
-    pub fn listen(self, port: u16) -> anyhow::Result<impl Stream<Item = quinn::Connecting>> {
-        let address = SocketAddrV4::new([127, 0, 0, 1].into(), port);
-        let endpoint = quinn::Endpoint::server(self.server_config, address.into())?;
+    pub fn listen(
+        self,
+        address: SocketAddr,
+        stack: IpStack,
+    ) -> anyhow::Result<impl Stream<Item = quinn::Connecting>> {
+        // dual-stack sockets are IPv6 sockets that also accept IPv4-mapped addresses
+        let domain = match (stack, address) {
+            (IpStack::V4, SocketAddr::V4(_)) => Domain::IPV4,
+            (IpStack::V6 | IpStack::Dual, SocketAddr::V6(_)) => Domain::IPV6,
+            _ => anyhow::bail!("Address {address} is incompatible with the {stack:?} IP stack"),
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        if domain == Domain::IPV6 {
+            socket.set_only_v6(matches!(stack, IpStack::V6))?;
+        }
+        socket.set_reuse_address(self.socket.reuse_address)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(self.socket.reuse_port)?;
+        socket
+            .bind(&address.into())
+            .with_context(|| format!("Failed to bind QUIC endpoint to {address}"))?;
+
+        self.listen_on_socket(socket.into())
+    }
+
+    /// Listen on an already-bound UDP socket using this Endpoint's configuration, e.g. a socket
+    /// inherited through systemd socket activation, or bound to a privileged port before the
+    /// process dropped its privileges
+    #[tracing::instrument(skip_all, err)]
+    pub fn listen_on_socket(
+        self,
+        socket: UdpSocket,
+    ) -> anyhow::Result<impl Stream<Item = quinn::Connecting>> {
+        anyhow::ensure!(
+            SockRef::from(&socket).r#type()? == Type::DGRAM,
+            "QUIC endpoints can only listen on UDP sockets"
+        );
+        socket.set_nonblocking(true)?;
+
+        // the OS may round (or cap) buffer sizes, so log the sizes that were actually set
+        let socket_ref = SockRef::from(&socket);
+        if let Some(size) = self.socket.send_buffer_size {
+            socket_ref.set_send_buffer_size(size)?;
+            tracing::debug!(
+                size = socket_ref.send_buffer_size()?,
+                "Set UDP send buffer size"
+            );
+        }
+        if let Some(size) = self.socket.recv_buffer_size {
+            socket_ref.set_recv_buffer_size(size)?;
+            tracing::debug!(
+                size = socket_ref.recv_buffer_size()?,
+                "Set UDP receive buffer size"
+            );
+        }
+
+        // hand the bound socket off to quinn
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(self.tls));
+        server_config.transport_config(Arc::new(self.transport));
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(server_config),
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+        let local_address = endpoint.local_addr()?;
         let connection_attempts = futures::stream::unfold(endpoint, |endpoint| async {
-            endpoint.accept().await.map(|attempt| (attempt, endpoint))
+            let attempt = endpoint.accept().await?;
+            metrics::counter!("proxy_connection_attempts_total").increment(1);
+            Some((attempt, endpoint))
         });
-        tracing::info!("listening for new connections");
+        tracing::info!(%local_address, "listening for new connections");
         Ok(connection_attempts)
     }
 }
+
+/// Listen for plain TCP connections on an address, bypassing QUIC and HTTP/3 entirely
+pub async fn listen_tcp(address: SocketAddr) -> anyhow::Result<impl Stream<Item = TcpStream>> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind TCP listener to {address}"))?;
+    let local_address = listener.local_addr()?;
+    let connections = futures::stream::unfold(listener, |listener| async {
+        loop {
+            match listener.accept().await {
+                Ok((connection, peer)) => {
+                    metrics::counter!("proxy_connection_attempts_total").increment(1);
+                    tracing::debug!(%peer, "Accepted TCP connection");
+                    return Some((connection, listener));
+                }
+                Err(error) => {
+                    tracing::warn!(%error, "Failed to accept TCP connection");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+            }
+        }
+    });
+    tracing::info!(%local_address, "listening for new TCP connections");
+    Ok(connections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use rustls::{Certificate, PrivateKey, RootCertStore};
+
+    /// Build a TLS configuration around a fresh self-signed certificate for localhost
+    fn self_signed() -> ServerConfig {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(certified.cert.der().to_vec())],
+                PrivateKey(certified.key_pair.serialize_der()),
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn ipv6_endpoints_receive_connection_attempts() {
+        // reserve an ephemeral UDP port, then hand it over to the Endpoint
+        let port = UdpSocket::bind("[::1]:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port));
+        let attempts = Endpoint::new(self_signed())
+            .listen(address, IpStack::V6)
+            .unwrap();
+        tokio::pin!(attempts);
+
+        // the handshake can't succeed without trusted roots, but the attempt still arrives
+        let client = quinn::Endpoint::client("[::1]:0".parse().unwrap()).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let connecting = client
+            .connect_with(
+                quinn::ClientConfig::new(Arc::new(config)),
+                address,
+                "localhost",
+            )
+            .unwrap();
+        tokio::spawn(connecting);
+
+        let attempt = tokio::time::timeout(Duration::from_secs(5), attempts.next())
+            .await
+            .expect("no connection attempt arrived")
+            .unwrap();
+        assert_eq!(attempt.remote_address(), client.local_addr().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn only_stale_sockets_are_removed() {
+        let directory = std::env::temp_dir().join(format!("proxy-sockets-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let socket = directory.join("health.sock");
+        drop(tokio::net::UnixListener::bind(&socket).unwrap());
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(&socket).unwrap();
+
+        let file = directory.join("health.conf");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
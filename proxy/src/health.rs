@@ -0,0 +1,231 @@
+use crate::{
+    endpoint::{HttpAddress, ACCEPT_ERROR_BACKOFF},
+    proxy::Upstream,
+};
+use anyhow::Context;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Largest request head read from a health check client
+const MAX_REQUEST_SIZE: usize = 1024;
+
+/// Time allowed for a health check client to send its request, or for an upstream probe
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Liveness and readiness of the proxy, as reported to health checks
+pub struct Health {
+    accepting: AtomicBool,
+    upstream: Option<Upstream>,
+}
+
+impl Health {
+    /// Create a new Health that isn't accepting connections yet, optionally probing an
+    /// upstream before reporting the proxy as ready
+    pub fn new(upstream: Option<Upstream>) -> Self {
+        Self {
+            accepting: AtomicBool::new(false),
+            upstream,
+        }
+    }
+
+    /// Record whether the proxy's listener is accepting new connections
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::Relaxed);
+    }
+
+    /// Check if the proxy is accepting new connections
+    fn is_live(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    /// Check if the proxy is accepting new connections and (if configured) can reach its upstream
+    async fn is_ready(&self) -> bool {
+        if !self.is_live() {
+            return false;
+        }
+        match &self.upstream {
+            Some(upstream) => match tokio::time::timeout(TIMEOUT, upstream.probe()).await {
+                Ok(Ok(())) => true,
+                Ok(Err(error)) => {
+                    tracing::warn!(%error, "Readiness probe failed to reach upstream");
+                    false
+                }
+                Err(_) => {
+                    tracing::warn!("Readiness probe timed out reaching upstream");
+                    false
+                }
+            },
+            None => true,
+        }
+    }
+}
+
+/// Serve plain-HTTP health checks at the given address: GET /healthz responds with 200 while
+/// the proxy is accepting connections, and GET /readyz additionally probes the upstream
+pub async fn install(address: HttpAddress, health: Arc<Health>) -> anyhow::Result<()> {
+    match &address {
+        HttpAddress::Tcp(address) => {
+            let listener = TcpListener::bind(address)
+                .await
+                .with_context(|| format!("Failed to start health server on {address}"))?;
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(respond(stream, health.clone()));
+                        }
+                        Err(error) => {
+                            tracing::warn!(%error, "Failed to accept health check");
+                            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        }
+                    }
+                }
+            });
+        }
+        #[cfg(unix)]
+        HttpAddress::Unix(path) => {
+            crate::endpoint::remove_stale_socket(path)?;
+            let listener = tokio::net::UnixListener::bind(path)
+                .with_context(|| format!("Failed to start health server on {}", path.display()))?;
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(respond(stream, health.clone()));
+                        }
+                        Err(error) => {
+                            tracing::warn!(%error, "Failed to accept health check");
+                            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        }
+                    }
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        HttpAddress::Unix(path) => anyhow::bail!(
+            "Unix socket health servers are not supported on this platform: {}",
+            path.display()
+        ),
+    }
+
+    tracing::info!(%address, "serving health checks");
+    Ok(())
+}
+
+/// Answer a single health check request, then close the connection
+async fn respond<S>(mut stream: S, health: Arc<Health>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let status = match tokio::time::timeout(TIMEOUT, read_request_line(&mut stream)).await {
+        Ok(Some(line)) => {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("GET"), Some("/healthz")) if health.is_live() => "200 OK",
+                (Some("GET"), Some("/readyz")) if health.is_ready().await => "200 OK",
+                (Some("GET"), Some("/healthz" | "/readyz")) => "503 Service Unavailable",
+                (Some("GET"), _) => "404 Not Found",
+                _ => "405 Method Not Allowed",
+            }
+        }
+        _ => "400 Bad Request",
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{status}\n",
+        status.len() + 1
+    );
+    if let Err(error) = stream.write_all(response.as_bytes()).await {
+        tracing::debug!(%error, "Failed to respond to health check");
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Read the head of an HTTP request, returning its request line
+async fn read_request_line<S>(stream: &mut S) -> Option<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut request = Vec::new();
+    let mut buffer = [0; 256];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let length = stream.read(&mut buffer).await.ok()?;
+        if length == 0 || request.len() + length > MAX_REQUEST_SIZE {
+            return None;
+        }
+        request.extend_from_slice(&buffer[..length]);
+    }
+
+    let line = request.split(|byte| *byte == b'\n').next()?;
+    String::from_utf8(line.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// Send a request (which may be incomplete) to a health server, returning the status line
+    /// of its response
+    async fn status(health: &Arc<Health>, request: &[u8]) -> String {
+        let (stream, mut client) = duplex(4 * MAX_REQUEST_SIZE);
+        let responding = tokio::spawn(respond(stream, health.clone()));
+        client.write_all(request).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        responding.await.unwrap();
+        response.lines().next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn probes_follow_whether_the_proxy_is_accepting() {
+        let health = Arc::new(Health::new(None));
+        for path in ["/healthz", "/readyz"] {
+            let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            health.set_accepting(false);
+            let unavailable = status(&health, request.as_bytes()).await;
+            assert_eq!(unavailable, "HTTP/1.1 503 Service Unavailable");
+            health.set_accepting(true);
+            assert_eq!(status(&health, request.as_bytes()).await, "HTTP/1.1 200 OK");
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_and_methods_are_refused() {
+        let health = Arc::new(Health::new(None));
+        health.set_accepting(true);
+        let not_found = status(&health, b"GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert_eq!(not_found, "HTTP/1.1 404 Not Found");
+        let not_allowed = status(&health, b"POST /healthz HTTP/1.1\r\n\r\n").await;
+        assert_eq!(not_allowed, "HTTP/1.1 405 Method Not Allowed");
+    }
+
+    #[tokio::test]
+    async fn oversized_and_unfinished_requests_are_bad_requests() {
+        let health = Arc::new(Health::new(None));
+        health.set_accepting(true);
+
+        let mut oversized = b"GET /healthz HTTP/1.1\r\n".to_vec();
+        oversized.extend(b"X-Padding: x\r\n".repeat(MAX_REQUEST_SIZE / 14 + 1));
+        oversized.extend(b"\r\n");
+        assert_eq!(
+            status(&health, &oversized).await,
+            "HTTP/1.1 400 Bad Request"
+        );
+
+        // a request head that never ends is cut off after the timeout
+        let started = tokio::time::Instant::now();
+        let unfinished = status(&health, b"GET /healthz HTTP/1.1\r\n").await;
+        assert_eq!(unfinished, "HTTP/1.1 400 Bad Request");
+        assert!(started.elapsed() >= TIMEOUT);
+    }
+}
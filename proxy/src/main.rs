@@ -1,88 +1,1104 @@
+use allowlist::Allowlist;
+use bytes::Bytes;
 use clap::Parser;
-use endpoint::Endpoint;
-use futures::{StreamExt, TryFutureExt};
-use proxy::Proxy;
-use rustls::{Certificate, PrivateKey};
-use session::Session;
-use std::path::PathBuf;
+use control::Negotiated;
+use endpoint::{CongestionControl, Endpoint, HttpAddress, IpStack, SocketOptions};
+use futures::{stream::BoxStream, FutureExt, StreamExt, TryFutureExt};
+use health::Health;
+use http::header::HeaderName;
+use pool::Pool;
+use proxy::{Proxy, ProxyStats, Rejection, Route, SslMode, Upstream, UpstreamTarget};
+use rate_limit::RateLimiter;
+use rustls::{Certificate, RootCertStore};
+use sec_http3::quic::{RecvStream as _, SendStream};
+use session::{
+    RecvStream, Session, SessionPolicy, Sessions, Stream, CLOSE_NO_ROUTE, CLOSE_POLICY_VIOLATION,
+    STREAM_REJECTED,
+};
+use socket2::TcpKeepalive;
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use telemetry::ActiveGauge;
+use tls::{CertFormat, PeerIdentity, TlsConfigBuilder};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+use tracing::Instrument;
 use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
 
+mod allowlist;
+mod control;
+mod dump;
 mod endpoint;
+mod health;
+mod pool;
 mod proxy;
+mod quic;
+mod rate_limit;
 mod session;
+mod startup;
+mod telemetry;
+mod tls;
 
 // TODO: switch over to wtransport for a simpler server, perhaps?
 // https://github.com/BiagioFesta/wtransport
 
+/// Reason given to clients when closing sessions whose CONNECT path has no upstream route
+const NO_ROUTE_REASON: &str = "no upstream route for this path";
+
+/// Datagram payload that clients send to check that their session is alive
+const PING: &[u8] = b"ping";
+
+/// Datagram payload sent back to clients in response to a PING
+const PONG: &[u8] = b"pong";
+
 /// Startup values for this server, provided by arguments when the binary is invoked.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Configuration {
-    /// path to a DER-encoded cert file
+    /// protocol that clients use to connect to the proxy
+    #[arg(long, value_enum, default_value = "web-transport")]
+    mode: Mode,
+
+    /// path to a PEM- or DER-encoded cert file (PEM files may contain a full chain)
     #[arg(short, long, default_value = "./certs/localhost.crt")]
     cert: PathBuf,
 
-    /// path to a DER-encoded key file
+    /// path to a PEM- or DER-encoded key file
     #[arg(short, long, default_value = "./certs/localhost.key")]
     key: PathBuf,
 
+    /// encoding of the cert and key files (detected from their contents by default)
+    #[arg(long, value_enum)]
+    cert_format: Option<CertFormat>,
+
+    /// ALPN protocols offered during the TLS handshake, separated by commas in order of
+    /// preference
+    #[arg(long, value_delimiter = ',', default_values_t = tls::HTTP3_ALPN_PROTOCOLS.map(String::from))]
+    alpn_protocols: Vec<String>,
+
+    /// accept 0-RTT early data from resuming clients. Early data can be replayed by an
+    /// attacker, re-running whatever Postgres messages (including writes) came with it, so
+    /// this is off by default and should only be enabled for replay-safe workloads
+    #[arg(long = "allow-0rtt")]
+    allow_0rtt: bool,
+
+    /// path to a PEM- or DER-encoded file of CA certificates that client certificates are
+    /// verified against (client certificates aren't requested by default)
+    #[arg(long)]
+    client_ca: Option<PathBuf>,
+
+    /// reject clients that don't present a certificate signed by the client CA
+    #[arg(long, requires = "client_ca")]
+    require_client_auth: bool,
+
+    /// host address that the server will bind to
+    #[arg(long, default_value = "127.0.0.1")]
+    host: IpAddr,
+
+    /// IP stack of the listening socket (defaults to the address family of the host)
+    #[arg(long, value_enum)]
+    ip_stack: Option<IpStack>,
+
     /// port that the server will listen on
     #[arg(short, long, default_value = "4433")]
     port: u16,
 
+    /// file descriptor of an already-bound UDP socket to listen on in place of the host and
+    /// port, e.g. 3 for a socket passed in through systemd socket activation
+    #[cfg(unix)]
+    #[arg(long, conflicts_with_all = ["host", "port", "ip_stack"])]
+    listen_fd: Option<i32>,
+
+    /// set SO_REUSEADDR on the listening UDP socket, e.g. for fast restarts
+    #[arg(long)]
+    reuse_address: bool,
+
+    /// set SO_REUSEPORT on the listening UDP socket, letting several proxies share a port
+    /// (Linux and BSD only)
+    #[cfg(unix)]
+    #[arg(long)]
+    reuse_port: bool,
+
+    /// size of the listening UDP socket's send buffer in bytes (the OS default if unset)
+    #[arg(long)]
+    udp_send_buffer: Option<usize>,
+
+    /// size of the listening UDP socket's receive buffer in bytes (the OS default if unset)
+    #[arg(long)]
+    udp_recv_buffer: Option<usize>,
+
+    /// connection string of the Postgres server that is being proxied, in place of the other
+    /// upstream address and TLS options: either a URI like
+    /// postgres://db.internal:5432?sslmode=require&sslrootcert=/etc/ssl/db.crt, KEY=VALUE pairs
+    /// like "host=db.internal sslmode=prefer", or the path to a Unix socket or its directory
+    /// (e.g. /var/run/postgresql). Any user or database is ignored, since clients send their own
+    #[arg(long, conflicts_with_all = [
+        "upstream_host", "upstream_port", "upstream_socket", "upstream_tls", "upstream_ca",
+    ])]
+    upstream: Option<UpstreamTarget>,
+
+    /// host (IP address or DNS name) of the TCP service that is being proxied
+    #[arg(long, default_value = "127.0.0.1")]
+    upstream_host: String,
+
     /// port of the TCP service that is being proxied
     #[arg(short, long, default_value = "5432")]
     upstream_port: u16,
+
+    /// path to the Unix-domain socket of the service that is being proxied, used in place of
+    /// the upstream host and port (e.g. /var/run/postgresql/.s.PGSQL.5432)
+    #[arg(long, conflicts_with_all = ["upstream_host", "upstream_port"])]
+    upstream_socket: Option<PathBuf>,
+
+    /// seconds that an upstream TCP connection may sit idle before TCP keepalive probes are
+    /// sent, or 0 to disable keepalive
+    #[arg(long, default_value = "60")]
+    upstream_keepalive_time: u64,
+
+    /// seconds between unanswered TCP keepalive probes to the upstream (the OS default if
+    /// unset)
+    #[arg(long)]
+    upstream_keepalive_interval: Option<u64>,
+
+    /// seconds to wait for a connection to the upstream (including any TLS negotiation)
+    /// before giving up on it, or 0 to wait as long as the OS does
+    #[arg(long, default_value = "5")]
+    upstream_connect_timeout: u64,
+
+    /// name of a CONNECT request header carrying the Postgres user (e.g. x-pg-user), which
+    /// replaces the user of every startup message (so startup interception is required)
+    #[arg(long, requires = "intercept_startup")]
+    user_header: Option<HeaderName>,
+
+    /// reject sessions that are missing the user header
+    #[arg(long, requires_all = ["user_header", "intercept_startup"])]
+    require_user_header: bool,
+
+    /// use the client certificate's common name (or else its first subject alternative name)
+    /// as the Postgres user, in place of a user header (so startup interception is required)
+    #[arg(
+        long,
+        requires_all = ["require_client_auth", "intercept_startup"],
+        conflicts_with = "user_header"
+    )]
+    user_from_client_cert: bool,
+
+    /// web origins (e.g. https://example.com) allowed to open WebTransport sessions, separated
+    /// by commas (any origin is allowed by default)
+    #[arg(long, value_delimiter = ',')]
+    allowed_origins: Vec<String>,
+
+    /// allow sessions without an Origin header (e.g. from non-browser clients) when
+    /// allowed-origins is set
+    #[arg(long, requires = "allowed_origins")]
+    allow_missing_origin: bool,
+
+    /// maximum number of WebTransport sessions multiplexed over each HTTP/3 connection
+    #[arg(long, default_value = "1")]
+    max_sessions: u64,
+
+    /// parse the Postgres startup message of each stream, rewriting the user to match the
+    /// user header before forwarding it upstream
+    #[arg(long)]
+    intercept_startup: bool,
+
+    /// users that clients may request in their startup messages, separated by commas (any
+    /// user is allowed by default)
+    #[arg(long, value_delimiter = ',', requires = "intercept_startup")]
+    allowed_users: Vec<String>,
+
+    /// databases that clients may request in their startup messages, separated by commas (any
+    /// database is allowed by default)
+    #[arg(long, value_delimiter = ',', requires = "intercept_startup")]
+    allowed_databases: Vec<String>,
+
+    /// path to a file of allowed users and databases, with one user=NAME or database=NAME
+    /// entry per line, added to allowed-users and allowed-databases
+    #[arg(long, requires = "intercept_startup")]
+    allowlist_file: Option<PathBuf>,
+
+    /// keep up to this many idle upstream connections per upstream and set of startup
+    /// parameters, handing them to new streams instead of connecting (and starting up) anew.
+    /// Only suitable for upstreams that trust the proxy, since clients never authenticate
+    /// pooled connections themselves
+    #[arg(long, requires = "intercept_startup")]
+    pool_size: Option<usize>,
+
+    /// seconds that a pooled upstream connection may sit idle before it is closed
+    #[arg(long, default_value = "300")]
+    pool_idle_timeout: u64,
+
+    /// route sessions on a CONNECT path to a specific upstream, formatted as PATH=HOST:PORT
+    /// or PATH=/SOCKET
+    /// (may be repeated; once any routes are set, sessions on other paths are rejected)
+    #[arg(long = "route")]
+    routes: Vec<Route>,
+
+    /// seconds between QUIC keep-alive packets
+    #[arg(long, default_value = "2")]
+    keepalive_interval: u64,
+
+    /// seconds without any QUIC packets (including keep-alives) before a connection is closed
+    #[arg(long, default_value = "30")]
+    max_idle_timeout: u64,
+
+    /// QUIC congestion control algorithm
+    #[arg(long, value_enum, default_value = "cubic")]
+    congestion_control: CongestionControl,
+
+    /// initial congestion window in bytes (defaults to the algorithm's own initial window)
+    #[arg(long)]
+    initial_window: Option<u64>,
+
+    /// maximum number of concurrent bi-directional streams per QUIC connection, including
+    /// the CONNECT stream of each WebTransport session
+    #[arg(long, default_value = "100")]
+    max_bidi_streams: u32,
+
+    /// maximum number of concurrent uni-directional streams per QUIC connection
+    #[arg(long, default_value = "100")]
+    max_uni_streams: u32,
+
+    /// bytes that a client may send on a single stream before it's read by the proxy (QUIC's
+    /// per-stream flow-control window)
+    #[arg(long, default_value = "1250000")]
+    stream_receive_window: u64,
+
+    /// bytes that a client may send across all streams of a connection before they're read
+    /// by the proxy (QUIC's connection-level flow-control window, unlimited by default)
+    #[arg(long)]
+    receive_window: Option<u64>,
+
+    /// bytes that the proxy may send on a connection before they're acknowledged
+    #[arg(long, default_value = "10000000")]
+    send_window: u64,
+
+    /// seconds to wait for active connections to close after a shutdown signal
+    #[arg(long, default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// seconds without traffic in either direction before a proxy connection is closed
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+
+    /// seconds after which a proxy connection is closed no matter how active it is, e.g. for
+    /// rotating connections (unlike idle-timeout, which only closes inactive connections)
+    #[arg(long)]
+    max_session_duration: Option<u64>,
+
+    /// log the first N bytes sent in each direction of every proxy connection as hex at trace
+    /// level, with everything after the startup messages redacted, for debugging handshakes
+    #[arg(long, default_value = "0")]
+    debug_dump_bytes: usize,
+
+    /// require TLS on connections to the upstream Postgres server
+    #[arg(long)]
+    upstream_tls: bool,
+
+    /// path to a PEM- or DER-encoded root certificate for verifying the upstream server
+    /// (defaults to the platform's native root certificates)
+    #[arg(long, requires = "upstream_tls")]
+    upstream_ca: Option<PathBuf>,
+
+    /// format of the log lines written to stderr
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// port for serving Prometheus metrics over HTTP on the host address (disabled by default)
+    #[arg(long, group = "metrics")]
+    metrics_port: Option<u16>,
+
+    /// Unix socket path for serving Prometheus metrics over HTTP in place of metrics-port,
+    /// keeping them off the network (e.g. for scraping from a sidecar)
+    #[arg(long, group = "metrics")]
+    metrics_socket: Option<PathBuf>,
+
+    /// port for serving plain-HTTP health checks (GET /healthz and /readyz) on the host address
+    /// (disabled by default)
+    #[arg(long, group = "health")]
+    health_port: Option<u16>,
+
+    /// Unix socket path for serving plain-HTTP health checks in place of health-port
+    #[arg(long, group = "health")]
+    health_socket: Option<PathBuf>,
+
+    /// only report the proxy as ready on /readyz once a TCP connection to the default upstream
+    /// succeeds
+    #[arg(long, requires = "health")]
+    ready_requires_upstream: bool,
+
+    /// maximum number of concurrently proxied connections
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// how to handle new connections once max-connections has been reached
+    #[arg(long, value_enum, default_value = "wait")]
+    max_connections_behavior: LimitBehavior,
+
+    /// maximum number of new connections accepted from a single client IP per minute, with
+    /// connections beyond the limit dropped before anything is dialed upstream
+    #[arg(long)]
+    connections_per_minute: Option<NonZeroU32>,
+}
+
+/// Protocol spoken between clients and the proxy
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum Mode {
+    /// WebTransport sessions over HTTP/3 and QUIC
+    WebTransport,
+    /// plain TCP connections (no TLS), e.g. for testing with psql
+    Tcp,
+}
+
+/// A new client connection, in whichever protocol the proxy is listening for
+enum Incoming {
+    WebTransport(quinn::Connecting),
+    Tcp(TcpStream),
+}
+
+/// Format of the proxy's logs
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum LogFormat {
+    /// human-readable lines, e.g. for local development
+    Text,
+    /// one JSON object per line (including the fields of every enclosing span), e.g. for log
+    /// aggregators
+    Json,
+}
+
+/// Handling of new connection attempts once the maximum number of connections is reached
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum LimitBehavior {
+    /// wait for an active connection to close before proceeding
+    Wait,
+    /// drop the connection attempt immediately
+    Reject,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // configure logging
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-
     // generate configuration values from arguments
     let configuration = Configuration::parse();
-    let cert = Certificate(std::fs::read(configuration.cert)?);
-    let key = PrivateKey(std::fs::read(configuration.key)?);
-
-    // set up the TLS configuration for the server
-    let mut tls_config = rustls::ServerConfig::builder()
-        .with_safe_default_cipher_suites()
-        .with_safe_default_kx_groups()
-        .with_protocol_versions(&[&rustls::version::TLS13])?
-        .with_no_client_auth()
-        .with_single_cert(vec![cert], key)?;
-
-    // handle ALPN protocols
-    tls_config.max_early_data_size = u32::MAX;
-    let alpn: Vec<Vec<u8>> = vec![
-        b"h3".to_vec(),
-        b"h3-32".to_vec(),
-        b"h3-31".to_vec(),
-        b"h3-30".to_vec(),
-        b"h3-29".to_vec(),
-    ];
-    tls_config.alpn_protocols = alpn;
-
-    // set up the QUIC endpoint listener corresponding to a single UDP socket that may host many connections
-    Endpoint::new(tls_config)
-        .listen(configuration.port)?
-        .for_each(|connection_attempt| async {
-            // spawn a task to handle each QUIC connection attempt
-            tokio::spawn(
-                async move {
-                    let session = Session::start(connection_attempt).await?;
-                    let stream = session.accept_bidirectional().await?;
-                    Proxy::start(stream, configuration.upstream_port).await
+
+    // configure logging (the formatters differ in type, so each builds its own subscriber)
+    let logs = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    match configuration.log_format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().flatten_event(true).with_span_list(true).init(),
+    }
+
+    // serve metrics, if configured
+    if let Some(address) = HttpAddress::new(
+        configuration.host,
+        configuration.metrics_port,
+        configuration.metrics_socket.as_ref(),
+    ) {
+        telemetry::install(address)?;
+    }
+
+    // listen for new connections in the configured mode
+    let address = SocketAddr::new(configuration.host, configuration.port);
+    let mut connection_attempts = match configuration.mode {
+        Mode::WebTransport => listen_web_transport(&configuration, address)?
+            .map(Incoming::WebTransport)
+            .boxed(),
+        Mode::Tcp => endpoint::listen_tcp(address)
+            .await?
+            .map(Incoming::Tcp)
+            .boxed(),
+    };
+
+    // resolve the default upstream and its TLS settings, from a connection string if given
+    let target = match configuration.upstream {
+        Some(target) => target,
+        None => UpstreamTarget {
+            upstream: match configuration.upstream_socket {
+                Some(path) => Upstream::Unix(path),
+                None => Upstream::Tcp {
+                    host: configuration.upstream_host,
+                    port: configuration.upstream_port,
+                },
+            },
+            ssl_mode: if configuration.upstream_tls {
+                SslMode::Require
+            } else {
+                SslMode::Disable
+            },
+            root_cert: configuration.upstream_ca,
+            user: None,
+            dbname: None,
+        },
+    };
+    if target.user.is_some() || target.dbname.is_some() {
+        tracing::warn!(
+            "Ignoring the user and database of the upstream connection string, which come from \
+             each client instead"
+        );
+    }
+
+    // set up the TLS configuration for upstream connections, if required
+    let upstream_tls = if target.ssl_mode != SslMode::Disable {
+        let mut roots = RootCertStore::empty();
+        match &target.root_cert {
+            Some(path) => {
+                for cert in tls::load_certs(path, None)? {
+                    roots.add(&cert)?;
+                }
+            }
+            None => {
+                for cert in rustls_native_certs::load_native_certs()? {
+                    roots.add(&Certificate(cert.to_vec()))?;
                 }
-                .inspect_err(|error| {
-                    tracing::error!(%error, "Stream error");
+            }
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Some(Arc::new(config))
+    } else {
+        None
+    };
+
+    // configure the proxy shared by every connection
+    let mut allowlist =
+        Allowlist::new(configuration.allowed_users, configuration.allowed_databases);
+    if let Some(path) = &configuration.allowlist_file {
+        allowlist.extend_from_file(path)?;
+    }
+    let proxy = Arc::new(
+        Proxy::new(target.upstream)
+            .with_routes(configuration.routes)
+            .with_startup_interception(configuration.intercept_startup)
+            .with_allowlist(allowlist)
+            .with_connect_timeout(
+                Some(configuration.upstream_connect_timeout)
+                    .filter(|seconds| *seconds > 0)
+                    .map(Duration::from_secs),
+            )
+            .with_upstream_keepalive(upstream_keepalive(
+                configuration.upstream_keepalive_time,
+                configuration.upstream_keepalive_interval,
+            ))
+            .with_idle_timeout(configuration.idle_timeout.map(Duration::from_secs))
+            .with_max_session_duration(configuration.max_session_duration.map(Duration::from_secs))
+            .with_debug_dump_bytes(configuration.debug_dump_bytes)
+            .with_upstream_tls(upstream_tls)
+            .with_upstream_tls_fallback(target.ssl_mode == SslMode::Prefer)
+            .with_pool(
+                configuration.pool_size.map(|size| {
+                    Pool::new(size, Duration::from_secs(configuration.pool_idle_timeout))
                 }),
-            );
-        })
-        .await;
+            ),
+    );
+
+    // configure the rules applied to every new session
+    let policy = Arc::new(SessionPolicy {
+        user_header: configuration.user_header,
+        require_user_header: configuration.require_user_header,
+        user_from_client_cert: configuration.user_from_client_cert,
+        max_sessions: configuration.max_sessions,
+        allowed_origins: configuration.allowed_origins,
+        allow_missing_origin: configuration.allow_missing_origin,
+    });
+
+    // cap the number of concurrent connections, if configured
+    let limit = connection_limit(
+        configuration.max_connections,
+        configuration.max_connections_behavior,
+    );
+
+    // throttle new connections from each client IP, if configured
+    let mut rate_limiter = configuration.connections_per_minute.map(RateLimiter::new);
+
+    // serve health checks, if configured
+    let health = Arc::new(Health::new(
+        configuration
+            .ready_requires_upstream
+            .then(|| proxy.upstream().clone()),
+    ));
+    if let Some(address) = HttpAddress::new(
+        configuration.host,
+        configuration.health_port,
+        configuration.health_socket.as_ref(),
+    ) {
+        health::install(address, health.clone()).await?;
+    }
+
+    // accept connections until the stream of attempts ends or a shutdown signal is received
+    health.set_accepting(true);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut tasks = JoinSet::new();
+    let mut totals = ProxyStats::default();
+    loop {
+        tokio::select! {
+            result = &mut shutdown => {
+                result?;
+                tracing::info!("Shutdown signal received, no longer accepting connections");
+                break;
+            }
+            Some(result) = tasks.join_next(), if !tasks.is_empty() => {
+                // reap completed connection tasks, keeping a running tally of proxied bytes
+                if let Ok(Ok(stats)) = result {
+                    totals += stats;
+                }
+            }
+            connection_attempt = connection_attempts.next() => {
+                let Some(connection_attempt) = connection_attempt else {
+                    break;
+                };
+
+                // drop connections from clients that are over their rate limit right away
+                if let Some(rate_limiter) = &mut rate_limiter {
+                    let remote_ip = match &connection_attempt {
+                        Incoming::WebTransport(connecting) => Some(connecting.remote_address().ip()),
+                        Incoming::Tcp(stream) => stream.peer_addr().ok().map(|address| address.ip()),
+                    };
+                    if let Some(remote_ip) = remote_ip.filter(|ip| !rate_limiter.check(*ip)) {
+                        tracing::warn!(%remote_ip, "Connection rate limit exceeded, dropping connection");
+                        metrics::counter!("proxy_connections_throttled_total").increment(1);
+                        continue;
+                    }
+                }
+
+                // spawn a task to handle each connection attempt, tagging every log line of
+                // the connection (down to its upstream connections) with a correlation ID
+                let span = tracing::info_span!("connection", connection_id = %Uuid::new_v4());
+                let connection = match connection_attempt {
+                    Incoming::WebTransport(connecting) => handle_connection(
+                        connecting,
+                        proxy.clone(),
+                        policy.clone(),
+                        limit.clone(),
+                    )
+                    .boxed(),
+                    Incoming::Tcp(stream) => {
+                        handle_tcp_connection(stream, proxy.clone(), limit.clone()).boxed()
+                    }
+                };
+                tasks.spawn(
+                    connection
+                        .inspect_err(|error| {
+                            tracing::error!(%error, "Connection error");
+                        })
+                        .instrument(span),
+                );
+            }
+        }
+    }
+
+    // give in-flight connections a grace period to drain before exiting
+    health.set_accepting(false);
+    tracing::info!(
+        active = tasks.len(),
+        "Waiting for active connections to close"
+    );
+    let grace_period = Duration::from_secs(configuration.shutdown_timeout);
+    let drain = async {
+        while let Some(result) = tasks.join_next().await {
+            if let Ok(Ok(stats)) = result {
+                totals += stats;
+            }
+        }
+    };
+    if tokio::time::timeout(grace_period, drain).await.is_err() {
+        tracing::warn!(
+            active = tasks.len(),
+            "Shutdown timeout elapsed, closing remaining connections"
+        );
+        tasks.shutdown().await;
+    }
+
+    tracing::info!(
+        client_to_upstream = totals.client_to_upstream,
+        upstream_to_client = totals.upstream_to_client,
+        "Total bytes proxied"
+    );
 
     Ok(())
 }
+
+/// Set up the QUIC endpoint listener corresponding to a single UDP socket that may host many
+/// connections, using the TLS and transport settings from the Configuration
+fn listen_web_transport(
+    configuration: &Configuration,
+    address: SocketAddr,
+) -> anyhow::Result<BoxStream<'static, quinn::Connecting>> {
+    let tls_config = TlsConfigBuilder::new(&configuration.cert, &configuration.key)
+        .with_cert_format(configuration.cert_format)
+        .with_alpn_protocols(&configuration.alpn_protocols)
+        .with_early_data(configuration.allow_0rtt)
+        .with_client_auth(
+            configuration.client_ca.clone(),
+            configuration.require_client_auth,
+        )
+        .build()?;
+
+    let endpoint = Endpoint::new(tls_config)
+        .with_timeouts(
+            Duration::from_secs(configuration.keepalive_interval),
+            Duration::from_secs(configuration.max_idle_timeout),
+        )?
+        .with_congestion_control(
+            configuration.congestion_control,
+            configuration.initial_window,
+        )
+        .with_stream_limits(
+            configuration.max_bidi_streams,
+            configuration.max_uni_streams,
+        )?
+        .with_flow_control(
+            configuration.stream_receive_window,
+            configuration.receive_window,
+            configuration.send_window,
+        )?
+        .with_socket_options(SocketOptions {
+            reuse_address: configuration.reuse_address,
+            #[cfg(unix)]
+            reuse_port: configuration.reuse_port,
+            send_buffer_size: configuration.udp_send_buffer,
+            recv_buffer_size: configuration.udp_recv_buffer,
+        });
+
+    #[cfg(unix)]
+    if let Some(fd) = configuration.listen_fd {
+        use std::os::fd::FromRawFd;
+        // SAFETY: the descriptor is owned by this process from here on, having been handed
+        // over by whoever started it (the caller is trusted to pass a descriptor that is open
+        // and used nowhere else)
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+        return Ok(endpoint.listen_on_socket(socket)?.boxed());
+    }
+
+    let ip_stack = configuration
+        .ip_stack
+        .unwrap_or_else(|| configuration.host.into());
+    Ok(endpoint.listen(address, ip_stack)?.boxed())
+}
+
+/// Proxy a single plain TCP connection through to the default upstream service,
+/// without any WebTransport sessions, routes, or user headers
+async fn handle_tcp_connection(
+    stream: TcpStream,
+    proxy: Arc<Proxy>,
+    limit: Option<(Arc<Semaphore>, LimitBehavior)>,
+) -> anyhow::Result<ProxyStats> {
+    // hold a connection permit until the proxy connection finishes
+    let _permit = match limit {
+        Some((semaphore, behavior)) => match acquire_permit(semaphore, behavior).await {
+            Some(permit) => Some(permit),
+            None => {
+                tracing::warn!("Connection limit reached, rejecting connection");
+                return Ok(ProxyStats::default());
+            }
+        },
+        None => None,
+    };
+
+    let _active = ActiveGauge::new("proxy_connections_active");
+    let started = Instant::now();
+    let upstream = proxy.upstream().clone();
+    let remote = stream.peer_addr().ok();
+    let stats = proxy.start(stream, &upstream, None, remote).await?;
+    metrics::histogram!("proxy_connection_duration_seconds").record(started.elapsed());
+    Ok(stats)
+}
+
+/// Proxy a single QUIC connection attempt through to the upstream service.
+///
+/// Each QUIC connection gets its own task, which accepts every session and stream of that
+/// connection in a single loop (since one WebTransportSession drives the whole connection).
+/// Every accepted stream is proxied in its own task on a JoinSet owned by the connection's
+/// task, so that all of a connection's streams are torn down along with the connection.
+async fn handle_connection(
+    connection_attempt: quinn::Connecting,
+    proxy: Arc<Proxy>,
+    policy: Arc<SessionPolicy>,
+    limit: Option<(Arc<Semaphore>, LimitBehavior)>,
+) -> anyhow::Result<ProxyStats> {
+    // hold a connection permit until the proxy connection finishes
+    let _permit = match limit {
+        Some((semaphore, behavior)) => match acquire_permit(semaphore, behavior).await {
+            Some(permit) => Some(permit),
+            None => {
+                tracing::warn!("Connection limit reached, rejecting connection");
+                return Ok(ProxyStats::default());
+            }
+        },
+        None => None,
+    };
+
+    // track the connection for as long as it is being proxied
+    let _active = ActiveGauge::new("proxy_connections_active");
+    let started = Instant::now();
+
+    // reject connections whose first session has nowhere to go before accepting any streams
+    let sessions = Sessions::start(connection_attempt, &policy).await?;
+    let primary = sessions.primary();
+    if proxy.route(primary.path()).is_none() {
+        primary.close(CLOSE_NO_ROUTE, NO_ROUTE_REASON).await?;
+        anyhow::bail!("No upstream route for path {}", primary.path());
+    }
+
+    // proxy every stream of every session to its own upstream connection
+    let streams = sessions.accept_all().fuse();
+    tokio::pin!(streams);
+    let uni_streams = sessions.accept_all_uni().fuse();
+    tokio::pin!(uni_streams);
+    let datagrams = sessions.accept_datagrams().fuse();
+    tokio::pin!(datagrams);
+    let mut proxies = JoinSet::new();
+    let mut totals = ProxyStats::default();
+    loop {
+        tokio::select! {
+            Some((session, stream)) = streams.next() => {
+                let Some(upstream) = proxy.route(session.path()).cloned() else {
+                    tracing::error!(
+                        session_id = ?session.id(),
+                        path = session.path(),
+                        "No upstream route for path"
+                    );
+                    proxies.spawn(async move {
+                        if let Err(error) = session.close(CLOSE_NO_ROUTE, NO_ROUTE_REASON).await {
+                            tracing::debug!(%error, "Failed to close unrouted session");
+                        }
+                        ProxyStats::default()
+                    });
+                    continue;
+                };
+                let proxy = proxy.clone();
+                let span = tracing::info_span!(
+                    "stream",
+                    session_id = ?session.id(),
+                    stream_id = %stream.send_id(),
+                    peer = session.peer_identity().and_then(PeerIdentity::name),
+                );
+                // errors end the stream that ran into them, but never its session or connection
+                proxies.spawn(
+                    async move {
+                        let result = proxy_stream(stream, &session, &proxy, &upstream).await;
+                        if let Err(error) = &result {
+                            tracing::error!(%error, "Stream error");
+                            close_rejected(&session, error).await;
+                        }
+                        result.unwrap_or_default()
+                    }
+                    .instrument(span),
+                );
+            }
+            Some((session, stream)) = uni_streams.next() => {
+                // unrouted sessions are closed by their first bi-directional stream instead
+                let Some(upstream) = proxy.route(session.path()).cloned() else {
+                    tracing::debug!(
+                        session_id = ?session.id(),
+                        stream_id = %stream.recv_id(),
+                        "Stopping unidirectional stream of unrouted session"
+                    );
+                    let mut stream = stream;
+                    stream.stop_sending(STREAM_REJECTED);
+                    continue;
+                };
+                let proxy = proxy.clone();
+                let span = tracing::info_span!(
+                    "uni_stream",
+                    session_id = ?session.id(),
+                    stream_id = %stream.recv_id(),
+                );
+                proxies.spawn(
+                    async move {
+                        if let Err(error) = serve_uni(stream, &session, &proxy, &upstream).await {
+                            tracing::error!(%error, "Stream error");
+                        }
+                        ProxyStats::default()
+                    }
+                    .instrument(span),
+                );
+            }
+            Some((session_id, datagram)) = datagrams.next() => {
+                // datagrams are only used as liveness checks, answering each ping with a pong
+                if datagram.as_ref() == PING {
+                    if let Err(error) = sessions.send_datagram(session_id, Bytes::from_static(PONG)) {
+                        tracing::warn!(%error, "Failed to answer ping datagram");
+                    }
+                }
+            }
+            Some(result) = proxies.join_next() => {
+                match result {
+                    Ok(stats) => totals += stats,
+                    Err(error) => tracing::error!(%error, "Stream task failed"),
+                }
+            }
+            else => break,
+        }
+    }
+
+    metrics::histogram!("proxy_connection_duration_seconds").record(started.elapsed());
+    Ok(totals)
+}
+
+/// Build TCP keepalive settings for upstream connections from the configured number of idle
+/// seconds before probing (0 disabling keepalive) and seconds between probes
+fn upstream_keepalive(time: u64, interval: Option<u64>) -> Option<TcpKeepalive> {
+    if time == 0 {
+        return None;
+    }
+
+    let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(time));
+    Some(match interval {
+        Some(seconds) => keepalive.with_interval(Duration::from_secs(seconds)),
+        None => keepalive,
+    })
+}
+
+/// Proxy a single stream of a Session to its upstream, unless the client negotiated a control
+/// stream (which only ever carries pings, never Postgres messages)
+async fn proxy_stream(
+    stream: Stream,
+    session: &Session,
+    proxy: &Proxy,
+    upstream: &Upstream,
+) -> anyhow::Result<ProxyStats> {
+    match control::negotiate(stream).await? {
+        Negotiated::Control(stream) => {
+            control::serve(stream, proxy, upstream).await?;
+            Ok(ProxyStats::default())
+        }
+        Negotiated::Postgres(stream) => {
+            proxy
+                .start(
+                    stream,
+                    upstream,
+                    session.user(),
+                    Some(session.remote_addr()),
+                )
+                .await
+        }
+    }
+}
+
+/// Serve a unidirectional stream from the client, which can only be a one-way control stream
+/// (since Postgres connections need both directions). The Pongs to its Pings are pushed back
+/// on a unidirectional stream that the proxy opens on the same Session.
+async fn serve_uni(
+    stream: RecvStream,
+    session: &Session,
+    proxy: &Proxy,
+    upstream: &Upstream,
+) -> anyhow::Result<()> {
+    match control::negotiate(stream).await? {
+        Negotiated::Control(stream) => {
+            let pongs = session.open_uni().await?;
+            control::serve(tokio::io::join(stream, pongs), proxy, upstream).await
+        }
+        Negotiated::Postgres(_) => {
+            anyhow::bail!("Unidirectional streams can only carry control frames, not Postgres")
+        }
+    }
+}
+
+/// Close the Session of a stream whose startup message broke the allowlist with a matching
+/// close code. The stream itself has already been sent a FATAL ErrorResponse and finished, so
+/// the primary Session is left open: closing it would close the whole QUIC connection, along
+/// with every other stream and Session on it. Unreachable upstreams only fail their own stream
+/// too, so that the Session's other streams carry on and later ones can retry.
+async fn close_rejected(session: &Session, error: &anyhow::Error) {
+    let Some(rejection @ Rejection::Policy(..)) = error.downcast_ref::<Rejection>() else {
+        return;
+    };
+    if session.is_primary() {
+        return;
+    }
+    if let Err(error) = session
+        .close(CLOSE_POLICY_VIOLATION, rejection.message())
+        .await
+    {
+        tracing::debug!(%error, "Failed to close rejected session");
+    }
+}
+
+/// Build the semaphore that caps concurrent connections (if there is a maximum), along with
+/// the behavior of connections beyond the cap
+fn connection_limit(
+    max_connections: Option<usize>,
+    behavior: LimitBehavior,
+) -> Option<(Arc<Semaphore>, LimitBehavior)> {
+    max_connections.map(|max_connections| (Arc::new(Semaphore::new(max_connections)), behavior))
+}
+
+/// Acquire a connection permit according to the configured limit behavior,
+/// returning None if the connection should be rejected instead
+async fn acquire_permit(
+    semaphore: Arc<Semaphore>,
+    behavior: LimitBehavior,
+) -> Option<OwnedSemaphorePermit> {
+    match behavior {
+        LimitBehavior::Wait => semaphore.acquire_owned().await.ok(),
+        LimitBehavior::Reject => semaphore.try_acquire_owned().ok(),
+    }
+}
+
+/// Resolve once the process has been asked to shut down (SIGINT, or SIGTERM on Unix)
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    /// Parse a command line, along with the arguments that every command line needs
+    fn parse(args: &[&str]) -> Result<Configuration, clap::Error> {
+        let required = ["proxy", "--cert", "localhost.crt", "--key", "localhost.key"];
+        Configuration::try_parse_from(required.iter().chain(args))
+    }
+
+    #[test]
+    fn session_users_require_startup_interception() {
+        let client_auth = ["--client-ca", "ca.crt", "--require-client-auth"];
+        let cert_user = [&client_auth[..], &["--user-from-client-cert"]].concat();
+        for args in [
+            &["--user-header", "x-pg-user"][..],
+            &["--user-header", "x-pg-user", "--require-user-header"],
+            &cert_user,
+        ] {
+            let error = parse(args).unwrap_err();
+            assert_eq!(
+                error.kind(),
+                clap::error::ErrorKind::MissingRequiredArgument
+            );
+            let intercepted = [args, &["--intercept-startup"]].concat();
+            assert!(parse(&intercepted).is_ok(), "{intercepted:?}");
+        }
+    }
+
+    /// Proxy TCP connections to a loopback upstream listener through a proxy whose connection
+    /// limit is set up from the command line, returning a listener for clients alongside it
+    async fn limited(
+        args: &[&str],
+    ) -> (
+        TcpListener,
+        TcpListener,
+        Arc<Proxy>,
+        Option<(Arc<Semaphore>, LimitBehavior)>,
+    ) {
+        let configuration = parse(args).unwrap();
+        let limit = connection_limit(
+            configuration.max_connections,
+            configuration.max_connections_behavior,
+        );
+        let clients = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Arc::new(Proxy::new(Upstream::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: upstream.local_addr().unwrap().port(),
+        }));
+        (clients, upstream, proxy, limit)
+    }
+
+    /// Open a client connection and hand the proxy's end of it to handle_tcp_connection
+    async fn connect(
+        clients: &TcpListener,
+        proxy: &Arc<Proxy>,
+        limit: &Option<(Arc<Semaphore>, LimitBehavior)>,
+    ) -> (TcpStream, JoinHandle<anyhow::Result<ProxyStats>>) {
+        let client = TcpStream::connect(clients.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = clients.accept().await.unwrap();
+        let task = tokio::spawn(handle_tcp_connection(stream, proxy.clone(), limit.clone()));
+        (client, task)
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_limit_are_rejected() {
+        let args = [
+            "--max-connections",
+            "1",
+            "--max-connections-behavior",
+            "reject",
+        ];
+        let (clients, upstream, proxy, limit) = limited(&args).await;
+        let (mut first, first_task) = connect(&clients, &proxy, &limit).await;
+        let (mut first_upstream, _) = upstream.accept().await.unwrap();
+
+        // the second connection is closed without ever reaching the upstream
+        let (mut second, second_task) = connect(&clients, &proxy, &limit).await;
+        let mut byte = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), second.read(&mut byte)).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+        second_task.await.unwrap().unwrap();
+        let accepted = tokio::time::timeout(Duration::from_millis(200), upstream.accept()).await;
+        assert!(
+            accepted.is_err(),
+            "a connection beyond the limit was proxied"
+        );
+
+        // while the first one is still proxied
+        first.write_all(b"x").await.unwrap();
+        first_upstream.read_exact(&mut byte).await.unwrap();
+        assert_eq!(&byte, b"x");
+        drop((first, first_upstream));
+        first_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_limit_wait_for_earlier_ones_to_finish() {
+        let args = [
+            "--max-connections",
+            "1",
+            "--max-connections-behavior",
+            "wait",
+        ];
+        let (clients, upstream, proxy, limit) = limited(&args).await;
+        let (first, first_task) = connect(&clients, &proxy, &limit).await;
+        let (first_upstream, _) = upstream.accept().await.unwrap();
+
+        // the second connection stalls without reaching the upstream
+        let (mut second, second_task) = connect(&clients, &proxy, &limit).await;
+        let accepted = tokio::time::timeout(Duration::from_millis(200), upstream.accept()).await;
+        assert!(
+            accepted.is_err(),
+            "a connection beyond the limit was proxied"
+        );
+        assert!(!second_task.is_finished());
+
+        // until the first one finishes
+        drop((first, first_upstream));
+        first_task.await.unwrap().unwrap();
+        let accepted = tokio::time::timeout(Duration::from_secs(1), upstream.accept()).await;
+        let (mut second_upstream, _) = accepted.unwrap().unwrap();
+        second.write_all(b"x").await.unwrap();
+        let mut byte = [0; 1];
+        second_upstream.read_exact(&mut byte).await.unwrap();
+        assert_eq!(&byte, b"x");
+    }
+}
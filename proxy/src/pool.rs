@@ -0,0 +1,539 @@
+use crate::proxy::{pump, CloseReason, Connection, ProxyStats, Tap, Upstream};
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
+use futures::FutureExt;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::MissedTickBehavior,
+};
+
+/// Shortest time between checks for idle connections that have timed out
+const MIN_REAP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Simple query run against every connection before it is returned to the Pool
+const RESET_QUERY: &str = "DISCARD ALL";
+
+/// Upstream address and startup parameters (sorted by name) identifying which idle connections
+/// a client may borrow
+type PoolKey = (String, Vec<(String, String)>);
+
+/// Pool of idle upstream connections that have already completed the Postgres startup handshake.
+///
+/// Connections are keyed by their upstream and the complete set of startup parameters (user,
+/// database, application_name, and so on), so a borrower only ever receives a connection that was started
+/// exactly as it would have been started for that borrower. Even so, pooling is only correct
+/// when the upstream trusts the proxy itself: the handshake is completed by the proxy, so
+/// clients are never asked to authenticate. Upstreams that request authentication are detected
+/// during the handshake and proxied without pooling instead.
+///
+/// Connections are only returned to the pool when a client ends its session cleanly with a
+/// Terminate message while the upstream is idle (outside of any transaction), after which
+/// RESET_QUERY is run to drop prepared statements, temporary tables, session-level SETs,
+/// LISTEN registrations, and advisory locks. State that DISCARD ALL doesn't cover (such as
+/// state kept by extensions) can still leak between borrowers, and the BackendKeyData replayed
+/// to every borrower identifies the same backend, so a CancelRequest from a former borrower
+/// could cancel a later borrower's query.
+///
+/// Connections that sit idle for longer than the idle timeout are closed by a background task,
+/// which runs for as long as the Pool exists.
+pub struct Pool {
+    idle: Arc<Mutex<HashMap<PoolKey, Vec<Idle>>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+/// Idle upstream connection waiting in the Pool
+struct Idle {
+    connection: Pooled,
+    since: Instant,
+}
+
+/// Upstream connection that has completed the startup handshake on behalf of clients
+pub struct Pooled {
+    key: PoolKey,
+    connection: Box<dyn Connection>,
+    greeting: BytesMut,
+}
+
+/// Result of establishing a new upstream connection for the Pool
+pub enum Established {
+    /// the handshake completed, so the connection can be pooled
+    Pooled(Pooled),
+    /// the upstream asked for authentication (or failed the handshake), so the connection must
+    /// be handed to the client along with every byte that the upstream has sent so far
+    Unpoolable(Box<dyn Connection>, BytesMut),
+}
+
+impl Pool {
+    /// Create a new Pool, holding at most max_idle connections for each set of startup
+    /// parameters and closing connections that sit idle for longer than idle_timeout
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        let idle = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(reap(Arc::downgrade(&idle), idle_timeout));
+        Self {
+            idle,
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    /// Borrow an idle connection to an upstream started with these parameters, if there is one
+    pub fn checkout(&self, upstream: &Upstream, parameters: &[(String, String)]) -> Option<Pooled> {
+        let key = key(upstream, parameters);
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+        let connections = idle.get_mut(&key)?;
+        let mut checked_out = None;
+        while let Some(mut candidate) = connections.pop() {
+            if candidate.since.elapsed() > self.idle_timeout {
+                tracing::debug!("Evicting idle pooled connection");
+                continue;
+            }
+
+            // upstreams may close idle connections at any time, so skip any connection with
+            // data (or EOF) already waiting to be read
+            let mut probe = [0; 1];
+            if candidate
+                .connection
+                .connection
+                .read(&mut probe)
+                .now_or_never()
+                .is_some()
+            {
+                tracing::debug!("Evicting closed pooled connection");
+                continue;
+            }
+
+            checked_out = Some(candidate.connection);
+            break;
+        }
+        if connections.is_empty() {
+            idle.remove(&key);
+        }
+
+        metrics::counter!(
+            "proxy_pool_checkouts_total",
+            "outcome" => if checked_out.is_some() { "hit" } else { "miss" }
+        )
+        .increment(1);
+        checked_out
+    }
+
+    /// Return a connection to the Pool, closing it instead if the pool is already full
+    fn checkin(&self, connection: Pooled) {
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+        let connections = idle.entry(connection.key.clone()).or_default();
+        connections.retain(|candidate| candidate.since.elapsed() <= self.idle_timeout);
+        if connections.len() < self.max_idle {
+            tracing::debug!("Returning connection to pool");
+            connections.push(Idle {
+                connection,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Complete the startup handshake on a new upstream connection, using an encoded
+    /// StartupMessage built from the given parameters
+    pub async fn establish(
+        upstream: &Upstream,
+        parameters: &[(String, String)],
+        mut connection: Box<dyn Connection>,
+        startup: &[u8],
+    ) -> anyhow::Result<Established> {
+        connection
+            .write_all(startup)
+            .await
+            .context("Failed to forward startup message to upstream")?;
+
+        // collect everything up to the first ReadyForQuery for replaying to every borrower
+        let mut greeting = BytesMut::new();
+        loop {
+            let (tag, body) = read_message(&mut connection, &mut greeting).await?;
+            match tag {
+                // AuthenticationOk is the only authentication message that needs no response
+                b'R' if body != 0i32.to_be_bytes() => {
+                    return Ok(Established::Unpoolable(connection, greeting))
+                }
+                b'E' => return Ok(Established::Unpoolable(connection, greeting)),
+                b'Z' => break,
+                _ => {}
+            }
+        }
+
+        Ok(Established::Pooled(Pooled {
+            key: key(upstream, parameters),
+            connection,
+            greeting,
+        }))
+    }
+
+    /// Proxy a client stream over a pooled connection, replaying the connection's startup
+    /// response first, then returning the connection to the pool if the client terminates its
+    /// session cleanly
+    pub async fn start<S>(
+        &self,
+        client: &mut S,
+        mut pooled: Pooled,
+        idle_timeout: Option<Duration>,
+    ) -> anyhow::Result<ProxyStats>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        client
+            .write_all(&pooled.greeting)
+            .await
+            .context("Failed to replay startup response to client")?;
+        client.flush().await.context("Failed to flush client")?;
+
+        let tracker = Tracker::new();
+        let mut stats = pump(client, &mut pooled.connection, idle_timeout, &tracker).await?;
+        stats.upstream_to_client += pooled.greeting.len() as u64;
+
+        // an upstream that closed first can't be reused, even if the client terminated later
+        let reusable = tracker.reusable() && stats.close_reason == Some(CloseReason::ClientEof);
+
+        if reusable {
+            match reset(&mut pooled.connection).await {
+                Ok(()) => self.checkin(pooled),
+                Err(error) => tracing::warn!(%error, "Failed to reset pooled connection"),
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+/// Close the idle connections of a Pool once they time out, checking twice per idle timeout
+/// until the Pool is dropped
+async fn reap(idle: Weak<Mutex<HashMap<PoolKey, Vec<Idle>>>>, idle_timeout: Duration) {
+    let mut interval = tokio::time::interval((idle_timeout / 2).max(MIN_REAP_INTERVAL));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(idle) = idle.upgrade() else {
+            return;
+        };
+        let mut idle = idle.lock().expect("Pool lock poisoned");
+        let mut evicted = 0;
+        idle.retain(|_, connections| {
+            let before = connections.len();
+            connections.retain(|candidate| candidate.since.elapsed() <= idle_timeout);
+            evicted += before - connections.len();
+            !connections.is_empty()
+        });
+        if evicted > 0 {
+            tracing::debug!(evicted, "Closed idle pooled connections");
+        }
+    }
+}
+
+/// Combine an upstream and its (sorted) startup parameters into a PoolKey
+fn key(upstream: &Upstream, parameters: &[(String, String)]) -> PoolKey {
+    let mut parameters = parameters.to_vec();
+    parameters.sort();
+    (upstream.to_string(), parameters)
+}
+
+/// Read a single backend message, appending the raw message to a buffer and returning its tag
+/// and body
+async fn read_message<U>(upstream: &mut U, buffer: &mut BytesMut) -> anyhow::Result<(u8, Vec<u8>)>
+where
+    U: AsyncRead + Unpin,
+{
+    let tag = upstream
+        .read_u8()
+        .await
+        .context("Failed to read message from upstream")?;
+    let length = upstream
+        .read_i32()
+        .await
+        .context("Failed to read message length from upstream")?;
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|length| *length >= 4)
+        .ok_or_else(|| anyhow::anyhow!("Invalid upstream message length {length}"))?;
+    let mut body = vec![0; length - 4];
+    upstream
+        .read_exact(&mut body)
+        .await
+        .context("Failed to read message body from upstream")?;
+
+    buffer.put_u8(tag);
+    buffer.put_i32(length as i32);
+    buffer.put_slice(&body);
+    Ok((tag, body))
+}
+
+/// Run RESET_QUERY on an idle connection, consuming every response up to ReadyForQuery
+async fn reset<U>(upstream: &mut U) -> anyhow::Result<()>
+where
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut query = BytesMut::new();
+    postgres_protocol::message::frontend::query(RESET_QUERY, &mut query)
+        .context("Failed to encode reset query")?;
+    upstream
+        .write_all(&query)
+        .await
+        .context("Failed to send reset query")?;
+    upstream.flush().await.context("Failed to flush upstream")?;
+
+    let mut responses = BytesMut::new();
+    loop {
+        let (tag, body) = read_message(upstream, &mut responses).await?;
+        match tag {
+            b'E' => anyhow::bail!("Reset query failed"),
+            b'Z' => {
+                anyhow::ensure!(body == b"I", "Upstream not idle after reset query");
+                return Ok(());
+            }
+            _ => responses.clear(),
+        }
+    }
+}
+
+/// Tracker for the boundaries of Postgres messages in a byte stream that is read in arbitrary
+/// chunks, yielding the header of every message in turn
+#[derive(Default)]
+struct Framer {
+    header: Vec<u8>,
+    remaining: usize,
+}
+
+/// Header of a message spotted by a Framer, with offsets into the chunk that completed it
+struct Header {
+    /// message type tag
+    tag: u8,
+    /// offset of the tag, or None if the header started in an earlier chunk
+    start: Option<usize>,
+    /// offset of the first byte of the body, which may be past the end of the chunk
+    body: usize,
+}
+
+impl Framer {
+    /// Advance over a chunk of bytes, collecting every message header completed within it
+    fn advance(&mut self, chunk: &[u8], headers: &mut Vec<Header>) {
+        let mut position = 0;
+        while position < chunk.len() {
+            if self.remaining > 0 {
+                let skip = self.remaining.min(chunk.len() - position);
+                self.remaining -= skip;
+                position += skip;
+                continue;
+            }
+
+            self.header.push(chunk[position]);
+            position += 1;
+            if self.header.len() == 5 {
+                let length = i32::from_be_bytes([
+                    self.header[1],
+                    self.header[2],
+                    self.header[3],
+                    self.header[4],
+                ]);
+                headers.push(Header {
+                    tag: self.header[0],
+                    start: position.checked_sub(5),
+                    body: position,
+                });
+                self.remaining = usize::try_from(length).unwrap_or(4).saturating_sub(4);
+                self.header.clear();
+            }
+        }
+    }
+}
+
+/// Tap tracking the Postgres messages that a pooled connection carries in each direction. A
+/// client Terminate is never forwarded; instead, the pump stops reading from the client and
+/// carries on draining the upstream until every outstanding query has finished.
+struct Tracker {
+    tracked: Mutex<Tracked>,
+}
+
+/// Progress of the messages seen by a Tracker
+struct Tracked {
+    client_framer: Framer,
+    upstream_framer: Framer,
+    headers: Vec<Header>,
+    /// queries (or syncs) sent upstream that have yet to see their ReadyForQuery
+    outstanding: usize,
+    /// status byte of the last ReadyForQuery
+    status: u8,
+    /// whether the status byte is at the start of the next chunk, since the ReadyForQuery
+    /// straddles two reads
+    status_pending: bool,
+    terminated: bool,
+    /// whether the Terminate was held back in full, so the upstream session is still open
+    clean: bool,
+}
+
+impl Tracker {
+    fn new() -> Self {
+        Self {
+            tracked: Mutex::new(Tracked {
+                client_framer: Framer::default(),
+                upstream_framer: Framer::default(),
+                headers: Vec::new(),
+                outstanding: 0,
+                status: b'I',
+                status_pending: false,
+                terminated: false,
+                clean: true,
+            }),
+        }
+    }
+
+    /// Check if the client terminated its session cleanly, leaving the upstream idle outside
+    /// of a transaction
+    fn reusable(&self) -> bool {
+        let tracked = self.tracked.lock().expect("Tracker lock poisoned");
+        tracked.terminated && tracked.clean && tracked.outstanding == 0 && tracked.status == b'I'
+    }
+}
+
+impl Tap for Tracker {
+    fn client_chunk(&self, chunk: &[u8]) -> (usize, bool) {
+        let mut tracked = self.tracked.lock().expect("Tracker lock poisoned");
+        let Tracked {
+            client_framer,
+            headers,
+            ..
+        } = &mut *tracked;
+        client_framer.advance(chunk, headers);
+        let mut forward = (chunk.len(), false);
+        for header in std::mem::take(&mut tracked.headers) {
+            match (header.tag, header.start) {
+                (b'Q' | b'S' | b'F', _) => tracked.outstanding += 1,
+                (b'X', Some(start)) => {
+                    forward = (start, true);
+                    tracked.terminated = true;
+                    break;
+                }
+                // part of the Terminate was already forwarded with the previous chunk, so the
+                // upstream session will end regardless
+                (b'X', None) => {
+                    forward = (0, true);
+                    tracked.terminated = true;
+                    tracked.clean = false;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        forward
+    }
+
+    fn upstream_chunk(&self, chunk: &[u8]) {
+        let mut tracked = self.tracked.lock().expect("Tracker lock poisoned");
+        if tracked.status_pending {
+            tracked.status = chunk[0];
+            tracked.status_pending = false;
+        }
+        let Tracked {
+            upstream_framer,
+            headers,
+            ..
+        } = &mut *tracked;
+        upstream_framer.advance(chunk, headers);
+        for header in std::mem::take(&mut tracked.headers) {
+            if header.tag == b'Z' {
+                tracked.outstanding = tracked.outstanding.saturating_sub(1);
+                match chunk.get(header.body) {
+                    Some(byte) => tracked.status = *byte,
+                    None => tracked.status_pending = true,
+                }
+            }
+        }
+    }
+
+    fn finished(&self) -> bool {
+        let tracked = self.tracked.lock().expect("Tracker lock poisoned");
+        tracked.terminated && tracked.outstanding == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    /// Upstream that every test connection is keyed on
+    fn upstream() -> Upstream {
+        Upstream::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: 5432,
+        }
+    }
+
+    /// Create a Pooled connection over an in-memory pipe, along with the upstream's end of it
+    fn pooled() -> (Pooled, DuplexStream) {
+        let (connection, peer) = duplex(1024);
+        let pooled = Pooled {
+            key: key(&upstream(), &[]),
+            connection: Box::new(connection),
+            greeting: BytesMut::new(),
+        };
+        (pooled, peer)
+    }
+
+    /// Read a single frontend message, returning its tag and body
+    async fn read_frontend(stream: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let tag = stream.read_u8().await.unwrap();
+        let length = stream.read_i32().await.unwrap();
+        let mut body = vec![0; length as usize - 4];
+        stream.read_exact(&mut body).await.unwrap();
+        (tag, body)
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed_once_they_time_out() {
+        let pool = Pool::new(1, Duration::from_millis(100));
+        let (pooled, mut peer) = pooled();
+        pool.checkin(pooled);
+
+        // the reaper drops the connection without waiting for the next checkout
+        let mut buffer = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), peer.read(&mut buffer)).await;
+        assert_eq!(read.expect("connection was never closed").unwrap(), 0);
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn terminated_sessions_return_their_connection_to_the_pool() {
+        let pool = Pool::new(1, Duration::from_secs(60));
+        let (pooled, mut upstream_peer) = pooled();
+        let (mut client, mut client_peer) = duplex(1024);
+
+        let mut query = BytesMut::new();
+        postgres_protocol::message::frontend::query("SELECT 1", &mut query).unwrap();
+        let mut terminate = BytesMut::new();
+        postgres_protocol::message::frontend::terminate(&mut terminate);
+        let ready = b"Z\0\0\0\x05I";
+
+        let peers = async {
+            client_peer.write_all(&query).await.unwrap();
+            assert_eq!(read_frontend(&mut upstream_peer).await.0, b'Q');
+            upstream_peer.write_all(ready).await.unwrap();
+            let mut response = [0; 6];
+            client_peer.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, ready);
+
+            // the Terminate is held back, and the connection is reset instead
+            client_peer.write_all(&terminate).await.unwrap();
+            let (tag, body) = read_frontend(&mut upstream_peer).await;
+            assert_eq!(tag, b'Q');
+            assert_eq!(body, b"DISCARD ALL\0");
+            upstream_peer.write_all(ready).await.unwrap();
+        };
+        let (stats, ()) = tokio::join!(pool.start(&mut client, pooled, None), peers);
+        let stats = stats.unwrap();
+        assert_eq!(stats.client_to_upstream, query.len() as u64);
+        assert_eq!(stats.close_reason, Some(CloseReason::ClientEof));
+        assert!(pool.checkout(&upstream(), &[]).is_some());
+    }
+}
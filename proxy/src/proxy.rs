@@ -1,33 +1,1689 @@
-use crate::session::Stream;
+use crate::{
+    allowlist::Allowlist,
+    dump::ByteDump,
+    pool::{Established, Pool},
+    startup::{error_response, StartupMessage, SSL_REQUEST_CODE},
+    telemetry::{self, ActiveGauge},
+};
 use anyhow::Context;
-use std::net::SocketAddrV4;
-use tokio::net::TcpStream;
+use rustls::{ClientConfig, ServerName};
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    ops::AddAssign,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
 
-/// Bi-directional proxy between a WebTransport Stream and a TCP connection
-pub struct Proxy;
+/// Size of the buffers used for copying data in each direction
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Time that the result of probing an upstream is reused for, so that Pings sent in a tight
+/// loop don't open a fresh upstream connection each
+const PROBE_TTL: Duration = Duration::from_secs(1);
+
+/// SQLSTATE for clients rejected before authentication (invalid_authorization_specification)
+const INVALID_AUTHORIZATION_SPECIFICATION: &str = "28000";
+
+/// SQLSTATE for clients whose upstream connection couldn't be established (connection_failure)
+const CONNECTION_FAILURE: &str = "08006";
+
+/// Bi-directional proxy between a client stream (e.g. a WebTransport Stream) and a TCP connection
+pub struct Proxy {
+    upstream: Upstream,
+    routes: HashMap<String, Upstream>,
+    connect_timeout: Option<Duration>,
+    upstream_keepalive: Option<TcpKeepalive>,
+    idle_timeout: Option<Duration>,
+    max_session_duration: Option<Duration>,
+    debug_dump_bytes: usize,
+    upstream_tls: Option<TlsConnector>,
+    upstream_tls_fallback: bool,
+    intercept_startup: bool,
+    allowlist: Allowlist,
+    pool: Option<Pool>,
+    probes: Mutex<HashMap<Upstream, Probe>>,
+}
+
+/// Outcome of the latest probe of an upstream, reused by Proxy::probe until PROBE_TTL passes
+struct Probe {
+    at: Instant,
+    error: Option<String>,
+}
+
+/// Upstream connection of any transport, e.g. plain TCP, TLS, or a Unix socket
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Byte counts transferred over the lifetime of a single proxy connection
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProxyStats {
+    /// bytes copied from the client to the upstream TCP service
+    pub client_to_upstream: u64,
+    /// bytes copied from the upstream TCP service back to the client
+    pub upstream_to_client: u64,
+    /// side that closed the connection first (unset for totals over several connections)
+    pub close_reason: Option<CloseReason>,
+}
+
+/// Reason that a proxy connection came to an end
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// the client closed its side first (or sent a Terminate)
+    ClientEof,
+    /// the upstream closed its side first
+    UpstreamEof,
+    /// no data flowed in either direction for longer than the idle timeout
+    IdleTimeout,
+    /// the connection was open for longer than the max session duration
+    MaxSessionDuration,
+    /// the connection failed with any other error
+    Error,
+}
+
+impl CloseReason {
+    /// Name of the close reason for log fields
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ClientEof => "client_eof",
+            Self::UpstreamEof => "upstream_eof",
+            Self::IdleTimeout => "idle_timeout",
+            Self::MaxSessionDuration => "max_session_duration",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Error for proxy connections closed by one of the proxy's own time limits, which can be told
+/// apart from other errors (by downcasting) when reporting why a connection closed
+#[derive(Debug)]
+pub enum Timeout {
+    /// the upstream connection wasn't established within this long
+    Connect(Duration),
+    /// no data flowed in either direction for this long
+    Idle(Duration),
+    /// the connection was open for this long
+    MaxSessionDuration(Duration),
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect(duration) => {
+                write!(
+                    formatter,
+                    "Timed out connecting to upstream after {duration:?}"
+                )
+            }
+            Self::Idle(duration) => {
+                write!(
+                    formatter,
+                    "Proxy connection idle for longer than {duration:?}"
+                )
+            }
+            Self::MaxSessionDuration(duration) => write!(
+                formatter,
+                "Max session duration of {duration:?} exceeded, closing proxy connection"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Error for clients that the proxy turned away with a FATAL ErrorResponse instead of
+/// proxying them, which can be told apart from other errors (by downcasting) so that
+/// transports with richer close semantics can pass the reason on
+#[derive(Debug)]
+pub enum Rejection {
+    /// the startup message asked for a user or database outside of the Allowlist
+    Policy(String),
+    /// the upstream couldn't be reached
+    UpstreamUnreachable(anyhow::Error),
+}
+
+impl Rejection {
+    /// SQLSTATE of the ErrorResponse sent to the client
+    fn sqlstate(&self) -> &'static str {
+        match self {
+            Self::Policy(..) => INVALID_AUTHORIZATION_SPECIFICATION,
+            Self::UpstreamUnreachable(..) => CONNECTION_FAILURE,
+        }
+    }
+
+    /// Message of the ErrorResponse sent to the client
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Policy(message) => message,
+            Self::UpstreamUnreachable(error) if error.is::<Timeout>() => {
+                "timed out connecting to the upstream database server"
+            }
+            Self::UpstreamUnreachable(..) => "could not connect to the upstream database server",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Policy(message) => write!(formatter, "Rejected startup message: {message}"),
+            Self::UpstreamUnreachable(error) => {
+                write!(
+                    formatter,
+                    "Rejected client, upstream unreachable: {error:#}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Summary of a single completed proxy session, emitted as a structured event for analytics
+#[derive(Clone, Copy, Debug)]
+pub struct SessionOutcome {
+    /// wall-clock time that the session started
+    pub started_at: SystemTime,
+    /// wall-clock time that the session ended
+    pub ended_at: SystemTime,
+    /// how long the session was open
+    pub duration: Duration,
+    /// bytes proxied in each direction (only known for sessions that closed without an error)
+    pub stats: ProxyStats,
+    /// why the session ended
+    pub close_reason: CloseReason,
+}
+
+impl SessionOutcome {
+    /// Log this outcome at info level, with every detail as a field of the event
+    pub fn emit(&self) {
+        let unix_millis = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64)
+        };
+        tracing::info!(
+            started_at_ms = unix_millis(self.started_at),
+            ended_at_ms = unix_millis(self.ended_at),
+            duration_ms = self.duration.as_millis() as u64,
+            client_to_upstream = self.stats.client_to_upstream,
+            upstream_to_client = self.stats.upstream_to_client,
+            close_reason = self.close_reason.as_str(),
+            "Proxy session outcome"
+        );
+    }
+}
+
+/// Address of an upstream service
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Upstream {
+    /// service listening on a TCP port
+    Tcp {
+        /// IP address or DNS name of the service
+        host: String,
+        /// port of the service
+        port: u16,
+    },
+    /// service listening on a Unix-domain socket, e.g. /var/run/postgresql/.s.PGSQL.5432
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp { host, port } if host.contains(':') => {
+                write!(formatter, "[{host}]:{port}")
+            }
+            Self::Tcp { host, port } => write!(formatter, "{host}:{port}"),
+            Self::Unix(path) => write!(formatter, "{}", path.display()),
+        }
+    }
+}
+
+impl Upstream {
+    /// Check that the upstream accepts connections, without negotiating TLS or starting up
+    pub async fn probe(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Tcp { host, port } => {
+                TcpStream::connect((host.as_str(), *port))
+                    .await
+                    .context("Failed to connect to upstream TCP target")?;
+            }
+            Self::Unix(path) => {
+                connect_unix(path, false).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse upstreams from HOST:PORT strings (with IPv6 hosts wrapped in brackets),
+/// or from absolute Unix socket paths
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.starts_with('/') {
+            return Ok(Self::Unix(PathBuf::from(value)));
+        }
+
+        let (host, port) = value
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Upstream must be formatted as HOST:PORT or /PATH"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        anyhow::ensure!(!host.is_empty(), "Upstream host cannot be empty");
+        Ok(Self::Tcp {
+            host: host.to_string(),
+            port: port.parse().context("Invalid upstream port")?,
+        })
+    }
+}
+
+/// Mapping from a WebTransport CONNECT path to the Upstream serving that path
+#[derive(Clone, Debug)]
+pub struct Route {
+    /// path of the CONNECT request, e.g. /db/primary
+    pub path: String,
+    /// upstream that sessions on this path are proxied to
+    pub upstream: Upstream,
+}
+
+/// Parse routes from PATH=HOST:PORT (or PATH=/SOCKET) strings
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (path, upstream) = value
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Route must be formatted as PATH=UPSTREAM"))?;
+        anyhow::ensure!(path.starts_with('/'), "Route path must start with '/'");
+        Ok(Self {
+            path: path.to_string(),
+            upstream: upstream.parse()?,
+        })
+    }
+}
+
+/// Default upstream along with the TLS settings for connecting to it
+#[derive(Clone, Debug)]
+pub struct UpstreamTarget {
+    /// address of the upstream service
+    pub upstream: Upstream,
+    /// whether connections to the upstream use TLS
+    pub ssl_mode: SslMode,
+    /// root certificate for verifying the upstream server, in place of the native roots
+    pub root_cert: Option<PathBuf>,
+    /// user named by the connection string, which is ignored in favor of each client's own
+    pub user: Option<String>,
+    /// database named by the connection string, which is ignored in favor of each client's own
+    pub dbname: Option<String>,
+}
+
+/// Subset of libpq's sslmode settings that the proxy supports for upstream connections
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SslMode {
+    /// never use TLS
+    Disable,
+    /// use TLS if the upstream offers it, and fall back to an unencrypted connection if not
+    Prefer,
+    /// always use TLS, failing connections to upstreams that refuse it
+    Require,
+}
+
+/// Parse upstream targets from libpq connection strings, either URIs formatted as
+/// postgres://[USER[:PASSWORD]@]HOST[:PORT][/DBNAME][?PARAMETER=VALUE&...] (with
+/// percent-encoded parts) or space-separated PARAMETER=VALUE pairs (with values optionally
+/// single-quoted), or from absolute paths to either a Unix socket or the directory holding it
+/// (e.g. /var/run/postgresql). Upstream certificates are always verified whenever TLS is used,
+/// so sslmode=require behaves like verify-full.
+impl FromStr for UpstreamTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.starts_with('/') {
+            let mut path = PathBuf::from(value);
+            let is_socket = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(".s.PGSQL."));
+            if !is_socket {
+                path.push(".s.PGSQL.5432");
+            }
+            return Ok(Self {
+                upstream: Upstream::Unix(path),
+                ssl_mode: SslMode::Disable,
+                root_cert: None,
+                user: None,
+                dbname: None,
+            });
+        }
+
+        let parameters = match value
+            .strip_prefix("postgres://")
+            .or_else(|| value.strip_prefix("postgresql://"))
+        {
+            Some(uri) => uri_parameters(uri)?,
+            None if value.contains('=') => key_value_parameters(value)?,
+            None => anyhow::bail!(
+                "Upstream must be a postgres:// URI, KEY=VALUE pairs, or an absolute socket path"
+            ),
+        };
+
+        let mut host = None;
+        let mut port = 5432;
+        let mut target = Self {
+            upstream: Upstream::Unix(PathBuf::new()),
+            ssl_mode: SslMode::Disable,
+            root_cert: None,
+            user: None,
+            dbname: None,
+        };
+        for (key, value) in parameters {
+            match (key.as_str(), value.as_str()) {
+                ("host", "") => (),
+                ("host", value) => host = Some(value.to_string()),
+                ("port", "") => (),
+                ("port", value) => {
+                    port = value
+                        .parse()
+                        .with_context(|| format!("Invalid upstream port {value}"))?
+                }
+                ("sslmode", "disable") => target.ssl_mode = SslMode::Disable,
+                ("sslmode", "prefer") => target.ssl_mode = SslMode::Prefer,
+                ("sslmode", "require" | "verify-ca" | "verify-full") => {
+                    target.ssl_mode = SslMode::Require
+                }
+                ("sslmode", mode) => anyhow::bail!(
+                    "Unsupported sslmode {mode}: use disable, prefer, require, verify-ca, or \
+                     verify-full"
+                ),
+                ("sslrootcert", path) => target.root_cert = Some(PathBuf::from(path)),
+                // credentials and databases come from each client
+                ("user", user) => target.user = Some(user.to_string()),
+                ("dbname", dbname) => target.dbname = Some(dbname.to_string()),
+                ("password", _) => (),
+                (key, _) => anyhow::bail!("Unsupported upstream connection parameter {key}"),
+            }
+        }
+
+        let host = host.context("Upstream connection string must name a host")?;
+        anyhow::ensure!(
+            !host.contains(','),
+            "Upstream connection string can only name a single host"
+        );
+        target.upstream = if host.starts_with('/') {
+            Upstream::Unix(Path::new(&host).join(format!(".s.PGSQL.{port}")))
+        } else {
+            Upstream::Tcp { host, port }
+        };
+        anyhow::ensure!(
+            target.ssl_mode != SslMode::Disable || target.root_cert.is_none(),
+            "sslrootcert requires an sslmode other than disable"
+        );
+        Ok(target)
+    }
+}
+
+/// Split the part of a connection URI after its scheme into connection parameters, decoding
+/// the percent-encoded user, password, host, port, and database along with every query
+/// parameter
+fn uri_parameters(uri: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let (uri, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let (authority, dbname) = uri.split_once('/').unwrap_or((uri, ""));
+    let (userinfo, hostport) = match authority.rsplit_once('@') {
+        Some((userinfo, hostport)) => (Some(userinfo), hostport),
+        None => (None, authority),
+    };
+
+    // IPv6 hosts are wrapped in brackets, since they contain colons themselves
+    let (host, port) = match hostport.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .context("Upstream URI has an unterminated IPv6 host")?;
+            let port = match rest {
+                "" => "",
+                rest => rest
+                    .strip_prefix(':')
+                    .context("Upstream URI has trailing characters after its IPv6 host")?,
+            };
+            (host, port)
+        }
+        None => hostport.split_once(':').unwrap_or((hostport, "")),
+    };
+
+    let mut parameters = vec![
+        ("host".to_string(), percent_decode(host)?),
+        ("port".to_string(), percent_decode(port)?),
+    ];
+    if let Some(userinfo) = userinfo {
+        let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+        parameters.push(("user".to_string(), percent_decode(user)?));
+        parameters.push(("password".to_string(), percent_decode(password)?));
+    }
+    if !dbname.is_empty() {
+        parameters.push(("dbname".to_string(), percent_decode(dbname)?));
+    }
+    for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+        let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+        parameters.push((percent_decode(key)?, percent_decode(value)?));
+    }
+    Ok(parameters)
+}
+
+/// Split a connection string of space-separated KEY=VALUE pairs into connection parameters.
+/// Like libpq, spaces are allowed around the equals sign, and values containing spaces (or
+/// nothing at all) are single-quoted, with backslashes escaping quotes and backslashes.
+fn key_value_parameters(value: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut parameters = Vec::new();
+    let mut characters = value.chars().peekable();
+    loop {
+        while characters.next_if(|c| c.is_whitespace()).is_some() {}
+        if characters.peek().is_none() {
+            return Ok(parameters);
+        }
+
+        let mut key = String::new();
+        while let Some(c) = characters.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        while characters.next_if(|c| c.is_whitespace()).is_some() {}
+        anyhow::ensure!(
+            characters.next() == Some('='),
+            "Upstream connection parameter {key} is missing a value"
+        );
+        while characters.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if characters.next_if_eq(&'\'').is_some() {
+            loop {
+                match characters.next() {
+                    Some('\'') => break,
+                    Some('\\') => value.extend(characters.next()),
+                    Some(c) => value.push(c),
+                    None => anyhow::bail!(
+                        "Upstream connection parameter {key} has an unterminated quoted value"
+                    ),
+                }
+            }
+        } else {
+            while let Some(c) = characters.next_if(|c| !c.is_whitespace()) {
+                match c {
+                    '\\' => value.extend(characters.next()),
+                    c => value.push(c),
+                }
+            }
+        }
+        parameters.push((key, value));
+    }
+}
+
+/// Decode a percent-encoded part of a connection URI
+fn percent_decode(value: &str) -> anyhow::Result<String> {
+    let decoded = percent_encoding::percent_decode_str(value)
+        .decode_utf8()
+        .with_context(|| format!("Upstream URI part {value} is not valid UTF-8"))?;
+    Ok(decoded.into_owned())
+}
+
+impl AddAssign for ProxyStats {
+    fn add_assign(&mut self, other: Self) {
+        self.client_to_upstream += other.client_to_upstream;
+        self.upstream_to_client += other.upstream_to_client;
+    }
+}
 
 impl Proxy {
-    /// Start consuming a Stream, copying both the read and write half of the stream to a TCP
-    /// connection until either side disconnects or emits an error.
-    #[tracing::instrument(skip(stream), err)]
-    pub async fn start(mut stream: Stream, upstream_port: u16) -> anyhow::Result<()> {
+    /// Create a new Proxy targeting a default upstream TCP service
+    pub fn new(upstream: Upstream) -> Self {
+        Self {
+            upstream,
+            routes: HashMap::new(),
+            connect_timeout: None,
+            upstream_keepalive: None,
+            idle_timeout: None,
+            max_session_duration: None,
+            debug_dump_bytes: 0,
+            upstream_tls: None,
+            upstream_tls_fallback: false,
+            intercept_startup: false,
+            allowlist: Allowlist::default(),
+            pool: None,
+            probes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Negotiate TLS with the upstream Postgres server using the provided client configuration
+    pub fn with_upstream_tls(mut self, config: Option<Arc<ClientConfig>>) -> Self {
+        self.upstream_tls = config.map(TlsConnector::from);
+        self
+    }
+
+    /// Fall back to unencrypted upstream connections when the upstream refuses TLS (or can't
+    /// offer it, over a Unix socket), rather than failing them (i.e. sslmode=prefer)
+    pub fn with_upstream_tls_fallback(mut self, fallback: bool) -> Self {
+        self.upstream_tls_fallback = fallback;
+        self
+    }
+
+    /// Route sessions to upstreams based on the path of their CONNECT request. Once any routes
+    /// are configured, sessions on unknown paths are no longer sent to the default upstream.
+    pub fn with_routes(mut self, routes: impl IntoIterator<Item = Route>) -> Self {
+        self.routes = routes
+            .into_iter()
+            .map(|route| (route.path, route.upstream))
+            .collect();
+        self
+    }
+
+    /// The default upstream, used by sessions when no routes are configured
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    /// Find the upstream for a session's CONNECT path, if that path is allowed
+    pub fn route(&self, path: &str) -> Option<&Upstream> {
+        if self.routes.is_empty() {
+            Some(&self.upstream)
+        } else {
+            self.routes.get(path)
+        }
+    }
+
+    /// Give up on connecting to an upstream (including any TLS negotiation) after the given
+    /// duration, rather than waiting on the OS, which can take minutes for unreachable hosts
+    pub fn with_connect_timeout(mut self, connect_timeout: Option<Duration>) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Enable TCP keepalive on upstream TCP connections, so that upstreams that vanish without
+    /// closing their connections (e.g. behind a dropped NAT mapping) are noticed
+    pub fn with_upstream_keepalive(mut self, keepalive: Option<TcpKeepalive>) -> Self {
+        self.upstream_keepalive = keepalive;
+        self
+    }
+
+    /// Close connections when no data flows in either direction for the given duration
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Close connections once they have been open for the given duration, however busy they
+    /// are (unlike the idle timeout, which only closes connections that stop transferring data)
+    pub fn with_max_session_duration(mut self, max_session_duration: Option<Duration>) -> Self {
+        self.max_session_duration = max_session_duration;
+        self
+    }
+
+    /// Log the first bytes sent in each direction of every stream as hex at trace level (with
+    /// everything after the startup messages redacted), or nothing at all if the limit is 0
+    pub fn with_debug_dump_bytes(mut self, debug_dump_bytes: usize) -> Self {
+        self.debug_dump_bytes = debug_dump_bytes;
+        self
+    }
+
+    /// Parse the startup message of every stream before forwarding it upstream,
+    /// overriding the client's requested user with the session's user (if any)
+    pub fn with_startup_interception(mut self, intercept_startup: bool) -> Self {
+        self.intercept_startup = intercept_startup;
+        self
+    }
+
+    /// Reject startup messages for users or databases outside of an Allowlist, which only
+    /// applies when startup interception is enabled
+    pub fn with_allowlist(mut self, allowlist: Allowlist) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// Borrow upstream connections from a Pool of connections that have already completed the
+    /// startup handshake, which only applies when startup interception is enabled
+    pub fn with_pool(mut self, pool: Option<Pool>) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Start consuming a client stream, copying both the read and write half of the stream to a
+    /// TCP connection until either side disconnects, emits an error, goes idle, or exceeds the
+    /// max session duration. Any duplex byte stream works as the client side, whether a
+    /// WebTransport Stream, a TcpStream, or an in-memory tokio::io::duplex pair. The remote
+    /// address of the client (if it has one) is recorded on every log line of the connection.
+    #[tracing::instrument(
+        skip(self, stream, remote),
+        fields(%upstream, remote = remote.map(tracing::field::display)),
+        err
+    )]
+    pub async fn start<S>(
+        &self,
+        stream: S,
+        upstream: &Upstream,
+        user: Option<&str>,
+        remote: Option<SocketAddr>,
+    ) -> anyhow::Result<ProxyStats>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let _active = ActiveGauge::new("proxy_streams_active");
+        let stream = ByteDump::new(stream, self.debug_dump_bytes);
+        let proxied = async {
+            if self.intercept_startup {
+                self.start_with_startup(stream, upstream, user).await
+            } else {
+                self.start_verbatim(stream, upstream).await
+            }
+        };
+
+        // dropping the proxied future drops both the client stream and upstream connection
+        let started_at = SystemTime::now();
+        let started = Instant::now();
+        let result = match self.max_session_duration {
+            Some(duration) => tokio::time::timeout(duration, proxied)
+                .await
+                .unwrap_or_else(|_| Err(Timeout::MaxSessionDuration(duration).into())),
+            None => proxied.await,
+        };
+
+        SessionOutcome {
+            started_at,
+            ended_at: SystemTime::now(),
+            duration: started.elapsed(),
+            stats: result.as_ref().copied().unwrap_or_default(),
+            close_reason: match &result {
+                Ok(stats) => stats.close_reason.unwrap_or(CloseReason::ClientEof),
+                Err(error) => match error.downcast_ref::<Timeout>() {
+                    Some(Timeout::Idle(..)) => CloseReason::IdleTimeout,
+                    Some(Timeout::MaxSessionDuration(..)) => CloseReason::MaxSessionDuration,
+                    Some(Timeout::Connect(..)) | None => CloseReason::Error,
+                },
+            },
+        }
+        .emit();
+
+        metrics::counter!("proxy_streams_total", "outcome" => telemetry::outcome(&result))
+            .increment(1);
+        if let Ok(stats) = &result {
+            metrics::counter!("proxy_bytes_total", "direction" => "client_to_upstream")
+                .increment(stats.client_to_upstream);
+            metrics::counter!("proxy_bytes_total", "direction" => "upstream_to_client")
+                .increment(stats.upstream_to_client);
+        }
+
+        result
+    }
+
+    /// Copy both halves of a client stream to a new upstream connection without inspecting them
+    async fn start_verbatim<S>(
+        &self,
+        mut stream: S,
+        upstream: &Upstream,
+    ) -> anyhow::Result<ProxyStats>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         tracing::debug!("Starting proxy connection");
+        let mut connection = self.connect(upstream).await?;
+        let stats = pump(&mut stream, &mut connection, self.idle_timeout, &()).await?;
+        tracing::info!(
+            client_to_upstream = stats.client_to_upstream,
+            upstream_to_client = stats.upstream_to_client,
+            "Proxy connection closing"
+        );
+
+        Ok(stats)
+    }
+
+    /// Start consuming a client stream like Proxy::start_verbatim, but intercept the Postgres startup message
+    /// first, rewriting the user parameter before forwarding the message to the upstream.
+    /// Proxy::start delegates to this method when startup interception is enabled.
+    async fn start_with_startup<S>(
+        &self,
+        mut stream: S,
+        upstream: &Upstream,
+        user: Option<&str>,
+    ) -> anyhow::Result<ProxyStats>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        tracing::debug!("Starting startup-intercepting proxy connection");
+
+        // read and rewrite the startup message before connecting to anything
+        // (cancel requests have no user to rewrite, so they are forwarded as-is)
+        let mut startup = StartupMessage::read(&mut stream).await?;
+        if startup.is_cancel_request() {
+            tracing::debug!("Forwarding cancel request");
+        } else if let Some(user) = user {
+            if startup.get("user") != Some(user) {
+                tracing::debug!(requested = startup.get("user"), "Overriding startup user");
+            }
+            startup.set("user", user);
+        }
+
+        // reject users and databases outside the allowlist the way Postgres rejects clients
+        // without a matching pg_hba.conf entry
+        if !startup.is_cancel_request() {
+            if let Err(message) = self.allowlist.check(&startup) {
+                let rejection = Rejection::Policy(message);
+                reject(&mut stream, &rejection).await?;
+                return Err(rejection.into());
+            }
+        }
+        let encoded = startup.encode()?;
+
+        // borrow (or establish) a pooled connection for the startup parameters, if configured
+        if let Some(pool) = self.pool.as_ref().filter(|_| !startup.is_cancel_request()) {
+            return self
+                .start_pooled(pool, stream, upstream, startup.parameters(), &encoded)
+                .await;
+        }
+
+        // forward the startup message, then copy everything else verbatim
+        let mut connection = if startup.is_cancel_request() {
+            self.connect(upstream).await?
+        } else {
+            self.connect_or_reject(&mut stream, upstream).await?
+        };
+        let startup = encoded;
+        connection
+            .write_all(&startup)
+            .await
+            .context("Failed to forward startup message to upstream")?;
+        let mut stats = pump(&mut stream, &mut connection, self.idle_timeout, &()).await?;
+        stats.client_to_upstream += startup.len() as u64;
+        tracing::info!(
+            client_to_upstream = stats.client_to_upstream,
+            upstream_to_client = stats.upstream_to_client,
+            "Proxy connection closing"
+        );
+
+        Ok(stats)
+    }
+
+    /// Proxy a client stream whose startup message has already been read over a connection
+    /// from the Pool, falling back to a regular connection if the upstream requires clients to
+    /// authenticate themselves
+    async fn start_pooled<S>(
+        &self,
+        pool: &Pool,
+        mut stream: S,
+        upstream: &Upstream,
+        parameters: &[(String, String)],
+        startup: &[u8],
+    ) -> anyhow::Result<ProxyStats>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stats = match pool.checkout(upstream, parameters) {
+            Some(pooled) => {
+                tracing::debug!("Borrowed pooled upstream connection");
+                pool.start(&mut stream, pooled, self.idle_timeout).await?
+            }
+            None => {
+                let connection = self.connect_or_reject(&mut stream, upstream).await?;
+                let mut stats =
+                    match Pool::establish(upstream, parameters, connection, startup).await? {
+                        Established::Pooled(pooled) => {
+                            pool.start(&mut stream, pooled, self.idle_timeout).await?
+                        }
+                        Established::Unpoolable(mut connection, greeting) => {
+                            tracing::debug!("Upstream requires authentication, skipping pool");
+                            stream
+                                .write_all(&greeting)
+                                .await
+                                .context("Failed to forward startup response to client")?;
+                            let mut stats =
+                                pump(&mut stream, &mut connection, self.idle_timeout, &()).await?;
+                            stats.upstream_to_client += greeting.len() as u64;
+                            stats
+                        }
+                    };
+                stats.client_to_upstream += startup.len() as u64;
+                stats
+            }
+        };
+        tracing::info!(
+            client_to_upstream = stats.client_to_upstream,
+            upstream_to_client = stats.upstream_to_client,
+            "Proxy connection closing"
+        );
+
+        Ok(stats)
+    }
+
+    /// Connect to an upstream, negotiating TLS if configured, within the connect timeout
+    async fn connect(&self, upstream: &Upstream) -> anyhow::Result<Box<dyn Connection>> {
+        let connection = self.connect_upstream(upstream);
+        let result = match self.connect_timeout {
+            Some(duration) => tokio::time::timeout(duration, connection)
+                .await
+                .unwrap_or_else(|_| Err(Timeout::Connect(duration).into())),
+            None => connection.await,
+        };
+        metrics::counter!("proxy_upstream_connects_total", "outcome" => telemetry::outcome(&result))
+            .increment(1);
+        result
+    }
+
+    /// Check that an upstream accepts connections within the connect timeout, without
+    /// negotiating TLS or starting up (e.g. to answer a client's Ping). Results are reused for
+    /// PROBE_TTL, so that clients can't turn their Pings into a flood of upstream connections.
+    pub async fn probe(&self, upstream: &Upstream) -> anyhow::Result<()> {
+        let cached = self
+            .probes
+            .lock()
+            .expect("Probe lock poisoned")
+            .get(upstream)
+            .filter(|probe| probe.at.elapsed() < PROBE_TTL)
+            .map(|probe| probe.error.clone());
+        if let Some(error) = cached {
+            return error.map_or(Ok(()), |error| Err(anyhow::anyhow!(error)));
+        }
+
+        let result = match self.connect_timeout {
+            Some(duration) => tokio::time::timeout(duration, upstream.probe())
+                .await
+                .unwrap_or_else(|_| Err(Timeout::Connect(duration).into())),
+            None => upstream.probe().await,
+        };
+        let mut probes = self.probes.lock().expect("Probe lock poisoned");
+        probes.retain(|_, probe| probe.at.elapsed() < PROBE_TTL);
+        probes.insert(
+            upstream.clone(),
+            Probe {
+                at: Instant::now(),
+                error: result.as_ref().err().map(|error| format!("{error:#}")),
+            },
+        );
+        result
+    }
 
-        // derive an address for the upstream socket
-        let upstream = SocketAddrV4::new([127, 0, 0, 1].into(), upstream_port);
+    /// Connect to an upstream like Proxy::connect, but tell clients that have already sent
+    /// their startup message why the connection failed with an ErrorResponse, rather than
+    /// leaving them with nothing but a closed stream
+    async fn connect_or_reject<S>(
+        &self,
+        stream: &mut S,
+        upstream: &Upstream,
+    ) -> anyhow::Result<Box<dyn Connection>>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let error = match self.connect(upstream).await {
+            Ok(connection) => return Ok(connection),
+            Err(error) => error,
+        };
+        let rejection = Rejection::UpstreamUnreachable(error);
+        if let Err(error) = reject(stream, &rejection).await {
+            tracing::debug!(%error, "Failed to tell client about upstream connection failure");
+        }
+        Err(rejection.into())
+    }
+
+    /// Resolve, connect to, and (optionally) encrypt an upstream connection for Proxy::connect
+    async fn connect_upstream(&self, upstream: &Upstream) -> anyhow::Result<Box<dyn Connection>> {
+        match upstream {
+            Upstream::Tcp { host, port } => self.connect_tcp(host, *port).await,
+            Upstream::Unix(path) => {
+                let tls_required = self.upstream_tls.is_some() && !self.upstream_tls_fallback;
+                connect_unix(path, tls_required).await
+            }
+        }
+    }
 
-        // connect to the upstream socket using TCP
-        let mut tcp = TcpStream::connect(upstream)
+    /// Connect to a TCP upstream, negotiating TLS if configured
+    async fn connect_tcp(&self, host: &str, port: u16) -> anyhow::Result<Box<dyn Connection>> {
+        // resolve the upstream host to a set of candidate socket addresses
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .context("Failed to resolve upstream host")?
+            .collect();
+
+        // connect to the first reachable upstream socket using TCP
+        let tcp = TcpStream::connect(addresses.as_slice())
             .await
             .context("Failed to connect to upstream TCP target")?;
+        tracing::debug!(upstream = %tcp.peer_addr()?, "Connected to upstream");
+
+        // the protocol is request/response, so Nagle's algorithm only delays small messages
+        tcp.set_nodelay(true)
+            .context("Failed to set TCP_NODELAY on upstream socket")?;
+        if let Some(keepalive) = &self.upstream_keepalive {
+            SockRef::from(&tcp)
+                .set_tcp_keepalive(keepalive)
+                .context("Failed to enable TCP keepalive on upstream socket")?;
+        }
 
-        // copy between the stream and the socket in both directions
-        tokio::io::copy_bidirectional(&mut stream, &mut tcp)
+        // encrypt the connection, if required
+        match &self.upstream_tls {
+            Some(connector) => {
+                negotiate_tls(tcp, connector, host, self.upstream_tls_fallback).await
+            }
+            None => Ok(Box::new(tcp)),
+        }
+    }
+}
+
+/// Connect to an upstream Unix socket. Postgres never offers TLS over Unix sockets, so
+/// connections fail outright when upstream TLS is required rather than going unencrypted.
+async fn connect_unix(path: &Path, tls_required: bool) -> anyhow::Result<Box<dyn Connection>> {
+    anyhow::ensure!(
+        !tls_required,
+        "Upstream TLS is not supported over Unix sockets"
+    );
+
+    #[cfg(unix)]
+    {
+        let socket = tokio::net::UnixStream::connect(path)
             .await
-            .context("Proxy connection disconnected")?;
+            .with_context(|| format!("Failed to connect to upstream socket {}", path.display()))?;
+        tracing::debug!(upstream = %path.display(), "Connected to upstream");
+        Ok(Box::new(socket))
+    }
 
-        tracing::debug!("Proxy connection closing");
+    #[cfg(not(unix))]
+    anyhow::bail!(
+        "Unix socket upstreams are not supported on this platform: {}",
+        path.display()
+    )
+}
 
-        Ok(())
+/// Upgrade an upstream TCP connection to TLS using the Postgres SSLRequest handshake, leaving
+/// it unencrypted if the upstream refuses and falling back is allowed
+async fn negotiate_tls(
+    mut tcp: TcpStream,
+    connector: &TlsConnector,
+    host: &str,
+    fallback: bool,
+) -> anyhow::Result<Box<dyn Connection>> {
+    // send an SSLRequest and wait for the single-byte response
+    let mut request = [0; 8];
+    request[..4].copy_from_slice(&8i32.to_be_bytes());
+    request[4..].copy_from_slice(&SSL_REQUEST_CODE.to_be_bytes());
+    tcp.write_all(&request)
+        .await
+        .context("Failed to send SSLRequest to upstream")?;
+    let response = tcp
+        .read_u8()
+        .await
+        .context("Failed to read SSLRequest response from upstream")?;
+
+    match response {
+        b'S' => {
+            let server_name = ServerName::try_from(host)
+                .context("Upstream host is not a valid TLS server name")?;
+            let tls = connector
+                .connect(server_name, tcp)
+                .await
+                .context("Failed to establish TLS with upstream")?;
+            tracing::debug!("Upstream TLS established");
+            Ok(Box::new(tls))
+        }
+        b'N' if fallback => {
+            tracing::debug!("Upstream refused TLS, continuing unencrypted");
+            Ok(Box::new(tcp))
+        }
+        b'N' => anyhow::bail!("Upstream refused TLS, but TLS is required"),
+        other => anyhow::bail!("Unexpected SSLRequest response from upstream: {other:#04x}"),
+    }
+}
+
+/// Send a FATAL ErrorResponse to a client that has sent its startup message, then close the
+/// client's side of the stream
+async fn reject<S>(stream: &mut S, rejection: &Rejection) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&error_response(rejection.sqlstate(), rejection.message()))
+        .await
+        .context("Failed to send rejection to client")?;
+    stream
+        .shutdown()
+        .await
+        .context("Failed to shut down client writer")
+}
+
+/// Observer of the data that a pump copies in each direction, which can stop reading from the
+/// client partway through a chunk and then end the pump early, leaving both connections open
+/// (e.g. so that an upstream connection can be returned to the Pool)
+pub trait Tap: Sync {
+    /// Inspect a chunk read from the client, returning how many of its leading bytes to
+    /// forward upstream, and whether to stop reading from the client after them
+    fn client_chunk(&self, chunk: &[u8]) -> (usize, bool);
+
+    /// Inspect a chunk read from the upstream, before it is forwarded to the client
+    fn upstream_chunk(&self, chunk: &[u8]);
+
+    /// Check whether the pump can end, once the Tap has stopped it reading from the client
+    fn finished(&self) -> bool;
+}
+
+/// Copy every byte in both directions without looking at any of them
+impl Tap for () {
+    fn client_chunk(&self, chunk: &[u8]) -> (usize, bool) {
+        (chunk.len(), false)
+    }
+
+    fn upstream_chunk(&self, _chunk: &[u8]) {}
+
+    fn finished(&self) -> bool {
+        false
+    }
+}
+
+/// How one direction of a pump came to an end
+enum Ended {
+    /// the direction is done, but the other one may still be copying
+    Closed,
+    /// the Tap finished the whole pump
+    Finished,
+}
+
+/// Copy data between a client and upstream connection until both directions have reached EOF
+/// (or the Tap finishes the pump). Like tokio::io::copy_bidirectional, both directions are
+/// copied concurrently (so a write blocked on one side never holds up reads from the other),
+/// and EOF in one direction shuts down the write half of the opposite side while the other
+/// direction keeps draining. This lets clients send a Terminate and half-close while still
+/// receiving any responses the upstream has yet to flush. Unlike copy_bidirectional, the whole
+/// connection is torn down with an error if no data flows in either direction for longer than
+/// the idle timeout.
+pub async fn pump<C, U, T>(
+    client: &mut C,
+    upstream: &mut U,
+    idle_timeout: Option<Duration>,
+    tap: &T,
+) -> anyhow::Result<ProxyStats>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+    T: Tap,
+{
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let (mut upstream_reader, mut upstream_writer) = tokio::io::split(upstream);
+    let activity = Activity::new();
+    let close_reason = OnceLock::new();
+    let client_to_upstream = AtomicU64::new(0);
+    let upstream_to_client = AtomicU64::new(0);
+    let client_stopped = AtomicBool::new(false);
+
+    let copy_client_to_upstream = async {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let length = client_reader
+                .read(&mut buffer)
+                .await
+                .context("Failed to read from client")?;
+            activity.touch();
+            if length == 0 {
+                tracing::debug!("Client closed its write side, draining upstream");
+                let _ = close_reason.set(CloseReason::ClientEof);
+                upstream_writer
+                    .shutdown()
+                    .await
+                    .context("Failed to shut down upstream writer")?;
+                return anyhow::Ok(Ended::Closed);
+            }
+
+            let (forward, stop) = tap.client_chunk(&buffer[..length]);
+            upstream_writer
+                .write_all(&buffer[..forward])
+                .await
+                .context("Failed to write to upstream")?;
+            upstream_writer
+                .flush()
+                .await
+                .context("Failed to flush upstream")?;
+            activity.touch();
+            client_to_upstream.fetch_add(forward as u64, Ordering::Relaxed);
+
+            if stop {
+                let _ = close_reason.set(CloseReason::ClientEof);
+                client_stopped.store(true, Ordering::Relaxed);
+                return Ok(if tap.finished() {
+                    Ended::Finished
+                } else {
+                    Ended::Closed
+                });
+            }
+        }
+    };
+
+    let copy_upstream_to_client = async {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let length = upstream_reader
+                .read(&mut buffer)
+                .await
+                .context("Failed to read from upstream")?;
+            activity.touch();
+            if length == 0 {
+                tracing::debug!("Upstream closed its write side, draining client");
+                let _ = close_reason.set(CloseReason::UpstreamEof);
+                client_writer
+                    .shutdown()
+                    .await
+                    .context("Failed to shut down client writer")?;
+                return anyhow::Ok(Ended::Closed);
+            }
+
+            // a client that the Tap stopped reading from may no longer care about responses,
+            // but gets them anyway (ignoring failures) since it may still be reading
+            let chunk = &buffer[..length];
+            tap.upstream_chunk(chunk);
+            let written = async {
+                client_writer.write_all(chunk).await?;
+                client_writer.flush().await
+            };
+            match written.await {
+                Ok(()) => {
+                    activity.touch();
+                    upstream_to_client.fetch_add(length as u64, Ordering::Relaxed);
+                }
+                Err(error) if client_stopped.load(Ordering::Relaxed) => {
+                    tracing::debug!(%error, "Stopped client is no longer reading");
+                }
+                Err(error) => return Err(error).context("Failed to write to client"),
+            }
+
+            if client_stopped.load(Ordering::Relaxed) && tap.finished() {
+                return Ok(Ended::Finished);
+            }
+        }
+    };
+
+    // run both directions until both are closed, or either one finishes the whole pump
+    let mut copy_client_to_upstream = std::pin::pin!(copy_client_to_upstream);
+    let mut copy_upstream_to_client = std::pin::pin!(copy_upstream_to_client);
+    let mut client_closed = false;
+    let mut upstream_closed = false;
+    while !(client_closed && upstream_closed) {
+        tokio::select! {
+            ended = &mut copy_client_to_upstream, if !client_closed => match ended? {
+                Ended::Closed => client_closed = true,
+                Ended::Finished => break,
+            },
+            ended = &mut copy_upstream_to_client, if !upstream_closed => match ended? {
+                Ended::Closed => upstream_closed = true,
+                Ended::Finished => break,
+            },
+            timeout = activity.idle(idle_timeout) => return Err(timeout.into()),
+        }
+    }
+
+    Ok(ProxyStats {
+        client_to_upstream: client_to_upstream.load(Ordering::Relaxed),
+        upstream_to_client: upstream_to_client.load(Ordering::Relaxed),
+        close_reason: close_reason.get().copied(),
+    })
+}
+
+/// Time of the last data to flow in either direction of a pump, shared by the futures copying
+/// each direction so that the idle timer restarts whenever either of them makes progress
+struct Activity {
+    started: Instant,
+    last: AtomicU64,
+}
+
+impl Activity {
+    /// Start tracking activity from now
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that data just flowed (or a read or write just finished)
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// Wait until nothing has been touched for the idle timeout, or forever without one
+    async fn idle(&self, idle_timeout: Option<Duration>) -> Timeout {
+        let Some(timeout) = idle_timeout else {
+            return futures::future::pending().await;
+        };
+        loop {
+            let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = self.started + last + timeout;
+            if Instant::now() >= deadline {
+                return Timeout::Idle(timeout);
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use tokio::{io::duplex, net::TcpListener};
+
+    /// Listen for a single upstream connection on an ephemeral loopback port
+    async fn listen() -> (TcpListener, Upstream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = Upstream::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        (listener, upstream)
+    }
+
+    #[tokio::test]
+    async fn intercepted_startup_messages_carry_the_session_user() {
+        let (listener, upstream) = listen().await;
+        let proxy = Proxy::new(upstream.clone()).with_startup_interception(true);
+        let (stream, mut client) = duplex(1024);
+
+        let mut crafted = BytesMut::new();
+        postgres_protocol::message::frontend::startup_message(
+            [("user", "mallory"), ("database", "app")],
+            &mut crafted,
+        )
+        .unwrap();
+        client.write_all(&crafted).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let upstream_side = async {
+            let (mut connection, _) = listener.accept().await?;
+            let startup = StartupMessage::read(&mut connection).await?;
+            anyhow::Ok(startup)
+        };
+        let (result, startup) = tokio::join!(
+            proxy.start(stream, &upstream, Some("alice"), None),
+            upstream_side
+        );
+        result.unwrap();
+        let startup = startup.unwrap();
+        assert_eq!(startup.get("user"), Some("alice"));
+        assert_eq!(startup.get("database"), Some("app"));
+    }
+
+    #[tokio::test]
+    async fn silent_connections_close_after_the_idle_timeout() {
+        let (listener, upstream) = listen().await;
+        let proxy =
+            Proxy::new(upstream.clone()).with_idle_timeout(Some(Duration::from_millis(200)));
+        let (stream, mut client) = duplex(1024);
+
+        let started = Instant::now();
+        let (result, accepted) = tokio::join!(
+            proxy.start(stream, &upstream, None, None),
+            listener.accept()
+        );
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Timeout::Idle(..))));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // both the client stream and the upstream connection are closed
+        let mut buffer = [0; 1];
+        assert_eq!(client.read(&mut buffer).await.unwrap(), 0);
+        let (mut upstream, _) = accepted.unwrap();
+        assert_eq!(upstream.read(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn busy_connections_close_after_the_max_session_duration() {
+        let (listener, upstream) = listen().await;
+        let proxy = Proxy::new(upstream.clone())
+            .with_max_session_duration(Some(Duration::from_millis(300)));
+        let (stream, mut client) = duplex(1024);
+
+        // keep traffic flowing well past the max session duration
+        let client_side = async {
+            let mut byte = [0; 1];
+            for _ in 0..20 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let echoed = match client.write_all(b"x").await {
+                    Ok(()) => client.read(&mut byte).await.unwrap_or(0),
+                    Err(_) => 0,
+                };
+                if echoed == 0 {
+                    return true;
+                }
+            }
+            false
+        };
+        let upstream_side = async {
+            let (mut connection, _) = listener.accept().await?;
+            let (mut reader, mut writer) = connection.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        };
+
+        let started = Instant::now();
+        let (result, closed, _) = tokio::join!(
+            proxy.start(stream, &upstream, None, None),
+            client_side,
+            upstream_side
+        );
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(Timeout::MaxSessionDuration(..))
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // the client sees the stream close instead of running out its traffic
+        assert!(closed);
+    }
+
+    #[tokio::test]
+    async fn traffic_in_either_direction_keeps_connections_open() {
+        let (mut client, mut client_peer) = duplex(1024);
+        let (mut upstream, mut upstream_peer) = duplex(1024);
+        let idle_timeout = Some(Duration::from_millis(200));
+
+        let peers = async {
+            for round in 0..6 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let mut byte = [0; 1];
+                if round % 2 == 0 {
+                    client_peer.write_all(b"x").await?;
+                    upstream_peer.read_exact(&mut byte).await?;
+                } else {
+                    upstream_peer.write_all(b"y").await?;
+                    client_peer.read_exact(&mut byte).await?;
+                }
+            }
+            client_peer.shutdown().await?;
+            upstream_peer.shutdown().await
+        };
+        let (stats, peers) =
+            tokio::join!(pump(&mut client, &mut upstream, idle_timeout, &()), peers);
+        peers.unwrap();
+        let stats = stats.unwrap();
+        assert_eq!(stats.client_to_upstream, 3);
+        assert_eq!(stats.upstream_to_client, 3);
+    }
+
+    #[tokio::test]
+    async fn blocked_writes_never_hold_up_the_other_direction() {
+        const LENGTH: usize = 256 * 1024;
+        let (mut client, mut client_peer) = duplex(1024);
+        let (mut upstream, mut upstream_peer) = duplex(1024);
+
+        // like Postgres streaming NOTICEs during a COPY, the upstream only starts reading once
+        // it has written everything, while the client reads and writes at the same time
+        let upstream_side = async {
+            upstream_peer.write_all(&[b'u'; LENGTH]).await?;
+            let mut received = vec![0; LENGTH];
+            upstream_peer.read_exact(&mut received).await?;
+            upstream_peer.shutdown().await
+        };
+        let (mut client_reader, mut client_writer) = tokio::io::split(&mut client_peer);
+        let client_side = async {
+            let writes = async {
+                client_writer.write_all(&[b'c'; LENGTH]).await?;
+                client_writer.shutdown().await
+            };
+            let reads = async {
+                let mut received = Vec::new();
+                client_reader
+                    .read_to_end(&mut received)
+                    .await
+                    .map(|_| received)
+            };
+            futures::future::try_join(writes, reads).await
+        };
+
+        let (stats, upstream_side, client_side) =
+            tokio::time::timeout(Duration::from_secs(10), async {
+                tokio::join!(
+                    pump(&mut client, &mut upstream, None, &()),
+                    upstream_side,
+                    client_side
+                )
+            })
+            .await
+            .expect("proxy deadlocked");
+        upstream_side.unwrap();
+        let (_, received) = client_side.unwrap();
+        assert_eq!(received.len(), LENGTH);
+        let stats = stats.unwrap();
+        assert_eq!(stats.client_to_upstream, LENGTH as u64);
+        assert_eq!(stats.upstream_to_client, LENGTH as u64);
+    }
+
+    #[tokio::test]
+    async fn repeated_probes_share_a_single_upstream_connection() {
+        let (listener, upstream) = listen().await;
+        let proxy = Proxy::new(upstream.clone());
+
+        for _ in 0..10 {
+            proxy.probe(&upstream).await.unwrap();
+        }
+        listener.accept().await.unwrap();
+        let second = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(second.is_err(), "probe results weren't reused");
+
+        // until the result expires
+        tokio::time::sleep(PROBE_TTL).await;
+        proxy.probe(&upstream).await.unwrap();
+        listener.accept().await.unwrap();
+    }
+
+    #[test]
+    fn connection_uris_are_percent_decoded() {
+        let target: UpstreamTarget =
+            "postgres://app%40corp:p%40ss@db%2Einternal:6432/my%20db?sslmode=prefer"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            target.upstream,
+            Upstream::Tcp {
+                host: "db.internal".to_string(),
+                port: 6432,
+            }
+        );
+        assert_eq!(target.ssl_mode, SslMode::Prefer);
+        assert_eq!(target.user.as_deref(), Some("app@corp"));
+        assert_eq!(target.dbname.as_deref(), Some("my db"));
+
+        // IPv6 hosts, and socket directories as encoded hosts
+        let target: UpstreamTarget = "postgresql://[::1]?sslmode=require&sslrootcert=/ca.pem"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            target.upstream,
+            Upstream::Tcp {
+                host: "::1".to_string(),
+                port: 5432,
+            }
+        );
+        assert_eq!(target.ssl_mode, SslMode::Require);
+        assert_eq!(target.root_cert, Some(PathBuf::from("/ca.pem")));
+        let target: UpstreamTarget = "postgres://%2Fvar%2Frun%2Fpostgresql:5433".parse().unwrap();
+        assert_eq!(
+            target.upstream,
+            Upstream::Unix(PathBuf::from("/var/run/postgresql/.s.PGSQL.5433"))
+        );
+
+        for invalid in [
+            "postgres://db?sslmode=allow",
+            "postgres://db?sslrootcert=/ca.pem",
+            "postgres://db:port",
+            "postgres://[::1",
+            "postgres:///app",
+            "postgres://db?application_name=proxy",
+        ] {
+            assert!(
+                invalid.parse::<UpstreamTarget>().is_err(),
+                "{invalid} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn key_value_connection_strings_are_parsed() {
+        let target: UpstreamTarget =
+            "host = db.internal port=6432 user=app password='it\\'s secret' dbname='my db' \
+             sslmode=verify-full"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            target.upstream,
+            Upstream::Tcp {
+                host: "db.internal".to_string(),
+                port: 6432,
+            }
+        );
+        assert_eq!(target.ssl_mode, SslMode::Require);
+        assert_eq!(target.user.as_deref(), Some("app"));
+        assert_eq!(target.dbname.as_deref(), Some("my db"));
+
+        let target: UpstreamTarget = "host=/var/run/postgresql sslmode=prefer".parse().unwrap();
+        assert_eq!(
+            target.upstream,
+            Upstream::Unix(PathBuf::from("/var/run/postgresql/.s.PGSQL.5432"))
+        );
+        assert_eq!(target.ssl_mode, SslMode::Prefer);
+
+        for invalid in [
+            "host=db sslmode=allow",
+            "host=db port",
+            "host='db",
+            "port=5432",
+            "host=a,b",
+        ] {
+            assert!(
+                invalid.parse::<UpstreamTarget>().is_err(),
+                "{invalid} parsed"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn preferred_tls_falls_back_when_the_upstream_refuses() {
+        let (listener, upstream) = listen().await;
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let proxy = Proxy::new(upstream.clone())
+            .with_upstream_tls(Some(Arc::new(config)))
+            .with_upstream_tls_fallback(true);
+
+        let upstream_side = async {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = [0; 8];
+            socket.read_exact(&mut request).await?;
+            assert_eq!(&request[4..], &SSL_REQUEST_CODE.to_be_bytes());
+            socket.write_all(b"N").await?;
+            let mut message = [0; 5];
+            socket.read_exact(&mut message).await?;
+            Ok::<_, std::io::Error>(message)
+        };
+        let client_side = async {
+            let mut connection = proxy.connect(&upstream).await?;
+            connection.write_all(b"plain").await?;
+            Ok::<_, anyhow::Error>(connection)
+        };
+        let (received, connection) = tokio::join!(upstream_side, client_side);
+        assert_eq!(&received.unwrap(), b"plain");
+        connection.unwrap();
+    }
+
+    #[tokio::test]
+    async fn upstream_hosts_are_resolved_by_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let upstream: Upstream = format!("localhost:{port}").parse().unwrap();
+        let proxy = Proxy::new(upstream.clone());
+        let (stream, mut client) = duplex(1024);
+
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let upstream_side = async {
+            let (mut socket, _) = listener.accept().await?;
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await?;
+            Ok::<_, std::io::Error>(received)
+        };
+        let (stats, received) =
+            tokio::join!(proxy.start(stream, &upstream, None, None), upstream_side);
+        assert_eq!(received.unwrap(), b"hello");
+        assert_eq!(stats.unwrap().client_to_upstream, 5);
+    }
+
+    #[tokio::test]
+    async fn byte_counts_match_the_payload_through_an_echo_server() {
+        const PAYLOAD: &[u8] = &[b'x'; 100_000];
+        let (listener, upstream) = listen().await;
+        let proxy = Proxy::new(upstream.clone());
+        let (stream, mut client) = duplex(1024);
+
+        // echo everything back until the client is done
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let (mut reader, mut writer) = socket.split();
+            tokio::io::copy(&mut reader, &mut writer).await?;
+            writer.shutdown().await
+        });
+        let client_side = async {
+            let (mut reader, mut writer) = tokio::io::split(&mut client);
+            let writes = async {
+                writer.write_all(PAYLOAD).await?;
+                writer.shutdown().await
+            };
+            let mut echoed = Vec::new();
+            let reads = reader.read_to_end(&mut echoed);
+            futures::future::try_join(writes, reads).await?;
+            Ok::<_, std::io::Error>(echoed)
+        };
+
+        let (stats, echoed) = tokio::join!(proxy.start(stream, &upstream, None, None), client_side);
+        let stats = stats.unwrap();
+        assert_eq!(echoed.unwrap(), PAYLOAD);
+        assert_eq!(stats.client_to_upstream, PAYLOAD.len() as u64);
+        assert_eq!(stats.upstream_to_client, PAYLOAD.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn upstream_responses_outlive_a_client_half_close() {
+        let (listener, upstream) = listen().await;
+        let proxy = Proxy::new(upstream.clone());
+        let (stream, mut client) = duplex(1024);
+
+        // the upstream only answers once the client is done sending, like a final response
+        let upstream_side = async {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = Vec::new();
+            socket.read_to_end(&mut request).await?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            socket.write_all(b"goodbye").await?;
+            socket.shutdown().await?;
+            Ok::<_, std::io::Error>(request)
+        };
+        let client_side = async {
+            client.write_all(b"X\0\0\0\x04").await?;
+            client.shutdown().await?;
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+
+        let (stats, request, response) = tokio::join!(
+            proxy.start(stream, &upstream, None, None),
+            upstream_side,
+            client_side
+        );
+        assert_eq!(request.unwrap(), b"X\0\0\0\x04");
+        assert_eq!(response.unwrap(), b"goodbye");
+        assert_eq!(stats.unwrap().upstream_to_client, 7);
     }
 }
@@ -0,0 +1,333 @@
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{
+    future::BoxFuture,
+    ready,
+    stream::{BoxStream, FuturesUnordered},
+    FutureExt, StreamExt,
+};
+use sec_http3::{
+    error::Code,
+    ext::Datagram,
+    quic::{self, StreamId},
+    sec_http3_quinn::{self, ConnectionError, ReadError},
+};
+use std::{
+    collections::VecDeque,
+    task::{Context, Poll},
+};
+
+/// Stream type of HTTP/3 push streams, which carry a push ID after their type
+const PUSH: u64 = 0x01;
+
+/// Stream type of WebTransport unidirectional streams, which carry a session ID after their type
+const WEBTRANSPORT_UNI: u64 = 0x54;
+
+/// Stream types that sec-http3 knows how to handle (control, push, QPACK encoder and decoder,
+/// and WebTransport), besides the reserved types that it discards
+const KNOWN_TYPES: [u64; 5] = [0x00, PUSH, 0x02, 0x03, WEBTRANSPORT_UNI];
+
+/// Type alias for the headers of incoming unidirectional streams that are still being read
+type Vetting = FuturesUnordered<BoxFuture<'static, Option<RecvStream>>>;
+
+/// Type alias for the read of a single chunk, which hands back the stream once it's done
+type ReadChunk = BoxFuture<'static, (quinn::RecvStream, Result<Option<Bytes>, quinn::ReadError>)>;
+
+/// QUIC connection for sec-http3 that reads the header of every incoming unidirectional stream
+/// before handing it over. sec-http3 treats a stream that finishes before its header does, or
+/// one of an unknown type, as an error of the whole connection. Here those streams are stopped
+/// on their own instead, and every other stream replays its header in the chunks that
+/// sec-http3 expects (so a header that arrives in a single write never waits on more data).
+/// Everything else is delegated to sec-http3's own quinn connection.
+pub struct Connection {
+    inner: sec_http3_quinn::Connection,
+    incoming_uni: BoxStream<'static, Result<quinn::RecvStream, quinn::ConnectionError>>,
+    vetting: Vetting,
+}
+
+impl Connection {
+    /// Wrap an established quinn connection
+    pub fn new(conn: quinn::Connection) -> Self {
+        Self {
+            inner: sec_http3_quinn::Connection::new(conn.clone()),
+            incoming_uni: futures::stream::unfold(conn, |conn| async {
+                Some((conn.accept_uni().await, conn))
+            })
+            .boxed(),
+            vetting: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<B: Buf> quic::Connection<B> for Connection {
+    type SendStream = sec_http3_quinn::SendStream<B>;
+    type RecvStream = RecvStream;
+    type BidiStream = sec_http3_quinn::BidiStream<B>;
+    type OpenStreams = OpenStreams;
+    type Error = ConnectionError;
+
+    fn poll_accept_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::RecvStream>, Self::Error>> {
+        loop {
+            // start reading the header of every stream that has arrived in the meantime
+            while let Poll::Ready(accepted) = self.incoming_uni.poll_next_unpin(cx) {
+                match accepted {
+                    Some(Ok(stream)) => self.vetting.push(vet(stream).boxed()),
+                    Some(Err(error)) => return Poll::Ready(Err(error.into())),
+                    None => return Poll::Ready(Ok(None)),
+                }
+            }
+
+            match ready!(self.vetting.poll_next_unpin(cx)) {
+                Some(Some(stream)) => return Poll::Ready(Ok(Some(stream))),
+                // a stopped stream, so move on to the next one
+                Some(None) => continue,
+                None => return Poll::Pending,
+            }
+        }
+    }
+
+    fn poll_accept_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::BidiStream>, Self::Error>> {
+        quic::Connection::<B>::poll_accept_bidi(&mut self.inner, cx)
+    }
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, Self::Error>> {
+        quic::Connection::<B>::poll_open_bidi(&mut self.inner, cx)
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::SendStream, Self::Error>> {
+        quic::Connection::<B>::poll_open_send(&mut self.inner, cx)
+    }
+
+    fn opener(&self) -> Self::OpenStreams {
+        OpenStreams(quic::Connection::<B>::opener(&self.inner))
+    }
+
+    fn close(&mut self, code: Code, reason: &[u8]) {
+        quic::Connection::<B>::close(&mut self.inner, code, reason)
+    }
+}
+
+impl<B: Buf> quic::SendDatagramExt<B> for Connection {
+    type Error = sec_http3_quinn::SendDatagramError;
+
+    fn send_datagram(&mut self, data: Datagram<B>) -> Result<(), Self::Error> {
+        self.inner.send_datagram(data)
+    }
+}
+
+impl quic::RecvDatagramExt for Connection {
+    type Buf = Bytes;
+    type Error = ConnectionError;
+
+    fn poll_accept_datagram(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::Buf>, Self::Error>> {
+        self.inner.poll_accept_datagram(cx)
+    }
+}
+
+/// Opener of outgoing streams, which sec-http3 shares with the tasks that send requests
+#[derive(Clone)]
+pub struct OpenStreams(sec_http3_quinn::OpenStreams);
+
+impl<B: Buf> quic::OpenStreams<B> for OpenStreams {
+    type SendStream = sec_http3_quinn::SendStream<B>;
+    type RecvStream = RecvStream;
+    type BidiStream = sec_http3_quinn::BidiStream<B>;
+    type Error = ConnectionError;
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, Self::Error>> {
+        quic::OpenStreams::<B>::poll_open_bidi(&mut self.0, cx)
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::SendStream, Self::Error>> {
+        quic::OpenStreams::<B>::poll_open_send(&mut self.0, cx)
+    }
+
+    fn close(&mut self, code: Code, reason: &[u8]) {
+        quic::OpenStreams::<B>::close(&mut self.0, code, reason)
+    }
+}
+
+/// Incoming unidirectional stream, which replays the header chunks that Connection read from it
+pub struct RecvStream {
+    id: StreamId,
+    replay: VecDeque<Bytes>,
+    stream: Option<quinn::RecvStream>,
+    reading: Option<ReadChunk>,
+}
+
+impl quic::RecvStream for RecvStream {
+    type Buf = Bytes;
+    type Error = ReadError;
+
+    fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, ReadError>> {
+        if let Some(chunk) = self.replay.pop_front() {
+            return Poll::Ready(Ok(Some(chunk)));
+        }
+
+        let reading = self.reading.get_or_insert_with(|| {
+            let mut stream = self
+                .stream
+                .take()
+                .expect("stream is read from once at a time");
+            async move {
+                let chunk = read_chunk(&mut stream).await;
+                (stream, chunk)
+            }
+            .boxed()
+        });
+        let (stream, chunk) = ready!(reading.poll_unpin(cx));
+        self.reading = None;
+        self.stream = Some(stream);
+        Poll::Ready(chunk.map_err(ReadError::from))
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        if let Some(stream) = self.stream.as_mut() {
+            let code = quinn::VarInt::from_u64(error_code).expect("invalid error code");
+            stream.stop(code).ok();
+        }
+    }
+
+    fn recv_id(&self) -> StreamId {
+        self.id
+    }
+}
+
+/// Read the next chunk of a stream, or None once it has finished
+async fn read_chunk(stream: &mut quinn::RecvStream) -> Result<Option<Bytes>, quinn::ReadError> {
+    Ok(stream
+        .read_chunk(usize::MAX, true)
+        .await?
+        .map(|chunk| chunk.bytes))
+}
+
+/// Read the header of an incoming unidirectional stream (its type, followed by a session or
+/// push ID for the types that carry one), stopping streams that end early or have an unknown
+/// type. The header is replayed as one chunk for the type and another for the rest, since
+/// sec-http3 reads each of them with a separate read.
+async fn vet(mut stream: quinn::RecvStream) -> Option<RecvStream> {
+    let id = stream.id();
+    let mut header = BytesMut::new();
+    let (ty, split) = loop {
+        if let Some(header) = parse_header(&header) {
+            break header;
+        }
+        match read_chunk(&mut stream).await {
+            Ok(Some(chunk)) => header.extend_from_slice(&chunk),
+            Ok(None) => {
+                tracing::debug!(stream_id = %id, "Stopping stream that ended before its header");
+                stream.stop(stream_creation_error()).ok();
+                return None;
+            }
+            Err(error) => {
+                tracing::debug!(stream_id = %id, %error, "Failed to read stream header");
+                return None;
+            }
+        }
+    };
+
+    if !KNOWN_TYPES.contains(&ty) && !is_reserved(ty) {
+        tracing::debug!(stream_id = %id, stream_type = ty, "Stopping stream of unknown type");
+        stream.stop(stream_creation_error()).ok();
+        return None;
+    }
+
+    let mut replay = VecDeque::with_capacity(2);
+    let mut header = header.freeze();
+    replay.push_back(header.split_to(split));
+    if !header.is_empty() {
+        replay.push_back(header);
+    }
+
+    Some(RecvStream {
+        id: id.0.try_into().expect("invalid stream id"),
+        replay,
+        stream: Some(stream),
+        reading: None,
+    })
+}
+
+/// H3_STREAM_CREATION_ERROR, the code for stopping streams with a malformed header
+fn stream_creation_error() -> quinn::VarInt {
+    quinn::VarInt::from_u64(Code::H3_STREAM_CREATION_ERROR.value()).expect("invalid error code")
+}
+
+/// Parse the type out of a complete stream header, along with where its type ends
+fn parse_header(header: &[u8]) -> Option<(u64, usize)> {
+    let (ty, length) = parse_varint(header)?;
+    if ty == PUSH || ty == WEBTRANSPORT_UNI {
+        parse_varint(&header[length..])?;
+    }
+    Some((ty, length))
+}
+
+/// Parse a QUIC variable-length integer, along with its length in bytes
+fn parse_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let first = *bytes.first()?;
+    let length = 1 << (first >> 6);
+    let bytes = bytes.get(..length)?;
+    let value = bytes[1..]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, byte| {
+            value << 8 | u64::from(*byte)
+        });
+    Some((value, length))
+}
+
+/// Check for the reserved stream types of 0x1f * N + 0x21, which exist to exercise the
+/// requirement that unknown types are ignored (and which sec-http3 discards)
+fn is_reserved(ty: u64) -> bool {
+    ty >= 0x21 && (ty - 0x21).is_multiple_of(0x1f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_are_complete_once_their_ids_arrive() {
+        // control streams are nothing but a type
+        assert_eq!(parse_header(&[0x00]), Some((0x00, 1)));
+        assert_eq!(parse_header(&[]), None);
+
+        // WebTransport streams carry a session ID after their 2-byte type
+        assert_eq!(parse_header(&[0x40, 0x54]), None);
+        assert_eq!(
+            parse_header(&[0x40, 0x54, 0x04]),
+            Some((WEBTRANSPORT_UNI, 2))
+        );
+        assert_eq!(parse_header(&[0x40, 0x54, 0x40]), None);
+        assert_eq!(
+            parse_header(&[0x40, 0x54, 0x40, 0x40, b'x']),
+            Some((WEBTRANSPORT_UNI, 2))
+        );
+    }
+
+    #[test]
+    fn reserved_types_are_recognised() {
+        assert!(is_reserved(0x21));
+        assert!(is_reserved(0x21 + 0x1f * 3));
+        assert!(!is_reserved(0x22));
+        assert!(!is_reserved(WEBTRANSPORT_UNI));
+    }
+}
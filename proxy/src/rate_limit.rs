@@ -0,0 +1,82 @@
+use governor::{DefaultKeyedRateLimiter, Quota};
+use std::{
+    net::IpAddr,
+    num::NonZeroU32,
+    time::{Duration, Instant},
+};
+
+/// Time between sweeps of the IPs whose allowance has refilled completely
+const PRUNE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Rate limiter for new connections, keyed by client IP. Each IP may burst up to the full
+/// per-minute allowance at once, after which its allowance refills steadily over the course
+/// of a minute.
+pub struct RateLimiter {
+    limiter: DefaultKeyedRateLimiter<IpAddr>,
+    last_prune: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter that allows each IP this many connections per minute
+    pub fn new(per_minute: NonZeroU32) -> Self {
+        Self::with_quota(Quota::per_minute(per_minute))
+    }
+
+    /// Create a limiter that allows each IP connections at the rate of a quota
+    fn with_quota(quota: Quota) -> Self {
+        Self {
+            limiter: governor::RateLimiter::keyed(quota),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Count a new connection from an IP, returning false if it has no allowance left
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        self.prune();
+        self.limiter.check_key(&ip).is_ok()
+    }
+
+    /// Forget IPs whose allowance has refilled completely (at most once per prune period), so
+    /// that a stream of one-off clients can't grow the limiter without bound
+    fn prune(&mut self) {
+        if self.last_prune.elapsed() < PRUNE_PERIOD {
+            return;
+        }
+
+        self.last_prune = Instant::now();
+        self.limiter.retain_recent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// Time it takes for a single connection of a test limiter's allowance to refill
+    const REFILL: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn clients_hammering_from_one_ip_are_throttled() {
+        let burst = NonZeroU32::new(5).unwrap();
+        let mut limiter =
+            RateLimiter::with_quota(Quota::with_period(REFILL).unwrap().allow_burst(burst));
+        let hammering = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let allowed = (0..50).filter(|_| limiter.check(hammering)).count();
+        assert_eq!(allowed, 5);
+
+        // other IPs still get their own allowance
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert!(limiter.check(other));
+
+        // a throttled IP is let back in once its allowance refills
+        std::thread::sleep(REFILL);
+        assert!(limiter.check(hammering));
+        assert!(!limiter.check(hammering));
+
+        // and forgotten once its allowance has refilled completely
+        std::thread::sleep(REFILL * 10);
+        limiter.limiter.retain_recent();
+        assert_eq!(limiter.limiter.len(), 0);
+    }
+}
@@ -1,89 +1,694 @@
-use bytes::Bytes;
-use http::Method;
+use crate::{quic, telemetry, tls::PeerIdentity};
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{header::HeaderName, Method, Request, Response, StatusCode, Uri};
+use quinn::VarInt;
 use sec_http3::{
     ext::Protocol,
+    quic::{RecvStream as _, SendStream as _},
     sec_http3_quinn,
-    server::Connection,
+    server::{Connection, RequestStream},
     webtransport::{
         server::{AcceptedBi, WebTransportSession},
-        stream::BidiStream,
+        stream::{self, BidiStream},
+        SessionId,
     },
 };
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tracing::Instrument;
 
 /// Type alias for the bidirectional streams supported by the Session
 pub type Stream = BidiStream<sec_http3_quinn::BidiStream<Bytes>, Bytes>;
 
-/// Newtype wrapper around the specific flavor of WebTransport sessions that this crate uses
-pub struct Session(WebTransportSession<sec_http3_quinn::Connection, Bytes>);
+/// Type alias for the unidirectional streams that clients open to a Session
+pub type RecvStream = stream::RecvStream<quic::RecvStream, Bytes>;
+
+/// Type alias for the unidirectional streams that the proxy opens to a client
+pub type SendStream = stream::SendStream<sec_http3_quinn::SendStream<Bytes>, Bytes>;
+
+/// Type alias for the WebTransportSession that drives every Session of a connection
+type Driver = WebTransportSession<quic::Connection, Bytes>;
+
+/// Type alias for the HTTP/3 request streams that carry CONNECT requests
+type ConnectStream = RequestStream<sec_http3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Type alias for the sending half of a CONNECT request's stream
+type ConnectSendStream = RequestStream<sec_http3_quinn::SendStream<Bytes>, Bytes>;
+
+/// Capsule type of CLOSE_WEBTRANSPORT_SESSION, sent over a CONNECT stream to close its session
+const CLOSE_WEBTRANSPORT_SESSION: u64 = 0x2843;
+
+/// Longest close reason (in bytes) that a CLOSE_WEBTRANSPORT_SESSION capsule may carry
+const MAX_CLOSE_REASON: usize = 1024;
+
+/// Close code for sessions whose CONNECT path has no upstream route
+pub const CLOSE_NO_ROUTE: u32 = 1;
+
+/// H3_REQUEST_REJECTED, the code for stopping streams that the proxy won't serve at all (such
+/// as those of unknown or unrouted Sessions)
+pub const STREAM_REJECTED: u64 = 0x10b;
+
+/// Close code for additional sessions whose startup message was rejected by the proxy's
+/// allowlist (the primary session stays open, since closing it would close the whole
+/// connection)
+pub const CLOSE_POLICY_VIOLATION: u32 = 3;
+
+/// Rules applied to the CONNECT request of every new Session
+#[derive(Clone, Debug, Default)]
+pub struct SessionPolicy {
+    /// header carrying the Postgres user that an upstream auth layer has verified
+    pub user_header: Option<HeaderName>,
+    /// reject sessions whose CONNECT request is missing the user header
+    pub require_user_header: bool,
+    /// take the Postgres user from the client's certificate instead of a header, rejecting
+    /// sessions from clients whose certificate doesn't name anyone
+    pub user_from_client_cert: bool,
+    /// maximum number of sessions multiplexed over a single HTTP/3 connection
+    pub max_sessions: u64,
+    /// web origins allowed to open sessions (any origin is allowed if empty)
+    pub allowed_origins: Vec<String>,
+    /// allow sessions whose CONNECT request has no Origin header, even with an allowlist
+    pub allow_missing_origin: bool,
+}
+
+/// Details of the CONNECT request that established a single WebTransport session
+pub struct Session {
+    id: SessionId,
+    uri: Uri,
+    user: Option<String>,
+    remote: SocketAddr,
+    peer: Option<Arc<PeerIdentity>>,
+    control: Control,
+    driver: Arc<Driver>,
+}
+
+/// Means of closing a Session, which depends on whether the Session drives its connection
+enum Control {
+    /// the primary Session, whose CONNECT stream is owned by sec-http3's WebTransportSession
+    Primary(quinn::Connection),
+    /// the sending half of an additional Session's CONNECT stream (until the Session is
+    /// closed), since closing the stream closes the Session
+    Additional(Mutex<Option<Box<ConnectSendStream>>>),
+}
 
 impl Session {
-    /// Upgrade a QUIC connection to an HTTP3 connection and negotiate a new WebTransport session.
-    #[tracing::instrument(skip_all, fields(remote = %connecting.remote_address()), err)]
-    pub async fn start(connecting: quinn::Connecting) -> anyhow::Result<Self> {
+    /// The ID of this Session, shared by every stream and datagram that belongs to it
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// The path of the CONNECT request that established this Session
+    pub fn path(&self) -> &str {
+        self.uri.path()
+    }
+
+    /// The Postgres user forwarded in the configured user header (or named by the client's
+    /// certificate), if any
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// The address of the client that established this Session (as of the handshake)
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// The identity of the client, if it authenticated with a certificate that the configured
+    /// client CA verified
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer.as_deref()
+    }
+
+    /// Check if this is the primary Session, which can't be closed without closing its whole
+    /// connection (and with it, every other Session multiplexed on that connection)
+    pub fn is_primary(&self) -> bool {
+        matches!(self.control, Control::Primary(..))
+    }
+
+    /// Open a unidirectional stream from the proxy to the client on this Session, for pushing
+    /// data that the client never answers on the same stream, e.g. streaming the NOTIFY events
+    /// of a LISTEN to the browser as they arrive without holding up a Postgres stream. Like
+    /// every stream the proxy opens, this only counts against the client's stream limits,
+    /// never against the bi-directional streams that clients open for Postgres connections.
+    pub async fn open_uni(&self) -> anyhow::Result<SendStream> {
+        Ok(self.driver.open_uni(self.id).await?)
+    }
+
+    /// Close this Session with an application error code and reason that clients can
+    /// interpret. Additional Sessions are sent a CLOSE_WEBTRANSPORT_SESSION capsule (which
+    /// browsers report through WebTransport.closed), but sec-http3 keeps the CONNECT stream of
+    /// the primary Session to itself, so the primary Session can only be closed along with its
+    /// whole QUIC connection, using the code as the connection's application error code.
+    pub async fn close(&self, code: u32, reason: &str) -> anyhow::Result<()> {
+        tracing::debug!(session_id = ?self.id, code, reason, "Closing WebTransport session");
+        match &self.control {
+            Control::Primary(connection) => {
+                connection.close(VarInt::from_u32(code), reason.as_bytes());
+            }
+            Control::Additional(stream) => {
+                let stream = stream.lock().expect("Session lock poisoned").take();
+                if let Some(mut stream) = stream {
+                    stream.send_data(close_capsule(code, reason)).await?;
+                    stream.finish().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every WebTransport session multiplexed over a single HTTP/3 connection.
+///
+/// WebTransportSession::accept takes ownership of the entire HTTP/3 connection, so the
+/// WebTransportSession of the first CONNECT request drives the connection for every Session:
+/// later CONNECT requests and the streams of every Session are all accepted through it, then
+/// matched up with their Session by ID. Since sec-http3 only sends datagrams on the first
+/// session, only that Session can receive datagrams from the server.
+pub struct Sessions {
+    driver: Arc<Driver>,
+    policy: SessionPolicy,
+    remote: SocketAddr,
+    peer: Option<Arc<PeerIdentity>>,
+    sessions: Arc<Registry<Session>>,
+}
+
+/// Live Sessions of a single connection by ID. Additional Sessions are removed once their
+/// CONNECT stream ends, so only Sessions that are still open count against max_sessions.
+struct Registry<T> {
+    entries: Mutex<HashMap<SessionId, Arc<T>>>,
+    max: u64,
+}
+
+impl<T> Registry<T> {
+    /// Create a Registry holding at most max entries at a time
+    fn new(max: u64) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max,
+        }
+    }
+
+    /// Look up an entry by its Session ID
+    fn get(&self, id: SessionId) -> Option<Arc<T>> {
+        let entries = self.entries.lock().expect("Session lock poisoned");
+        entries.get(&id).cloned()
+    }
+
+    /// Check if there is no room for another entry
+    fn is_full(&self) -> bool {
+        self.entries.lock().expect("Session lock poisoned").len() as u64 >= self.max
+    }
+
+    /// Add an entry for a new Session
+    fn insert(&self, id: SessionId, entry: Arc<T>) {
+        let mut entries = self.entries.lock().expect("Session lock poisoned");
+        entries.insert(id, entry);
+    }
+
+    /// Forget the entry of a Session that has ended
+    fn remove(&self, id: SessionId) {
+        self.entries
+            .lock()
+            .expect("Session lock poisoned")
+            .remove(&id);
+    }
+}
+
+impl Sessions {
+    /// Upgrade a QUIC connection to an HTTP3 connection and negotiate its first WebTransport
+    /// session.
+    #[tracing::instrument(
+        skip_all,
+        fields(remote = %connecting.remote_address(), alpn = tracing::field::Empty),
+        err
+    )]
+    pub async fn start(
+        connecting: quinn::Connecting,
+        policy: &SessionPolicy,
+    ) -> anyhow::Result<Self> {
+        let result = Self::handshake(connecting, policy).await;
+        metrics::counter!("proxy_handshakes_total", "outcome" => telemetry::outcome(&result))
+            .increment(1);
+        result
+    }
+
+    /// Run through the QUIC, HTTP/3, and WebTransport handshakes for Sessions::start
+    async fn handshake(
+        connecting: quinn::Connecting,
+        policy: &SessionPolicy,
+    ) -> anyhow::Result<Self> {
         tracing::debug!("new connection attempted");
-        let connection = connecting
-            .await
-            .map(sec_http3::sec_http3_quinn::Connection::new)?;
+        let quic = connecting.await?;
+        if let Some(alpn) = negotiated_alpn(&quic) {
+            tracing::Span::current().record("alpn", alpn.as_str());
+        }
+        tracing::debug!("new QUIC connection established");
+        let remote = quic.remote_address();
+        let peer = peer_identity(&quic)?;
+        let connection = quic::Connection::new(quic.clone());
 
         let mut h3: Connection<_, Bytes> = sec_http3::server::builder()
             .enable_webtransport(true)
             .enable_connect(true)
             .enable_datagram(true)
-            .max_webtransport_sessions(1)
+            .max_webtransport_sessions(policy.max_sessions)
             .send_grease(true)
             .build(connection)
             .await?;
 
         tracing::debug!("new HTTP/3 connection established");
 
-        // handle stream requests over the new HTTP3 connection
-        // TODO: we should be able to establish multiple WebTransport Sessions
-        // over the same underlying HTTP/3 connection, right? That'd require something custom
-        // for the third argument to WebTransportSession::accept, though, as that takes
-        // ownership of the *entire* h3 connection.
+        // the first request over the new HTTP3 connection establishes the first session
         let (request, stream) = h3
             .accept()
             .await?
             .ok_or(anyhow::anyhow!("Connection closed"))?;
+        let user = match validate(&request, policy, peer.as_deref()) {
+            Ok(user) => user,
+            Err(status) => {
+                reject(stream, status).await?;
+                anyhow::bail!("Rejected WebTransport session request with status {status}");
+            }
+        };
 
-        // verify that this is really a WebTransport request
-        let extensions = request.extensions();
-        anyhow::ensure!(
-            matches!(request.method(), &Method::CONNECT),
-            "Request was not a proper CONNECT",
-        );
-        anyhow::ensure!(
-            extensions.get() == Some(&Protocol::WEB_TRANSPORT),
-            "Request was not using the WEB_TRANSPORT protocol",
+        // build a real session from this request, which takes over the whole connection
+        let uri = request.uri().clone();
+        let driver = Arc::new(WebTransportSession::accept(request, stream, h3).await?);
+        let session = Arc::new(Session {
+            id: driver.session_id(),
+            uri,
+            user,
+            remote,
+            peer: peer.clone(),
+            control: Control::Primary(quic),
+            driver: driver.clone(),
+        });
+        tracing::debug!(
+            session_id = ?session.id,
+            path = session.path(),
+            user = ?session.user,
+            peer = ?session.peer,
+            "WebTransport session initiated",
         );
-        tracing::debug!("new WebTransport session requested");
 
-        // build a real session from this request
-        let session = WebTransportSession::accept(request, stream, h3).await?;
+        let sessions = Registry::new(policy.max_sessions);
+        sessions.insert(session.id, session);
+        Ok(Self {
+            driver,
+            policy: policy.clone(),
+            remote,
+            peer,
+            sessions: Arc::new(sessions),
+        })
+    }
+
+    /// The first Session established over this connection
+    pub fn primary(&self) -> Arc<Session> {
+        self.session(self.driver.session_id())
+            .expect("Primary session should always be present")
+    }
+
+    /// Look up a Session by its ID
+    fn session(&self, id: SessionId) -> Option<Arc<Session>> {
+        self.sessions.get(id)
+    }
+
+    /// Accept the next bi-directional stream of any Session on this connection, returning
+    /// None once the connection has been closed. New CONNECT requests are turned into new
+    /// Sessions along the way (with failures logged rather than returned, since they only
+    /// concern the request's own stream), while other requests are answered with a 405 and
+    /// returned as a recoverable UnsupportedRequest error.
+    #[tracing::instrument(skip(self), fields(session_id = ?self.driver.session_id()))]
+    pub async fn accept_bidirectional(&self) -> anyhow::Result<Option<(Arc<Session>, Stream)>> {
+        loop {
+            tracing::debug!("Waiting for the next bi-directional stream request");
+
+            let Some(request) = self.driver.accept_bi().await? else {
+                tracing::debug!("Connection closed");
+                return Ok(None);
+            };
+
+            match request {
+                AcceptedBi::BidiStream(id, stream) => match self.session(id) {
+                    Some(session) => {
+                        tracing::debug!(session_id = ?id, "Bidirectional Stream initiated");
+                        return Ok(Some((session, stream)));
+                    }
+                    None => {
+                        tracing::warn!(session_id = ?id, "Rejecting stream of unknown session");
+                        let mut stream = stream;
+                        stream.stop_sending(STREAM_REJECTED);
+                        stream.reset(STREAM_REJECTED);
+                    }
+                },
+                AcceptedBi::Request(request, stream) if request.method() == Method::CONNECT => {
+                    if let Err(error) = self.open(request, stream).await {
+                        tracing::warn!(%error, "Failed to open additional WebTransport session");
+                    }
+                }
+                AcceptedBi::Request(request, stream) => {
+                    tracing::debug!(
+                        method = %request.method(),
+                        uri = %request.uri(),
+                        "HTTP/3 request received over an existing session"
+                    );
+                    if let Err(error) = reject(stream, StatusCode::METHOD_NOT_ALLOWED).await {
+                        tracing::debug!(%error, "Failed to respond to unsupported request");
+                    }
+                    return Err(UnsupportedRequest("Request").into());
+                }
+            }
+        }
+    }
+
+    /// Accept the next unidirectional stream that a client opened on any Session of this
+    /// connection, returning None once the connection has been closed. Unidirectional streams
+    /// are queued separately from bi-directional ones, so accepting them never holds up (or
+    /// gets held up by) the Postgres streams of Sessions::accept_bidirectional.
+    ///
+    /// Streams that end before their header are stopped by quic::Connection before they get
+    /// here, and streams of unknown Sessions are stopped with STREAM_REJECTED, so neither one
+    /// affects the rest of the connection.
+    pub async fn accept_uni(&self) -> anyhow::Result<Option<(Arc<Session>, RecvStream)>> {
+        loop {
+            let Some((id, stream)) = self.driver.accept_uni().await? else {
+                tracing::debug!("Connection closed");
+                return Ok(None);
+            };
+
+            match self.session(id) {
+                Some(session) => {
+                    tracing::debug!(session_id = ?id, "Unidirectional Stream initiated");
+                    return Ok(Some((session, stream)));
+                }
+                None => {
+                    tracing::warn!(session_id = ?id, "Stopping stream of unknown session");
+                    let mut stream = stream;
+                    stream.stop_sending(STREAM_REJECTED);
+                }
+            }
+        }
+    }
+
+    /// Establish an additional Session from a CONNECT request on an existing connection
+    async fn open(&self, request: Request<()>, mut stream: ConnectStream) -> anyhow::Result<()> {
+        let user = match validate(&request, &self.policy, self.peer.as_deref()) {
+            Ok(_) if self.sessions.is_full() => Err(StatusCode::TOO_MANY_REQUESTS),
+            result => result,
+        };
+        let user = match user {
+            Ok(user) => user,
+            Err(status) => {
+                tracing::warn!(%status, "Rejected additional WebTransport session request");
+                return reject(stream, status).await;
+            }
+        };
+
+        // accept the session the same way WebTransportSession::accept does
+        let response = Response::builder()
+            .header("sec-webtransport-http3-draft", "draft02")
+            .status(StatusCode::OK)
+            .body(())?;
+        stream.send_response(response).await?;
+
+        let id = stream.send_id().into();
+        let (send, mut recv) = stream.split();
+        let session = Arc::new(Session {
+            id,
+            uri: request.uri().clone(),
+            user,
+            remote: self.remote,
+            peer: self.peer.clone(),
+            // hold on to the CONNECT stream, since closing it would close the session
+            control: Control::Additional(Mutex::new(Some(Box::new(send)))),
+            driver: self.driver.clone(),
+        });
         tracing::debug!(
-            session_id = ?session.session_id(),
-            "WebTransport session initiated",
+            session_id = ?session.id,
+            path = session.path(),
+            user = ?session.user,
+            peer = ?session.peer,
+            "Additional WebTransport session initiated",
         );
-        Ok(Self(session))
+
+        self.sessions.insert(id, session);
+
+        // forget the Session once either side ends its CONNECT stream (which is all a client
+        // sends on it after the request, besides a CLOSE_WEBTRANSPORT_SESSION capsule)
+        let sessions = Arc::downgrade(&self.sessions);
+        let watch = async move {
+            while let Ok(Some(_)) = recv.recv_data().await {}
+            tracing::debug!(session_id = ?id, "Additional WebTransport session ended");
+            if let Some(sessions) = sessions.upgrade() {
+                sessions.remove(id);
+            }
+        };
+        tokio::spawn(watch.instrument(tracing::Span::current()));
+        Ok(())
     }
 
-    /// Accept a bi-directional stream tied to this Session.
-    #[tracing::instrument(skip(self), fields(session_id = ?self.0.session_id()), err)]
-    pub async fn accept_bidirectional(&self) -> anyhow::Result<Stream> {
-        tracing::debug!("Waiting for the next bi-directional stream request");
+    /// Accept every datagram sent to any Session on this connection until it is closed,
+    /// along with the ID of its Session. Datagrams are lossy, so they are never proxied
+    /// upstream as part of the Postgres protocol.
+    pub fn accept_datagrams(&self) -> impl futures::Stream<Item = (SessionId, Bytes)> + '_ {
+        futures::stream::unfold(self, |sessions| async move {
+            match sessions.driver.accept_datagram().await {
+                Ok(Some((id, datagram))) => {
+                    tracing::debug!(session_id = ?id, length = datagram.len(), "Datagram received");
+                    Some(((id, datagram), sessions))
+                }
+                Ok(None) => None,
+                Err(error) => {
+                    tracing::debug!(%error, "Stopped accepting datagrams");
+                    None
+                }
+            }
+        })
+    }
 
-        let request = self
-            .0
-            .accept_bi()
-            .await?
-            .ok_or(anyhow::anyhow!("Unsupported stream type requested"))?;
+    /// Send an unreliable datagram to the client over a Session (which must be the primary)
+    pub fn send_datagram(&self, id: SessionId, datagram: Bytes) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            id == self.driver.session_id(),
+            "Datagrams can only be sent on the first session of a connection"
+        );
+        self.driver.send_datagram(datagram)?;
+        Ok(())
+    }
 
-        let AcceptedBi::BidiStream(_, stream) = request else {
-            // FIXME: handle these additional requests over the same connection
-            todo!("handle additional http3 requests over this stream");
+    /// Accept every bi-directional stream of every Session until the connection is closed,
+    /// skipping over any unsupported requests along the way.
+    pub fn accept_all(&self) -> impl futures::Stream<Item = (Arc<Session>, Stream)> + '_ {
+        futures::stream::unfold(self, |sessions| async move {
+            loop {
+                match sessions.accept_bidirectional().await {
+                    Ok(stream) => return stream.map(|stream| (stream, sessions)),
+                    Err(error) if error.is::<UnsupportedRequest>() => {
+                        tracing::warn!(%error, "Skipping unsupported stream request");
+                    }
+                    Err(error) => {
+                        tracing::error!(%error, "Failed to accept stream request");
+                        return None;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Accept every unidirectional stream of every Session until the connection is closed
+    pub fn accept_all_uni(&self) -> impl futures::Stream<Item = (Arc<Session>, RecvStream)> + '_ {
+        futures::stream::unfold(self, |sessions| async move {
+            match sessions.accept_uni().await {
+                Ok(stream) => stream.map(|stream| (stream, sessions)),
+                Err(error) => {
+                    tracing::debug!(%error, "Stopped accepting unidirectional streams");
+                    None
+                }
+            }
+        })
+    }
+}
+
+/// The ALPN protocol (e.g. h3 or h3-29) that a client settled on during the TLS handshake.
+/// quinn doesn't expose the negotiated QUIC version itself, but clients that fall back to a
+/// draft version of QUIC also fall back to the matching draft of HTTP/3.
+fn negotiated_alpn(quic: &quinn::Connection) -> Option<String> {
+    let data = quic
+        .handshake_data()?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?;
+    data.protocol
+        .map(|protocol| String::from_utf8_lossy(&protocol).into_owned())
+}
+
+/// Read the identity out of the certificate chain that a client authenticated with, if any
+fn peer_identity(quic: &quinn::Connection) -> anyhow::Result<Option<Arc<PeerIdentity>>> {
+    let Some(identity) = quic.peer_identity() else {
+        return Ok(None);
+    };
+    let certs = identity
+        .downcast::<Vec<rustls::Certificate>>()
+        .map_err(|_| anyhow::anyhow!("Unexpected type of QUIC peer identity"))?;
+    Ok(PeerIdentity::from_certificates(&certs)?.map(Arc::new))
+}
+
+/// Verify that a request is really a WebTransport CONNECT request allowed by the policy,
+/// extracting the user forwarded by any upstream auth layer (or named by the client's
+/// certificate). Disallowed requests are turned into the status code that they should be
+/// rejected with.
+fn validate(
+    request: &Request<()>,
+    policy: &SessionPolicy,
+    peer: Option<&PeerIdentity>,
+) -> Result<Option<String>, StatusCode> {
+    if request.method() != Method::CONNECT {
+        tracing::warn!(method = %request.method(), "Request was not a proper CONNECT");
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    if request.extensions().get() != Some(&Protocol::WEB_TRANSPORT) {
+        tracing::warn!("Request was not using the WEB_TRANSPORT protocol");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    tracing::debug!("new WebTransport session requested");
+
+    // browsers always send an Origin, so a missing one means a non-browser client
+    if !policy.allowed_origins.is_empty() {
+        match header(request, &http::header::ORIGIN) {
+            Some(origin)
+                if policy
+                    .allowed_origins
+                    .iter()
+                    .any(|allowed| allowed == origin) => {}
+            None if policy.allow_missing_origin => {}
+            origin => {
+                tracing::warn!(?origin, "Request origin is not allowed");
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+    }
+
+    if policy.user_from_client_cert {
+        return match peer.and_then(PeerIdentity::name) {
+            Some(user) => Ok(Some(user.to_string())),
+            None => {
+                tracing::warn!(?peer, "Client certificate doesn't name a user");
+                Err(StatusCode::FORBIDDEN)
+            }
         };
+    }
+
+    let user = policy
+        .user_header
+        .as_ref()
+        .and_then(|name| header(request, name))
+        .map(String::from);
+    if policy.require_user_header && user.is_none() {
+        tracing::warn!("Request was missing the required user header");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(user)
+}
+
+/// Extract a named header from a request as a string, ignoring non-UTF8 values
+fn header<'a>(request: &'a Request<()>, name: &HeaderName) -> Option<&'a str> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Respond to a request with an error status instead of establishing a session
+async fn reject(mut stream: ConnectStream, status: StatusCode) -> anyhow::Result<()> {
+    let mut response = Response::builder().status(status);
+    if status == StatusCode::METHOD_NOT_ALLOWED {
+        // 405 responses must list the methods that the resource does support
+        response = response.header(http::header::ALLOW, Method::CONNECT.as_str());
+    }
+    let response = response.body(())?;
+    stream.send_response(response).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Encode a CLOSE_WEBTRANSPORT_SESSION capsule, cutting the reason down to the longest
+/// allowed length (at a character boundary)
+fn close_capsule(code: u32, reason: &str) -> Bytes {
+    let mut length = reason.len().min(MAX_CLOSE_REASON);
+    while !reason.is_char_boundary(length) {
+        length -= 1;
+    }
+    let reason = &reason.as_bytes()[..length];
+
+    let mut capsule = BytesMut::new();
+    put_varint(&mut capsule, CLOSE_WEBTRANSPORT_SESSION);
+    put_varint(&mut capsule, 4 + reason.len() as u64);
+    capsule.put_u32(code);
+    capsule.put_slice(reason);
+    capsule.freeze()
+}
+
+/// Append a QUIC variable-length integer (which must be less than 2^62) to a buffer
+fn put_varint(buffer: &mut BytesMut, value: u64) {
+    if value < 1 << 6 {
+        buffer.put_u8(value as u8);
+    } else if value < 1 << 14 {
+        buffer.put_u16(0x4000 | value as u16);
+    } else if value < 1 << 30 {
+        buffer.put_u32(0x8000_0000 | value as u32);
+    } else {
+        buffer.put_u64(0xc000_0000_0000_0000 | value);
+    }
+}
+
+/// Recoverable error for stream requests (named by their AcceptedBi variant) that a Session
+/// does not support
+#[derive(Debug)]
+pub struct UnsupportedRequest(&'static str);
+
+impl fmt::Display for UnsupportedRequest {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "Unsupported {} stream requested", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedRequest {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Session ID of the nth client-initiated bi-directional stream
+    fn session_id(n: u64) -> SessionId {
+        SessionId::try_from(n * 4).unwrap()
+    }
+
+    #[test]
+    fn ended_sessions_no_longer_count_against_the_limit() {
+        let max_sessions = 3;
+        let registry = Registry::new(max_sessions);
+        registry.insert(session_id(0), Arc::new("primary"));
+
+        // more sessions than the limit come and go, one after another
+        for n in 1..=max_sessions + 1 {
+            assert!(!registry.is_full(), "session {n} was refused");
+            registry.insert(session_id(n), Arc::new("additional"));
+            assert!(registry.get(session_id(n)).is_some());
+            registry.remove(session_id(n));
+            assert!(registry.get(session_id(n)).is_none());
+        }
 
-        tracing::debug!("Bidirectional Stream initiated");
-        Ok(stream)
+        // while sessions that are still open count in full
+        for n in 1..max_sessions {
+            registry.insert(session_id(n), Arc::new("additional"));
+        }
+        assert!(registry.is_full());
+        registry.remove(session_id(1));
+        assert!(!registry.is_full());
+        assert!(registry.get(session_id(0)).is_some());
     }
 }
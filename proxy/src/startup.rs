@@ -0,0 +1,323 @@
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Postgres CancelRequest code, sent in place of a protocol version to cancel a running query
+const CANCEL_REQUEST_CODE: i32 = 80877102;
+
+/// Postgres SSLRequest code, sent in place of a protocol version to request TLS
+pub const SSL_REQUEST_CODE: i32 = 80877103;
+
+/// Postgres GSSENCRequest code, sent in place of a protocol version to request GSSAPI encryption
+pub const GSSENC_REQUEST_CODE: i32 = 80877104;
+
+/// Postgres frontend/backend protocol version 3.0
+const PROTOCOL_VERSION: i32 = 196608;
+
+/// Largest startup message accepted from a client (matching Postgres' own limit), so that
+/// clients can't force large allocations before a single byte is sent upstream
+pub const MAX_STARTUP_LENGTH: usize = 10_000;
+
+/// Parsed Postgres StartupMessage, the first frame sent by a client. Unlike every other
+/// frontend message, the StartupMessage is length-prefixed without a leading type byte.
+/// CancelRequests share the same framing, so they are parsed (and forwarded) here too.
+#[derive(Debug)]
+pub struct StartupMessage {
+    parameters: Vec<(String, String)>,
+    cancel: Option<(i32, i32)>,
+}
+
+impl StartupMessage {
+    /// Read and parse a StartupMessage from the start of a client stream. Encryption requests
+    /// are declined (as a Postgres server without TLS would) until the real startup message
+    /// arrives, since the proxy's own transport is responsible for encrypting the stream.
+    /// Like Postgres, each kind of request is only declined once (e.g. libpq's GSSENCRequest
+    /// followed by an SSLRequest), and repeating one is an error.
+    pub async fn read<S>(stream: &mut S) -> anyhow::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut declined = Vec::with_capacity(2);
+        loop {
+            let (code, body) = read_frame(stream).await?;
+            match code {
+                SSL_REQUEST_CODE | GSSENC_REQUEST_CODE => {
+                    anyhow::ensure!(body.len() == 4, "Invalid encryption request length");
+                    anyhow::ensure!(
+                        !declined.contains(&code),
+                        "Client repeated an encryption request that was already declined"
+                    );
+                    declined.push(code);
+                    tracing::debug!(code, "Declining client encryption request");
+                    stream
+                        .write_all(b"N")
+                        .await
+                        .context("Failed to decline client encryption request")?;
+                }
+                _ => return Self::parse(code, &body),
+            }
+        }
+    }
+
+    /// Parse the body of a StartupMessage or CancelRequest, identified by its leading code
+    fn parse(code: i32, body: &[u8]) -> anyhow::Result<Self> {
+        // cancel requests carry a backend process ID and secret key instead of parameters
+        if code == CANCEL_REQUEST_CODE {
+            anyhow::ensure!(body.len() == 12, "Invalid cancel request length");
+            let process_id = i32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+            let secret_key = i32::from_be_bytes([body[8], body[9], body[10], body[11]]);
+            return Ok(Self {
+                parameters: Vec::new(),
+                cancel: Some((process_id, secret_key)),
+            });
+        }
+
+        anyhow::ensure!(
+            code == PROTOCOL_VERSION,
+            "Unsupported protocol version {}.{}",
+            code >> 16,
+            code & 0xffff
+        );
+
+        // parameters are a sequence of null-terminated key/value pairs, ending in a single null
+        let mut fields = body[4..].split(|byte| *byte == 0);
+        let mut parameters = Vec::new();
+        loop {
+            let key = fields
+                .next()
+                .context("Startup message parameters were not terminated")?;
+            if key.is_empty() {
+                break;
+            }
+            let value = fields
+                .next()
+                .context("Startup message parameter was missing a value")?;
+            parameters.push((
+                String::from_utf8(key.to_vec()).context("Invalid startup parameter name")?,
+                String::from_utf8(value.to_vec()).context("Invalid startup parameter value")?,
+            ));
+        }
+
+        Ok(Self {
+            parameters,
+            cancel: None,
+        })
+    }
+
+    /// Check if this message is a CancelRequest rather than a real StartupMessage
+    pub fn is_cancel_request(&self) -> bool {
+        self.cancel.is_some()
+    }
+
+    /// Get every startup parameter, in the order they were sent
+    pub fn parameters(&self) -> &[(String, String)] {
+        &self.parameters
+    }
+
+    /// Get the value of a startup parameter
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set the value of a startup parameter, replacing any value sent by the client
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.parameters.iter_mut().find(|(name, _)| name == key) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => self.parameters.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Encode this StartupMessage for forwarding to an upstream server
+    pub fn encode(&self) -> anyhow::Result<BytesMut> {
+        let mut buffer = BytesMut::new();
+        if let Some((process_id, secret_key)) = self.cancel {
+            postgres_protocol::message::frontend::cancel_request(
+                process_id,
+                secret_key,
+                &mut buffer,
+            );
+            return Ok(buffer);
+        }
+
+        let parameters = self
+            .parameters
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()));
+        postgres_protocol::message::frontend::startup_message(parameters, &mut buffer)
+            .context("Failed to encode startup message")?;
+        Ok(buffer)
+    }
+}
+
+/// Encode a FATAL ErrorResponse with a SQLSTATE code and message, for rejecting clients in a
+/// way that any Postgres client can display
+pub fn error_response(code: &str, message: &str) -> BytesMut {
+    let mut body = Vec::new();
+    for (field, value) in [
+        (b'S', "FATAL"),
+        (b'V', "FATAL"),
+        (b'C', code),
+        (b'M', message),
+    ] {
+        body.push(field);
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+
+    // the length includes itself, but not the leading message type
+    let mut buffer = BytesMut::with_capacity(body.len() + 5);
+    buffer.put_u8(b'E');
+    buffer.put_i32(body.len() as i32 + 4);
+    buffer.put_slice(&body);
+    buffer
+}
+
+/// Read a single length-prefixed startup frame, returning its leading code and the whole body
+/// (including the code), while enforcing MAX_STARTUP_LENGTH before allocating anything
+async fn read_frame<S>(stream: &mut S) -> anyhow::Result<(i32, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    // the length includes itself, followed by the protocol version (or request code) and the body
+    let length = stream
+        .read_i32()
+        .await
+        .context("Failed to read startup message length")?;
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|length| (8..=MAX_STARTUP_LENGTH).contains(length))
+        .ok_or_else(|| anyhow::anyhow!("Invalid startup message length {length}"))?;
+    let mut body = vec![0; length - 4];
+    stream
+        .read_exact(&mut body)
+        .await
+        .context("Failed to read startup message body")?;
+    let code = i32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+    Ok((code, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// SSLRequest, exactly as psql sends it with the default sslmode of prefer
+    const SSL_REQUEST: [u8; 8] = [0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f];
+
+    /// GSSENCRequest, exactly as psql sends it (before any SSLRequest) with gssencmode=prefer
+    const GSSENC_REQUEST: [u8; 8] = [0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x30];
+
+    /// Encode a StartupMessage with the parameters that psql sends
+    fn startup_message() -> BytesMut {
+        let mut buffer = BytesMut::new();
+        postgres_protocol::message::frontend::startup_message(
+            [
+                ("user", "alice"),
+                ("database", "app"),
+                ("application_name", "psql"),
+                ("client_encoding", "UTF8"),
+            ],
+            &mut buffer,
+        )
+        .unwrap();
+        buffer
+    }
+
+    #[tokio::test]
+    async fn ssl_requests_are_declined_before_the_startup_message() {
+        let (mut stream, mut client) = duplex(1024);
+        client.write_all(&SSL_REQUEST).await.unwrap();
+        client.write_all(&startup_message()).await.unwrap();
+
+        let startup = StartupMessage::read(&mut stream).await.unwrap();
+        assert_eq!(startup.get("user"), Some("alice"));
+        assert_eq!(startup.get("database"), Some("app"));
+        assert_eq!(startup.get("application_name"), Some("psql"));
+
+        drop(stream);
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies, b"N");
+    }
+
+    #[tokio::test]
+    async fn gssenc_and_ssl_requests_are_each_declined() {
+        let (mut stream, mut client) = duplex(1024);
+        client.write_all(&GSSENC_REQUEST).await.unwrap();
+        client.write_all(&SSL_REQUEST).await.unwrap();
+        client.write_all(&startup_message()).await.unwrap();
+
+        let startup = StartupMessage::read(&mut stream).await.unwrap();
+        assert_eq!(startup.get("user"), Some("alice"));
+
+        drop(stream);
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies, b"NN");
+    }
+
+    #[tokio::test]
+    async fn repeated_ssl_requests_are_rejected() {
+        let (mut stream, mut client) = duplex(1024);
+        client.write_all(&SSL_REQUEST).await.unwrap();
+        client.write_all(&SSL_REQUEST).await.unwrap();
+        client.write_all(&startup_message()).await.unwrap();
+
+        let error = StartupMessage::read(&mut stream).await.unwrap_err();
+        assert!(error.to_string().contains("repeated"), "{error}");
+
+        drop(stream);
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies, b"N");
+    }
+
+    /// Read a StartupMessage from a client that has sent the given bytes but keeps its side of
+    /// the stream open, so that reading more than those bytes would never finish
+    async fn read_prefix(bytes: &[u8]) -> anyhow::Result<StartupMessage> {
+        let (mut stream, mut client) = duplex(1024);
+        client.write_all(bytes).await.unwrap();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            StartupMessage::read(&mut stream),
+        )
+        .await
+        .expect("startup message was read past the rejected frame")
+    }
+
+    #[tokio::test]
+    async fn oversized_startup_messages_are_rejected_before_their_body() {
+        // only the length is sent, so the frame must be rejected without reading (or
+        // allocating room for) the body it claims to have
+        let error = read_prefix(&i32::MAX.to_be_bytes()).await.unwrap_err();
+        assert!(error.to_string().contains("length"), "{error}");
+
+        let length = MAX_STARTUP_LENGTH as i32 + 1;
+        let error = read_prefix(&length.to_be_bytes()).await.unwrap_err();
+        assert!(error.to_string().contains("length"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn undersized_startup_messages_are_rejected() {
+        for length in [-1, 0, 4, 7] {
+            let error = read_prefix(&i32::to_be_bytes(length)).await.unwrap_err();
+            assert!(error.to_string().contains("length"), "{error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_protocol_versions_are_rejected() {
+        // a protocol 2.0 StartupMessage, otherwise identical to a 3.0 one
+        let mut message = startup_message();
+        message[4..8].copy_from_slice(&131072_i32.to_be_bytes());
+        let error = read_prefix(&message).await.unwrap_err();
+        assert!(
+            error.to_string().contains("protocol version 2.0"),
+            "{error}"
+        );
+    }
+}
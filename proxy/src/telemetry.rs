@@ -0,0 +1,97 @@
+use crate::endpoint::HttpAddress;
+use anyhow::Context;
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+/// Serve Prometheus metrics over HTTP at the given address, describing every metric that the
+/// proxy records along the way
+pub fn install(address: HttpAddress) -> anyhow::Result<()> {
+    let builder = match &address {
+        HttpAddress::Tcp(address) => PrometheusBuilder::new().with_http_listener(*address),
+        #[cfg(unix)]
+        HttpAddress::Unix(path) => {
+            // the exporter would remove whatever is at the path, socket or not
+            crate::endpoint::remove_stale_socket(path)?;
+            PrometheusBuilder::new().with_http_uds_listener(path)
+        }
+        #[cfg(not(unix))]
+        HttpAddress::Unix(path) => anyhow::bail!(
+            "Unix socket metrics servers are not supported on this platform: {}",
+            path.display()
+        ),
+    };
+    builder
+        .install()
+        .with_context(|| format!("Failed to start metrics server on {address}"))?;
+
+    describe_counter!(
+        "proxy_connection_attempts_total",
+        "QUIC connection attempts accepted by the endpoint"
+    );
+    describe_counter!(
+        "proxy_handshakes_total",
+        "HTTP/3 + WebTransport handshakes, labeled by outcome"
+    );
+    describe_counter!(
+        "proxy_connections_throttled_total",
+        "Connection attempts dropped for exceeding the per-IP rate limit"
+    );
+    describe_gauge!(
+        "proxy_connections_active",
+        "QUIC connections currently being proxied"
+    );
+    describe_histogram!(
+        "proxy_connection_duration_seconds",
+        Unit::Seconds,
+        "Lifetime of proxied QUIC connections"
+    );
+    describe_counter!(
+        "proxy_upstream_connects_total",
+        "Connections to upstream Postgres servers, labeled by outcome"
+    );
+    describe_gauge!(
+        "proxy_streams_active",
+        "WebTransport streams currently being proxied"
+    );
+    describe_counter!(
+        "proxy_streams_total",
+        "Proxied WebTransport streams, labeled by outcome"
+    );
+    describe_counter!(
+        "proxy_bytes_total",
+        Unit::Bytes,
+        "Bytes proxied, labeled by direction"
+    );
+    describe_counter!(
+        "proxy_pool_checkouts_total",
+        "Attempts to borrow a pooled upstream connection, labeled by hit or miss"
+    );
+
+    tracing::info!(%address, "serving metrics");
+    Ok(())
+}
+
+/// Label value for the outcome of a fallible operation
+pub fn outcome<T, E>(result: &Result<T, E>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "error",
+    }
+}
+
+/// Guard that increments a gauge for as long as it is held, e.g. for tracking active tasks
+pub struct ActiveGauge(&'static str);
+
+impl ActiveGauge {
+    /// Increment the named gauge until this guard is dropped
+    pub fn new(name: &'static str) -> Self {
+        metrics::gauge!(name).increment(1.0);
+        Self(name)
+    }
+}
+
+impl Drop for ActiveGauge {
+    fn drop(&mut self) {
+        metrics::gauge!(self.0).decrement(1.0);
+    }
+}
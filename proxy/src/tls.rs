@@ -0,0 +1,226 @@
+use anyhow::Context;
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use std::path::{Path, PathBuf};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// ALPN protocols offered by default: HTTP/3, along with the drafts that older clients still
+/// ask for
+pub const HTTP3_ALPN_PROTOCOLS: [&str; 5] = ["h3", "h3-32", "h3-31", "h3-30", "h3-29"];
+
+/// Encoding of certificate and private key files
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum CertFormat {
+    /// PEM-encoded files, which may contain a full certificate chain
+    Pem,
+    /// DER-encoded files, containing a single certificate or key
+    Der,
+}
+
+impl CertFormat {
+    /// Detect the encoding of file contents, assuming that anything without a PEM header is DER
+    fn detect(contents: &[u8]) -> Self {
+        if contents.trim_ascii_start().starts_with(b"-----BEGIN") {
+            Self::Pem
+        } else {
+            Self::Der
+        }
+    }
+}
+
+/// Builder for the TLS configuration of a QUIC server: TLS 1.3 only (as QUIC requires), with
+/// rustls's safe default cipher suites and key exchange groups
+#[derive(Clone, Debug)]
+pub struct TlsConfigBuilder {
+    cert: PathBuf,
+    key: PathBuf,
+    format: Option<CertFormat>,
+    alpn_protocols: Vec<Vec<u8>>,
+    early_data: bool,
+    client_ca: Option<PathBuf>,
+    require_client_auth: bool,
+}
+
+impl TlsConfigBuilder {
+    /// Start building a configuration for the certificate chain and private key in these
+    /// files, offering the HTTP/3 ALPN protocols and refusing 0-RTT early data
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            format: None,
+            alpn_protocols: HTTP3_ALPN_PROTOCOLS
+                .iter()
+                .map(|protocol| protocol.as_bytes().to_vec())
+                .collect(),
+            early_data: false,
+            client_ca: None,
+            require_client_auth: false,
+        }
+    }
+
+    /// Read the certificate and key files in a specific format, rather than detecting it
+    pub fn with_cert_format(mut self, format: Option<CertFormat>) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Offer these ALPN protocols (in order of preference) instead of the HTTP/3 defaults
+    pub fn with_alpn_protocols<P>(mut self, protocols: impl IntoIterator<Item = P>) -> Self
+    where
+        P: AsRef<[u8]>,
+    {
+        self.alpn_protocols = protocols
+            .into_iter()
+            .map(|protocol| protocol.as_ref().to_vec())
+            .collect();
+        self
+    }
+
+    /// Accept 0-RTT early data from resuming clients, saving a round trip on reconnects.
+    /// Early data can be replayed by anyone who captures it, and for a database proxy it
+    /// carries the client's first Postgres messages, so a replayed handshake could re-run
+    /// whatever statements were sent along with it (including writes). Only enable this when
+    /// every statement that could arrive that early is safe to run twice.
+    pub fn with_early_data(mut self, early_data: bool) -> Self {
+        self.early_data = early_data;
+        self
+    }
+
+    /// Verify client certificates against the CA certificates in this file (if any), only
+    /// letting clients without a certificate through when client auth isn't required.
+    /// Clients that present a certificate the CA didn't sign are always rejected.
+    pub fn with_client_auth(mut self, ca: Option<PathBuf>, required: bool) -> Self {
+        self.client_ca = ca;
+        self.require_client_auth = required;
+        self
+    }
+
+    /// Load the certificate and key, then build the ServerConfig
+    pub fn build(self) -> anyhow::Result<ServerConfig> {
+        let certs = load_certs(&self.cert, self.format)?;
+        let key = load_key(&self.key, self.format)?;
+        let config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?;
+        let mut config = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca, None)? {
+                    roots.add(&cert).with_context(|| {
+                        format!("Failed to add client CA certificate from {}", ca.display())
+                    })?;
+                }
+                let verifier = if self.require_client_auth {
+                    AllowAnyAuthenticatedClient::new(roots).boxed()
+                } else {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+                };
+                config.with_client_cert_verifier(verifier)
+            }
+            None => config.with_no_client_auth(),
+        }
+        .with_single_cert(certs, key)?;
+        // QUIC only allows early data to be disabled (0) or unlimited (u32::MAX)
+        config.max_early_data_size = if self.early_data { u32::MAX } else { 0 };
+        config.alpn_protocols = self.alpn_protocols;
+        Ok(config)
+    }
+}
+
+/// Identity of an authenticated client, taken from the leaf of its verified certificate chain
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// common name of the certificate's subject
+    pub common_name: Option<String>,
+    /// DNS names, email addresses, and URIs from the certificate's subject alternative names
+    pub subject_alt_names: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// Parse the identity out of a client's certificate chain (leaf first), if it has one
+    pub fn from_certificates(certs: &[Certificate]) -> anyhow::Result<Option<Self>> {
+        let Some(leaf) = certs.first() else {
+            return Ok(None);
+        };
+        let (_, cert) = X509Certificate::from_der(&leaf.0)
+            .map_err(|error| anyhow::anyhow!("Failed to parse client certificate: {error}"))?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .find_map(|name| name.as_str().ok())
+            .map(String::from);
+        let subject_alt_names = cert
+            .subject_alternative_name()
+            .map_err(|error| anyhow::anyhow!("Invalid client certificate SAN: {error}"))?
+            .map(|extension| {
+                extension
+                    .value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name)
+                        | GeneralName::RFC822Name(name)
+                        | GeneralName::URI(name) => Some(name.to_string()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Some(Self {
+            common_name,
+            subject_alt_names,
+        }))
+    }
+
+    /// The name that identifies this client (e.g. as a Postgres user): the subject's common
+    /// name, falling back to the first subject alternative name
+    pub fn name(&self) -> Option<&str> {
+        self.common_name
+            .as_deref()
+            .or_else(|| self.subject_alt_names.first().map(String::as_str))
+    }
+}
+
+/// Load a certificate chain (leaf first) from a file, detecting the format if none is provided
+pub fn load_certs(path: &Path, format: Option<CertFormat>) -> anyhow::Result<Vec<Certificate>> {
+    let contents = std::fs::read(path)
+        .with_context(|| format!("Failed to read certificate file {}", path.display()))?;
+
+    match format.unwrap_or_else(|| CertFormat::detect(&contents)) {
+        CertFormat::Der => Ok(vec![Certificate(contents)]),
+        CertFormat::Pem => {
+            let certs = rustls_pemfile::certs(&mut contents.as_slice())
+                .map(|cert| cert.map(|cert| Certificate(cert.to_vec())))
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| {
+                    format!("Failed to parse PEM certificates in {}", path.display())
+                })?;
+            anyhow::ensure!(
+                !certs.is_empty(),
+                "No certificates found in {}",
+                path.display()
+            );
+            Ok(certs)
+        }
+    }
+}
+
+/// Load a private key from a file, detecting the format if none is provided
+pub fn load_key(path: &Path, format: Option<CertFormat>) -> anyhow::Result<PrivateKey> {
+    let contents = std::fs::read(path)
+        .with_context(|| format!("Failed to read private key file {}", path.display()))?;
+
+    match format.unwrap_or_else(|| CertFormat::detect(&contents)) {
+        CertFormat::Der => Ok(PrivateKey(contents)),
+        CertFormat::Pem => rustls_pemfile::private_key(&mut contents.as_slice())
+            .with_context(|| format!("Failed to parse PEM private key in {}", path.display()))?
+            .map(|key| PrivateKey(key.secret_der().to_vec()))
+            .with_context(|| format!("No private key found in {}", path.display())),
+    }
+}
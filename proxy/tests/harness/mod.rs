@@ -0,0 +1,296 @@
+//! Harness for running the proxy binary between a WebTransport client and a real Postgres
+//! server, started in a container with testcontainers (so a Docker daemon has to be running)
+
+use anyhow::Context;
+use bytes::BytesMut;
+use client::{server_error, ConnectionCore, ConnectionError, Param, DEFAULT_MIN_SCRAM_ITERATIONS};
+use fallible_iterator::FallibleIterator;
+use postgres_protocol::message::{backend::Message, frontend};
+use std::{
+    net::{TcpListener as StdTcpListener, UdpSocket},
+    path::PathBuf,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+use testcontainers_modules::{
+    postgres::Postgres,
+    testcontainers::{runners::AsyncRunner, ContainerAsync, ImageExt},
+};
+use tokio::{
+    io::Join,
+    net::{TcpListener, TcpStream},
+    process::{Child, Command},
+    task::JoinHandle,
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+use web_transport_quinn::{ClientBuilder, RecvStream, SendStream, Session};
+
+/// Password of the containerized postgres user
+pub const PASSWORD: &str = "postgres";
+
+/// Time allowed for the proxy to start accepting sessions
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running proxy, listening on an ephemeral port with a self-signed certificate, in front
+/// of a Postgres container. Both are shut down when the Harness is dropped.
+pub struct Harness {
+    _postgres: ContainerAsync<Postgres>,
+    _proxy: Child,
+    certs: PathBuf,
+    port: u16,
+    relay: Option<Relay>,
+}
+
+/// TCP port that the proxy uses as its upstream in place of Postgres itself, which refuses
+/// connections until it starts relaying them to Postgres
+struct Relay {
+    port: u16,
+    upstream: (String, u16),
+    task: Option<JoinHandle<()>>,
+}
+
+impl Harness {
+    /// Start Postgres (with password auth over TCP) and a proxy to it, waiting until the proxy
+    /// accepts WebTransport sessions
+    pub async fn start() -> anyhow::Result<Self> {
+        Self::launch(false, &[]).await
+    }
+
+    /// Start Postgres and a proxy like Harness::start, with additional proxy arguments
+    pub async fn start_with(args: &[&str]) -> anyhow::Result<Self> {
+        Self::launch(false, args).await
+    }
+
+    /// Start Postgres and a proxy like Harness::start (with additional proxy arguments), but
+    /// point the proxy at a relay that refuses every upstream connection until
+    /// Harness::open_relay is called
+    pub async fn start_with_relay(args: &[&str]) -> anyhow::Result<Self> {
+        Self::launch(true, args).await
+    }
+
+    /// Start Postgres and a proxy with additional arguments, optionally behind a closed Relay
+    async fn launch(relayed: bool, args: &[&str]) -> anyhow::Result<Self> {
+        let postgres = Postgres::default()
+            .with_env_var("POSTGRES_HOST_AUTH_METHOD", "password")
+            .start()
+            .await
+            .context("Failed to start Postgres container")?;
+        let postgres_address = (
+            postgres.get_host().await?.to_string(),
+            postgres.get_host_port_ipv4(5432).await?,
+        );
+
+        // reserve an ephemeral TCP port for the relay, leaving it closed for now
+        let (relay, (upstream_host, upstream_port)) = if relayed {
+            let port = StdTcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+            let relay = Relay {
+                port,
+                upstream: postgres_address,
+                task: None,
+            };
+            (Some(relay), ("127.0.0.1".to_string(), port))
+        } else {
+            (None, postgres_address)
+        };
+
+        // reserve an ephemeral UDP port, then hand it over to the proxy
+        let port = UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+
+        let certs = std::env::temp_dir().join(format!(
+            "pg-webtransport-test-{}-{port}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&certs)?;
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+        std::fs::write(certs.join("localhost.crt"), certified.cert.pem())?;
+        std::fs::write(
+            certs.join("localhost.key"),
+            certified.key_pair.serialize_pem(),
+        )?;
+
+        let proxy = Command::new(env!("CARGO_BIN_EXE_proxy"))
+            .arg("--cert")
+            .arg(certs.join("localhost.crt"))
+            .arg("--key")
+            .arg(certs.join("localhost.key"))
+            .args(["--host", "127.0.0.1", "--port", &port.to_string()])
+            .arg("--upstream")
+            .arg(format!(
+                "postgres://{upstream_host}:{upstream_port}?sslmode=disable"
+            ))
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to start proxy")?;
+
+        let harness = Self {
+            _postgres: postgres,
+            _proxy: proxy,
+            certs,
+            port,
+            relay,
+        };
+        let started = Instant::now();
+        loop {
+            match harness.session().await {
+                Ok(session) => {
+                    session.close(0, b"ready");
+                    return Ok(harness);
+                }
+                Err(error) if started.elapsed() > STARTUP_TIMEOUT => {
+                    return Err(error.context("Proxy never started accepting sessions"));
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+
+    /// Start relaying the proxy's upstream connections to Postgres
+    pub async fn open_relay(&mut self) -> anyhow::Result<()> {
+        let relay = self
+            .relay
+            .as_mut()
+            .context("Harness was started without a relay")?;
+        let listener = TcpListener::bind(("127.0.0.1", relay.port)).await?;
+        let upstream = relay.upstream.clone();
+        relay.task = Some(tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let upstream = upstream.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut postgres) = TcpStream::connect(upstream).await {
+                        let _ = tokio::io::copy_bidirectional(&mut client, &mut postgres).await;
+                    }
+                });
+            }
+        }));
+        Ok(())
+    }
+
+    /// Open a new WebTransport session with the proxy, skipping certificate verification
+    pub async fn session(&self) -> anyhow::Result<Session> {
+        let client = ClientBuilder::new()
+            .dangerous()
+            .with_no_certificate_verification()?;
+        let url = format!("https://127.0.0.1:{}/", self.port).parse::<url::Url>()?;
+        Ok(client.connect(url).await?)
+    }
+
+    /// Open a Postgres connection through the proxy on a stream of a new session
+    pub async fn connect(&self) -> anyhow::Result<Client> {
+        Client::open(&self.session().await?).await
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.certs);
+        if let Some(task) = self.relay.as_mut().and_then(|relay| relay.task.take()) {
+            task.abort();
+        }
+    }
+}
+
+/// Postgres connection over a single WebTransport stream, run by the client crate's own
+/// ConnectionCore (the same startup, authentication, and framing as the browser client)
+pub struct Client {
+    _session: Session,
+    core: ConnectionCore<Compat<Join<RecvStream, SendStream>>>,
+}
+
+impl Client {
+    /// Open a Postgres connection through the proxy on a new stream of an existing session
+    pub async fn open(session: &Session) -> anyhow::Result<Self> {
+        let (send, recv) = session.open_bi().await?;
+        Ok(Self {
+            _session: session.clone(),
+            core: ConnectionCore::new(tokio::io::join(recv, send).compat()),
+        })
+    }
+
+    /// Start up as a user of the postgres database, then wait until the server is ready for
+    /// queries
+    pub async fn startup(&mut self, user: &str, password: &str) -> Result<(), ConnectionError> {
+        self.startup_with(user, password, &[]).await
+    }
+
+    /// Start up like Client::startup, with additional startup parameters
+    pub async fn startup_with(
+        &mut self,
+        user: &str,
+        password: &str,
+        params: &[(&str, &str)],
+    ) -> Result<(), ConnectionError> {
+        let mut startup = vec![("user", user), ("database", "postgres")];
+        startup.extend_from_slice(params);
+        self.core
+            .startup(&startup, password.as_bytes(), DEFAULT_MIN_SCRAM_ITERATIONS)
+            .await
+    }
+
+    /// Send a Terminate message, then finish the stream
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.core.close().await
+    }
+
+    /// Run a simple query, collecting the text of every column of every row
+    pub async fn simple_query(&mut self, query: &str) -> anyhow::Result<Vec<Vec<Option<String>>>> {
+        let mut buffer = BytesMut::new();
+        frontend::query(query, &mut buffer)?;
+        self.core.encode(buffer).await?;
+        self.rows().await
+    }
+
+    /// Run a query through the extended query protocol, binding its parameters the same way
+    /// as the browser client, and collect the text of every column of every row
+    pub async fn query_with_params(
+        &mut self,
+        query: &str,
+        params: &[Param],
+    ) -> anyhow::Result<Vec<Vec<Option<String>>>> {
+        let mut buffer = BytesMut::new();
+        frontend::parse("", query, [], &mut buffer)?;
+        client::bind("", "", params, 0, &mut buffer)?;
+        frontend::execute("", 0, &mut buffer)?;
+        frontend::sync(&mut buffer);
+        self.core.encode(buffer).await?;
+        self.rows().await
+    }
+
+    /// Collect the text of every column of every row until the server is ready for the next
+    /// query, failing with the first ErrorResponse along the way
+    async fn rows(&mut self) -> anyhow::Result<Vec<Vec<Option<String>>>> {
+        let mut rows = Vec::new();
+        let mut error = None;
+        loop {
+            let message = self
+                .core
+                .decode()
+                .await?
+                .context("Stream closed before the server was ready for the next query")?;
+            match message {
+                Message::DataRow(body) => {
+                    let ranges = body.ranges().collect::<Vec<_>>()?;
+                    let row = ranges
+                        .into_iter()
+                        .map(|range| {
+                            range.map(|range| {
+                                String::from_utf8_lossy(&body.buffer()[range]).into_owned()
+                            })
+                        })
+                        .collect();
+                    rows.push(row);
+                }
+                Message::ErrorResponse(body) => error = Some(server_error(body.fields())),
+                Message::ReadyForQuery(_) => break,
+                _ => {}
+            }
+        }
+
+        match error {
+            Some(error) => Err(ConnectionError::Server(Box::new(error)).into()),
+            None => Ok(rows),
+        }
+    }
+}
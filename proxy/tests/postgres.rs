@@ -0,0 +1,279 @@
+//! End-to-end tests of the proxy against a real Postgres server. These need a running Docker
+//! daemon, so they're ignored by default: run them with `cargo test -- --ignored`.
+
+mod harness;
+
+use bytes::Bytes;
+use client::{options_string, ConnectionError, Param};
+use harness::{Client, Harness, PASSWORD};
+use std::time::Duration;
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn select_round_trips_through_the_proxy() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let mut client = harness.connect().await?;
+    client.startup("postgres", PASSWORD).await?;
+
+    let rows = client.simple_query("SELECT 1").await?;
+    assert_eq!(rows, vec![vec![Some("1".to_string())]]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn wrong_password_is_rejected_by_the_upstream() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let mut client = harness.connect().await?;
+
+    let error = client
+        .startup("postgres", "not the password")
+        .await
+        .expect_err("startup should fail with the wrong password");
+    assert!(
+        matches!(error, ConnectionError::Authentication { .. }),
+        "failure should come from authentication: {error}"
+    );
+    // invalid_password
+    assert_eq!(error.sqlstate(), Some("28P01"));
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn failed_upstream_connect_leaves_the_session_open() -> anyhow::Result<()> {
+    let mut harness = Harness::start_with_relay(&["--intercept-startup"]).await?;
+    let session = harness.session().await?;
+
+    let mut client = Client::open(&session).await?;
+    let error = client
+        .startup("postgres", PASSWORD)
+        .await
+        .expect_err("startup should fail while the upstream refuses connections");
+    // connection_failure
+    assert_eq!(error.sqlstate(), Some("08006"));
+
+    harness.open_relay().await?;
+    let mut client = Client::open(&session).await?;
+    client.startup("postgres", PASSWORD).await?;
+    let rows = client.simple_query("SELECT 1").await?;
+    assert_eq!(rows, vec![vec![Some("1".to_string())]]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn policy_rejections_leave_the_session_open() -> anyhow::Result<()> {
+    let harness =
+        Harness::start_with(&["--intercept-startup", "--allowed-users", "postgres"]).await?;
+    let session = harness.session().await?;
+    let mut client = Client::open(&session).await?;
+    client.startup("postgres", PASSWORD).await?;
+
+    let mut rejected = Client::open(&session).await?;
+    let error = rejected
+        .startup("someone else", PASSWORD)
+        .await
+        .expect_err("startup should fail for a user outside the allowlist");
+    // invalid_authorization_specification
+    assert_eq!(error.sqlstate(), Some("28000"));
+
+    // the rejection only ends its own stream, not the other streams of the primary session
+    let rows = client.simple_query("SELECT 1").await?;
+    assert_eq!(rows, vec![vec![Some("1".to_string())]]);
+    let mut later = Client::open(&session).await?;
+    later.startup("postgres", PASSWORD).await?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn startup_parameters_configure_the_session() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let mut client = harness.connect().await?;
+    let options = options_string([("search_path", "app, public")]);
+    client
+        .startup_with(
+            "postgres",
+            PASSWORD,
+            &[("options", &options), ("statement_timeout", "5s")],
+        )
+        .await?;
+
+    let rows = client.simple_query("SHOW search_path").await?;
+    assert_eq!(rows, vec![vec![Some("app, public".to_string())]]);
+    let rows = client.simple_query("SHOW statement_timeout").await?;
+    assert_eq!(rows, vec![vec![Some("5s".to_string())]]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn repeated_startup_parameters_are_rejected() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let mut client = harness.connect().await?;
+
+    let error = client
+        .startup_with("postgres", PASSWORD, &[("user", "someone else")])
+        .await
+        .expect_err("startup should fail with two users");
+    assert!(
+        matches!(error, ConnectionError::InvalidInput(..)),
+        "failure should come from the client: {error}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn typed_parameters_round_trip() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let mut client = harness.connect().await?;
+    client.startup("postgres", PASSWORD).await?;
+
+    let rows = client
+        .query_with_params(
+            "SELECT $1::int4, $2::text, $3::bool, $4::int4 IS NULL",
+            &[
+                Param::Number(42.0),
+                Param::Text("it's text".to_string()),
+                Param::Bool(true),
+                Param::Null,
+            ],
+        )
+        .await?;
+    let expected = ["42", "it's text", "t", "t"];
+    assert_eq!(
+        rows,
+        vec![expected.map(|value| Some(value.to_string())).to_vec()]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn ping_datagrams_are_answered() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let session = harness.session().await?;
+
+    // datagrams can be lost, even over loopback, so keep pinging until one is answered
+    for _ in 0..5 {
+        session.send_datagram(Bytes::from_static(b"ping"))?;
+        if let Ok(pong) =
+            tokio::time::timeout(Duration::from_millis(500), session.read_datagram()).await
+        {
+            assert_eq!(pong?.as_ref(), b"pong");
+            return Ok(());
+        }
+    }
+    anyhow::bail!("no ping datagram was answered")
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn one_way_pings_are_answered_on_a_pushed_stream() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let session = harness.session().await?;
+    let mut client = Client::open(&session).await?;
+    client.startup("postgres", PASSWORD).await?;
+
+    // a single write carries the header, preamble, and Ping, before the stream finishes
+    let mut pings = session.open_uni().await?;
+    pings.write_all(b"PGWT-CTL?\0\0\0\x2a").await?;
+    pings.finish()?;
+    let mut pongs = session.accept_uni().await?;
+    let mut pong = [0; 6];
+    pongs.read_exact(&mut pong).await?;
+    // the nonce, then a reachable upstream
+    assert_eq!(&pong, b"!\0\0\0\x2a\0");
+    assert!(pongs.read_to_end(64).await?.is_empty());
+
+    // the Postgres stream carries on alongside the unidirectional streams
+    let rows = client.simple_query("SELECT 1").await?;
+    assert_eq!(rows, vec![vec![Some("1".to_string())]]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn malformed_unidirectional_streams_are_stopped_on_their_own() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let session = harness.session().await?;
+    let mut client = Client::open(&session).await?;
+    client.startup("postgres", PASSWORD).await?;
+
+    // raw QUIC streams, which finish before their header does
+    let mut empty = (*session).open_uni().await?;
+    empty.finish()?;
+    let mut truncated = (*session).open_uni().await?;
+    truncated.write_all(&[0x40, 0x54]).await?;
+    truncated.finish()?;
+
+    // raw QUIC streams with an unknown stream type, and with an unknown session ID
+    let mut unknown_type = (*session).open_uni().await?;
+    unknown_type.write_all(&[0x0d]).await?;
+    let stopped = unknown_type.stopped().await?.map(|code| code.into_inner());
+    assert_eq!(stopped, Some(0x103));
+    let mut unknown_session = (*session).open_uni().await?;
+    unknown_session.write_all(&[0x40, 0x54, 0x4f, 0xa0]).await?;
+    let stopped = unknown_session
+        .stopped()
+        .await?
+        .map(|code| code.into_inner());
+    assert_eq!(stopped, Some(0x10b));
+
+    // none of which affects the rest of the connection
+    let rows = client.simple_query("SELECT 1").await?;
+    assert_eq!(rows, vec![vec![Some("1".to_string())]]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn plain_http_requests_leave_the_session_working() -> anyhow::Result<()> {
+    let harness = Harness::start().await?;
+    let session = harness.session().await?;
+
+    // a raw HTTP/3 HEADERS frame for GET https://localhost/, QPACK-encoded with the static table
+    let mut headers = vec![0x00, 0x00, 0xd1, 0xd7, 0x50, 0x09];
+    headers.extend_from_slice(b"localhost");
+    headers.push(0xc1);
+    let mut frame = vec![0x01, headers.len() as u8];
+    frame.extend_from_slice(&headers);
+    let (mut send, mut recv) = (*session).open_bi().await?;
+    send.write_all(&frame).await?;
+    send.finish()?;
+
+    // the request is answered (with a 405) on a cleanly finished stream, rather than reset
+    let response = recv.read_to_end(1024).await?;
+    assert!(!response.is_empty(), "the request got no response");
+
+    // and the session keeps accepting Postgres streams
+    let mut client = Client::open(&session).await?;
+    client.startup("postgres", PASSWORD).await?;
+    let rows = client.simple_query("SELECT 1").await?;
+    assert_eq!(rows, vec![vec![Some("1".to_string())]]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires a Docker daemon"]
+async fn streams_beyond_the_limit_wait_for_earlier_ones_to_close() -> anyhow::Result<()> {
+    // the session's CONNECT stream takes up one of the two bi-directional streams
+    let harness = Harness::start_with(&["--max-bidi-streams", "2"]).await?;
+    let session = harness.session().await?;
+    let mut first = Client::open(&session).await?;
+    first.startup("postgres", PASSWORD).await?;
+
+    let refused = tokio::time::timeout(Duration::from_millis(500), Client::open(&session)).await;
+    assert!(refused.is_err(), "a stream beyond the limit was opened");
+
+    // closing the first stream frees up room for another
+    first.close().await?;
+    drop(first);
+    let mut second = tokio::time::timeout(Duration::from_secs(5), Client::open(&session)).await??;
+    second.startup("postgres", PASSWORD).await?;
+    let rows = second.simple_query("SELECT 1").await?;
+    assert_eq!(rows, vec![vec![Some("1".to_string())]]);
+    Ok(())
+}